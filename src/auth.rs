@@ -0,0 +1,111 @@
+use chrono::Utc;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+const MAX_ATTEMPTS: u32 = 3;
+const LOCKOUT_SECS: i64 = 10 * 60;
+pub const DEFAULT_INVITE_HOURS: i64 = 24;
+
+pub fn hash_password(password: &str) -> Result<String, argon2::Error> {
+    let salt = nanoid!(16);
+    argon2::hash_encoded(
+        password.as_bytes(),
+        salt.as_bytes(),
+        &argon2::Config::default(),
+    )
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match argon2::verify_encoded(hash, password.as_bytes()) {
+        Ok(valid) => valid,
+        Err(err) => {
+            log::warn!("Password hash: {}", err);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    Member,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "member" | "user" => Some(Role::Member),
+            "admin" | "op" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Member => write!(f, "member"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub role: Role,
+    pub expires: i64,
+    pub created_by: String,
+}
+
+impl Invite {
+    pub fn new(role: Role, hours: i64, created_by: String) -> Self {
+        Self {
+            code: nanoid!(16),
+            role,
+            expires: Utc::now().timestamp() + hours * 60 * 60,
+            created_by,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now().timestamp() > self.expires
+    }
+
+    pub fn link(&self, bot_name: &str) -> String {
+        format!("https://t.me/{}?start={}", bot_name, self.code)
+    }
+}
+
+/// Failed `/op` attempts per telegram user, kept in memory only.
+#[derive(Debug, Default)]
+pub struct LoginAttempts {
+    attempts: HashMap<i32, (u32, i64)>,
+}
+
+impl LoginAttempts {
+    pub fn is_locked(&self, user_id: i32) -> bool {
+        match self.attempts.get(&user_id) {
+            Some((count, last)) => {
+                *count >= MAX_ATTEMPTS && Utc::now().timestamp() - last < LOCKOUT_SECS
+            }
+            None => false,
+        }
+    }
+
+    pub fn fail(&mut self, user_id: i32) {
+        let now = Utc::now().timestamp();
+        let entry = self.attempts.entry(user_id).or_insert((0, now));
+        if now - entry.1 >= LOCKOUT_SECS {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        entry.1 = now;
+    }
+
+    pub fn reset(&mut self, user_id: i32) {
+        self.attempts.remove(&user_id);
+    }
+}
@@ -0,0 +1,429 @@
+use parking_lot::RwLock;
+use pickledb::{PickleDb, SerializationMethod};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use ate::auth::Invite;
+use ate::db::{DBKeys, StoreHandler};
+use ate::export::ExportFormat;
+use ate::history::Cooked;
+use ate::meal::Meal;
+use ate::pantry::Pantry;
+use ate::plan::Plan;
+use ate::poll::PollKind;
+use ate::settings::ChatSettings;
+use ate::shopping::ShoppingList;
+use ate::state::{State, TgState};
+use ate::{import, migration, tags, Config, StateLock};
+
+const USAGE: &str = "Offline maintenance of the ate database, stop the bot first!
+
+Usage: ate-admin <command>
+
+  meals list                          List all saved meals
+  meals search <query>                Saved meals whose name contains the query
+  meals show <id|name>                Details of a saved meal
+  meals edit <id> <field> <value>     Set name, rating, url, tags, servings, prep or cook
+  meals delete <id>                   Delete a saved meal
+  import <file> [--replace]           Import meals from JSON, CSV or a schema.org Recipe
+  export <json|csv|md> [file]         Export all meals to a file or stdout
+  whitelist list                      List whitelisted users and admins
+  whitelist add <user> [admin]        Whitelist a user
+  whitelist remove <user>             Remove a user from whitelist and admins
+  state                               Counts of the stored telegram state
+  state dump                          Stored telegram state as json
+  verify                              Check for undecodable records, orphan polls and missing images
+  migrate                             Run the database migrations";
+
+fn main() {
+    pretty_env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let result = match args.as_slice() {
+        ["meals", rest @ ..] => meals(rest),
+        ["import", file] => import_meals(file, false),
+        ["import", file, "--replace"] => import_meals(file, true),
+        ["export", format] => export_meals(format, None),
+        ["export", format, file] => export_meals(format, Some(file)),
+        ["whitelist", rest @ ..] => whitelist(rest),
+        ["state"] => state(false),
+        ["state", "dump"] => state(true),
+        ["verify"] => verify(),
+        ["migrate"] => {
+            // migrations run when the stores are opened
+            StoreHandler::new(false);
+            Ok("Migrations done.".to_string())
+        }
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(text) => println!("{}", text),
+        Err(text) => {
+            eprintln!("{}", text);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn saved_meals(db: &PickleDb) -> Vec<Meal> {
+    db.liter(&DBKeys::Meals.to_string())
+        .filter_map(|item| item.get_item::<Meal>())
+        .collect()
+}
+
+fn find_meal(db: &PickleDb, id_or_name: &str) -> Result<Meal, String> {
+    saved_meals(db)
+        .into_iter()
+        .find(|meal| meal.id == id_or_name || meal.name.to_uppercase() == id_or_name.to_uppercase())
+        .ok_or(format!("No meal {}", id_or_name))
+}
+
+fn meal_line(meal: &Meal) -> String {
+    format!(
+        "{}  {}{}",
+        meal.id,
+        meal.name.to_uppercase(),
+        if meal.tags.len() > 0 {
+            format!("  #{}", meal.tags.join(" #"))
+        } else {
+            "".to_string()
+        }
+    )
+}
+
+fn meals(args: &[&str]) -> Result<String, String> {
+    let mut sh = StoreHandler::new(false);
+    let key = DBKeys::Meals.to_string();
+    match args {
+        ["list"] | [] => {
+            let mut meals = saved_meals(&sh.db);
+            meals.sort_by_key(|meal| meal.name.to_uppercase());
+            Ok(format!(
+                "{} meals\n{}",
+                meals.len(),
+                meals.iter().map(meal_line).collect::<Vec<_>>().join("\n")
+            ))
+        }
+        ["search", query @ ..] => {
+            let query = query.join(" ").to_uppercase();
+            Ok(saved_meals(&sh.db)
+                .iter()
+                .filter(|meal| meal.name.to_uppercase().contains(&query))
+                .map(meal_line)
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+        ["show", id_or_name @ ..] => {
+            let meal = find_meal(&sh.db, &id_or_name.join(" "))?;
+            serde_json::to_string_pretty(&meal).map_err(|err| err.to_string())
+        }
+        ["edit", id, field, value @ ..] => {
+            let meal = find_meal(&sh.db, id)?;
+            let value = value.join(" ");
+            let mut edited = meal.clone();
+            let number = |value: &str| {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| format!("Not a number: {}", value))
+            };
+            match *field {
+                "name" => edited.name = value.clone(),
+                "rating" => match number(&value)? {
+                    rating @ 1..=5 => edited.rating = Some(rating as u8),
+                    _ => return Err("Rating has to be 1-5!".to_string()),
+                },
+                "url" => edited.url = Some(value.clone()).filter(|url| url.len() > 0),
+                "tags" => {
+                    edited.tags = tags::normalize_all(
+                        &value
+                            .split_whitespace()
+                            .map(|tag| tag.to_string())
+                            .collect(),
+                    )
+                }
+                "servings" => edited.servings = Some(number(&value)?.min(255) as u8),
+                "prep" => edited.prep_time = Some(number(&value)?),
+                "cook" => edited.cook_time = Some(number(&value)?),
+                _ => return Err(format!("Unknown field: {}\n\n{}", field, USAGE)),
+            }
+            match sh.db.lrem_value(&key, &meal) {
+                Ok(true) => {
+                    sh.db.ladd(&key, &edited);
+                    Ok(format!("Saved {}", meal_line(&edited)))
+                }
+                Ok(false) => Err(format!("No meal {}", id)),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        ["delete", id] => {
+            let meal = find_meal(&sh.db, id)?;
+            match sh.db.lrem_value(&key, &meal) {
+                Ok(true) => Ok(format!("Deleted {}", meal_line(&meal))),
+                Ok(false) => Err(format!("No meal {}", id)),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn import_meals(file: &str, replace: bool) -> Result<String, String> {
+    let content = std::fs::read_to_string(file).map_err(|err| err.to_string())?;
+    let (format, meals) = import::parse(&content)?;
+    let state = open_state()?;
+    let saved = state.read().get_saved_meals();
+    let duplicates = import::duplicates(&meals, &saved);
+    let imported = import::commit(&state, &meals, replace).map_err(|err| err.to_string())?;
+    Ok(format!(
+        "Imported {} of {} meals ({:?}), {} duplicates {}.",
+        imported,
+        meals.len(),
+        format,
+        duplicates.len(),
+        if replace { "replaced" } else { "skipped" }
+    ))
+}
+
+/// Opens the state like the bot does (without a backup), for commands sharing its write path.
+fn open_state() -> Result<StateLock, String> {
+    let config_str = std::fs::read_to_string("./config.json")
+        .map_err(|err| format!("No config file found: {}", err))?;
+    let mut config: Config =
+        serde_json::from_str(&config_str).map_err(|err| format!("Wrong config file: {}", err))?;
+    config.backup = false;
+    Ok(Arc::new(RwLock::new(State::new(config))))
+}
+
+fn export_meals(format: &str, file: Option<&str>) -> Result<String, String> {
+    let format = ExportFormat::parse(format).ok_or(USAGE.to_string())?;
+    let sh = StoreHandler::new(false);
+    let meals = saved_meals(&sh.db);
+    let content = format.export(&meals);
+    match file {
+        Some(file) => {
+            std::fs::write(file, content).map_err(|err| err.to_string())?;
+            Ok(format!("Exported {} meals to {}", meals.len(), file))
+        }
+        None => Ok(content),
+    }
+}
+
+fn users(db: &PickleDb, key: &DBKeys) -> Vec<String> {
+    db.liter(&key.to_string())
+        .filter_map(|item| item.get_item::<String>())
+        .collect()
+}
+
+fn whitelist(args: &[&str]) -> Result<String, String> {
+    let mut sh = StoreHandler::new(false);
+    let admins = users(&sh.db, &DBKeys::Admins);
+    let whitelisted = users(&sh.db, &DBKeys::Whitelist);
+    match args {
+        ["list"] | [] => Ok(whitelisted
+            .iter()
+            .map(|user| {
+                if admins.contains(user) {
+                    format!("{} (admin)", user)
+                } else {
+                    user.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        ["add", user] | ["add", user, "admin"] => {
+            let user = user.to_string();
+            if !whitelisted.contains(&user) {
+                sh.db.ladd(&DBKeys::Whitelist.to_string(), &user);
+            }
+            let admin = args.len() == 3;
+            if admin && !admins.contains(&user) {
+                sh.db.ladd(&DBKeys::Admins.to_string(), &user);
+            }
+            Ok(format!(
+                "Whitelisted {}{}",
+                user,
+                if admin { " (admin)" } else { "" }
+            ))
+        }
+        ["remove", user] => {
+            let user = user.to_string();
+            let mut removed = false;
+            for key in vec![DBKeys::Whitelist, DBKeys::Admins] {
+                match sh.db.lrem_value(&key.to_string(), &user) {
+                    Ok(rem) => removed = removed || rem,
+                    Err(err) => return Err(err.to_string()),
+                }
+            }
+            if removed {
+                Ok(format!("Removed {}", user))
+            } else {
+                Err(format!("{} is not whitelisted", user))
+            }
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn load_state(sh: &StoreHandler) -> Result<TgState, String> {
+    sh.state_db
+        .get::<TgState>(&DBKeys::State.to_string())
+        .ok_or("No telegram state stored".to_string())
+}
+
+fn state(dump: bool) -> Result<String, String> {
+    let sh = StoreHandler::new(false);
+    let tg = load_state(&sh)?;
+    if dump {
+        return serde_json::to_string_pretty(&tg).map_err(|err| err.to_string());
+    }
+    let open_polls = tg.polls.values().filter(|poll| !poll.is_canceled).count();
+    Ok(format!(
+        "Keyboards: {}\nTemporary meals: {}\nPolls: {} ({} open)\nJobs: {}",
+        tg.keyboards.len(),
+        tg.meals.len(),
+        tg.polls.len(),
+        open_polls,
+        tg.jobs.len()
+    ))
+}
+
+/// Keys of a store whose value does not decode as `T`.
+fn undecodable_keys<T: DeserializeOwned>(db: &PickleDb) -> Vec<String> {
+    db.get_all()
+        .into_iter()
+        .filter(|key| db.get::<T>(key).is_none())
+        .collect()
+}
+
+/// Number of items of a list in the main store that do not decode as `T`.
+fn undecodable_items<T: DeserializeOwned>(db: &PickleDb, key: &DBKeys) -> usize {
+    db.liter(&key.to_string())
+        .filter(|item| item.get_item::<T>().is_none())
+        .count()
+}
+
+/// Checks the stored telegram state before opening the stores upgrades it.
+fn verify_state(problems: &mut Vec<String>) {
+    let path = format!("database/{}.db", DBKeys::State);
+    let db = match PickleDb::load_read_only(&path, SerializationMethod::Json) {
+        Ok(db) => db,
+        Err(_) => return,
+    };
+    let key = DBKeys::State.to_string();
+    if !db.exists(&key) || db.get::<TgState>(&key).is_some() {
+        return;
+    }
+    match db.get::<Value>(&key) {
+        Some(value) => {
+            let (_, dropped) = migration::lenient_state(value);
+            problems.push(format!(
+                "Telegram state does not decode, upgrading it drops {} entries{}",
+                dropped.len(),
+                dropped
+                    .iter()
+                    .map(|entry| format!("\n  {}", entry))
+                    .collect::<String>()
+            ));
+        }
+        None => problems.push("Telegram state is not valid json".to_string()),
+    }
+}
+
+fn verify() -> Result<String, String> {
+    let mut problems = vec![];
+    verify_state(&mut problems);
+    let sh = StoreHandler::new(false);
+    for (key, count) in vec![
+        (
+            DBKeys::Meals,
+            undecodable_items::<Meal>(&sh.db, &DBKeys::Meals),
+        ),
+        (
+            DBKeys::Whitelist,
+            undecodable_items::<String>(&sh.db, &DBKeys::Whitelist),
+        ),
+        (
+            DBKeys::Admins,
+            undecodable_items::<String>(&sh.db, &DBKeys::Admins),
+        ),
+        (
+            DBKeys::Invites,
+            undecodable_items::<Invite>(&sh.db, &DBKeys::Invites),
+        ),
+    ] {
+        if count > 0 {
+            problems.push(format!("{} undecodable {} records", count, key));
+        }
+    }
+    for (key, keys) in vec![
+        (DBKeys::Plans, undecodable_keys::<Plan>(&sh.plan_db)),
+        (
+            DBKeys::Shopping,
+            undecodable_keys::<ShoppingList>(&sh.shopping_db),
+        ),
+        (
+            DBKeys::Settings,
+            undecodable_keys::<ChatSettings>(&sh.settings_db),
+        ),
+        (
+            DBKeys::History,
+            undecodable_keys::<Vec<Plan>>(&sh.history_db),
+        ),
+        (
+            DBKeys::Cooking,
+            undecodable_keys::<Vec<Cooked>>(&sh.cooking_db),
+        ),
+        (
+            DBKeys::Nutrition,
+            undecodable_keys::<String>(&sh.nutrition_db),
+        ),
+        (DBKeys::Tags, undecodable_keys::<String>(&sh.tags_db)),
+        (DBKeys::Pantry, undecodable_keys::<Pantry>(&sh.pantry_db)),
+    ] {
+        if keys.len() > 0 {
+            problems.push(format!("Undecodable {} records: {}", key, keys.join(", ")));
+        }
+    }
+    let meals = saved_meals(&sh.db);
+    let meal_ids: HashSet<&String> = meals.iter().map(|meal| &meal.id).collect();
+    if let Ok(tg) = load_state(&sh) {
+        for poll in tg.polls.values() {
+            if let PollKind::Meal { meal_id, .. } = &poll.poll_kind {
+                if !meal_ids.contains(meal_id) && !tg.meals.contains_key(meal_id) {
+                    problems.push(format!(
+                        "Poll {} refers to missing meal {}",
+                        poll.id, meal_id
+                    ));
+                }
+            }
+        }
+    }
+    for meal in meals.iter() {
+        for image in meal.images.iter().filter(|image| !image.exists()) {
+            problems.push(format!(
+                "Image {} of {} is missing",
+                image.path().display(),
+                meal_line(meal)
+            ));
+        }
+        let unstored = meal
+            .photos
+            .iter()
+            .filter(|photo| meal.image_of(photo).is_none())
+            .count();
+        if unstored > 0 {
+            problems.push(format!(
+                "{} photos of {} are not stored",
+                unstored,
+                meal_line(meal)
+            ));
+        }
+    }
+    if problems.len() == 0 {
+        Ok(format!("{} meals, no problems found.", meals.len()))
+    } else {
+        Err(problems.join("\n"))
+    }
+}
@@ -1,17 +1,25 @@
+use chrono::{Duration as DateDuration, Local};
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use teloxide::dispatching::UpdateWithCx;
 use teloxide::types::{
-    ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MediaKind, Message, MessageCommon,
-    MessageKind, ReplyMarkup,
+    ChatId, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaPhoto,
+    MediaKind, Message, MessageCommon, MessageKind, ReplyMarkup,
 };
+use teloxide::Bot;
 
 use crate::command::Command;
+use crate::error::Error;
+use crate::history;
+use crate::import;
 use crate::keyboard::Keyboard;
 use crate::meal::Meal;
+use crate::outbox::{self, Limit};
 use crate::plan::Plan;
 use crate::poll::PollKind;
-use crate::request::{RequestKind, RequestResult};
+use crate::request::{Hook, RequestKind, RequestResult};
+use crate::tags;
 use crate::{ContextCallback, StateLock};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,20 +54,124 @@ impl Button {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ButtonKind {
-    DisplayPlanMeal { meal: Meal, plan: Plan },
-    DisplayListMeal { meal: Meal },
+    DisplayPlanMeal {
+        meal: Meal,
+        plan: Plan,
+    },
+    DisplayListMeal {
+        meal: Meal,
+    },
     ShowList,
-    ShowPlan { plan: Plan },
-    RerollPlan { plan: Plan },
-    ClearVotes { plan: Plan },
-    SaveMeal { meal_id: String },
-    RateMeal { meal_id: String, rating: u8 },
-    CancelMeal { meal_id: String },
-    DeleteMeal { meal: Meal },
-    PollRating { meal: Meal },
-    SavePollRating { meal_id: String },
-    CancelPollRating { meal_id: String },
-    CommandButton { command: Command },
+    ShowPlan {
+        plan: Plan,
+    },
+    RerollPlan {
+        plan: Plan,
+    },
+    ClearVotes {
+        plan: Plan,
+    },
+    SaveMeal {
+        meal_id: String,
+    },
+    RateMeal {
+        meal_id: String,
+        rating: u8,
+    },
+    CancelMeal {
+        meal_id: String,
+    },
+    DeleteMeal {
+        meal: Meal,
+    },
+    PollRating {
+        meal: Meal,
+    },
+    SavePollRating {
+        meal_id: String,
+    },
+    CancelPollRating {
+        meal_id: String,
+    },
+    SetPollDeadline {
+        meal_id: String,
+        hours: Option<i64>,
+    },
+    StopPoll,
+    CommandButton {
+        command: Command,
+    },
+    CookStep {
+        meal_id: String,
+        step: usize,
+    },
+    StartTimer {
+        meal_name: String,
+        step: usize,
+        minutes: u32,
+    },
+    TickShoppingItem {
+        index: usize,
+    },
+    TogglePlanLock {
+        plan_id: String,
+        entry: usize,
+    },
+    SwapPlanEntry {
+        plan_id: String,
+        entry: usize,
+    },
+    PickPlanMeal {
+        plan_id: String,
+        entry: usize,
+        page: usize,
+    },
+    SetPlanMeal {
+        plan_id: String,
+        entry: usize,
+        meal_id: String,
+    },
+    PlanKeyboard {
+        plan_id: String,
+    },
+    ShowPlanHistory {
+        page: usize,
+    },
+    ShowArchivedPlan {
+        plan_id: String,
+    },
+    ReusePlan {
+        plan_id: String,
+    },
+    CloneWeek {
+        plan_id: String,
+    },
+    ToggleTag {
+        meal_id: String,
+        tag: String,
+    },
+    ConfirmImport {
+        import_id: String,
+        replace: bool,
+    },
+    CancelImport {
+        import_id: String,
+    },
+    ShowPhoto {
+        meal_id: String,
+        index: usize,
+    },
+    SetCoverPhoto {
+        meal_id: String,
+        file_unique_id: String,
+    },
+    DeletePhoto {
+        meal_id: String,
+        file_unique_id: String,
+    },
+    SendAlbum {
+        meal_id: String,
+    },
     PinMessage,
     DeleteMessage,
 }
@@ -110,14 +222,18 @@ impl ButtonKind {
         result
     }
 
-    pub fn run(button: &ButtonKind, state: &StateLock, cx: &ContextCallback) -> RequestResult {
-        match button {
+    pub fn run(
+        button: &ButtonKind,
+        state: &StateLock,
+        cx: &ContextCallback,
+    ) -> Result<RequestResult, Error> {
+        Ok(match button {
             ButtonKind::SaveMeal { meal_id } => {
                 let meals = state.read().meals().clone();
                 let meal_opt = meals.get(meal_id).clone();
                 match meal_opt {
                     Some(meal) => {
-                        state.write().save_meal(&meal);
+                        state.write().save_meal(&meal)?;
                         state.write().meals_mut().remove(&meal.id);
                         Self::edit_callback_text(&cx, format!("{}\n\nSaved!", meal), None)
                     }
@@ -128,6 +244,83 @@ impl ButtonKind {
                     ),
                 }
             }
+            ButtonKind::ShowPhoto { meal_id, index } => {
+                let meal_opt = state.read().get_saved_meal(meal_id.clone());
+                match meal_opt {
+                    Some(meal) => gallery_request(cx, state, &meal, *index),
+                    None => return Err(Error::NotFound("Meal".to_string())),
+                }
+            }
+            ButtonKind::SetCoverPhoto {
+                meal_id,
+                file_unique_id,
+            } => {
+                let edited = state.write().edit_saved_meal(meal_id, |meal| {
+                    meal.set_cover(file_unique_id);
+                })?;
+                match edited {
+                    Some(meal) => {
+                        let index = meal.cover_index().unwrap_or(0);
+                        gallery_request(cx, state, &meal, index)
+                    }
+                    None => return Err(Error::NotFound("Meal".to_string())),
+                }
+            }
+            ButtonKind::DeletePhoto {
+                meal_id,
+                file_unique_id,
+            } => {
+                let index = state
+                    .read()
+                    .get_saved_meal(meal_id.clone())
+                    .and_then(|meal| {
+                        meal.photos
+                            .iter()
+                            .position(|photo| &photo.file_unique_id == file_unique_id)
+                    })
+                    .unwrap_or(0);
+                let edited = state.write().edit_saved_meal(meal_id, |meal| {
+                    meal.remove_photo(file_unique_id);
+                })?;
+                match edited {
+                    Some(meal) if meal.photos.len() > 0 => {
+                        gallery_request(cx, state, &meal, index.min(meal.photos.len() - 1))
+                    }
+                    Some(meal) => Self::edit_callback_text(
+                        &cx,
+                        format!("{}\n\nDeleted last photo!", meal),
+                        None,
+                    ),
+                    None => return Err(Error::NotFound("Meal".to_string())),
+                }
+            }
+            ButtonKind::SendAlbum { meal_id } => {
+                let mut request = RequestResult::default();
+                let meal_opt = state.read().get_saved_meal(meal_id.clone());
+                if let (Some(meal), Some(message)) = (meal_opt, &cx.update.message) {
+                    let media: Vec<InputMedia> = meal
+                        .photos
+                        .iter()
+                        .enumerate()
+                        .map(|(i, photo)| {
+                            let media =
+                                InputMediaPhoto::new(InputFile::FileId(photo.file_id.clone()));
+                            // telegram shows the caption of the first photo for the album
+                            InputMedia::Photo(if i == 0 {
+                                media.caption(meal.name.to_uppercase())
+                            } else {
+                                media
+                            })
+                        })
+                        .collect();
+                    if media.len() > 0 {
+                        request.add(RequestKind::MediaGroup(
+                            cx.bot.send_media_group(message.chat_id(), media),
+                        ));
+                    }
+                }
+                request
+            }
             ButtonKind::PinMessage => {
                 let mut result = RequestResult::default();
                 if let Some(message) = &cx.update.message {
@@ -142,45 +335,92 @@ impl ButtonKind {
                 Self::edit_callback_text(&cx, "Canceled!".to_string(), None)
             }
             ButtonKind::RateMeal { meal_id, rating } => {
-                let rated_meal = state.write().rate_meal(meal_id.clone(), rating.clone());
+                let rated_meal = state.write().rate_meal(meal_id.clone(), rating.clone())?;
                 log::info!("Rated meal: {:?}", rated_meal);
                 Self::edit_callback_text(
                     &cx,
-                    match rated_meal {
-                        Ok(meal) => format!("{}\n\nChange rating or save your meal!", meal),
-                        Err(()) => {
-                            log::warn!("Meal not found: {}", meal_id);
-                            "No meal to rate found!".to_string()
-                        }
-                    },
+                    format!("{}\n\nChange rating or save your meal!", rated_meal),
                     Some(
                         Keyboard::new()
-                            .buttons(vec![
-                                rate_meal_button_row(*rating, meal_id),
-                                save_meal_button_row(meal_id),
-                            ])
+                            .buttons(new_meal_buttons(state, meal_id, *rating, Some(&rated_meal)))
                             .save(state)
                             .inline_keyboard(),
                     ),
                 )
             }
-            ButtonKind::DeleteMeal { meal } => Self::edit_callback_text(
-                &cx,
-                match state.write().remove_saved_meal(&meal) {
-                    Ok(b) => {
-                        if b {
-                            format!("{}\n\nRemoved!", meal,)
-                        } else {
-                            format!("{}\n\nNot Found!", meal,)
-                        }
+            ButtonKind::ConfirmImport { import_id, replace } => {
+                let meals = state.write().imports.remove(import_id);
+                match meals {
+                    Some(meals) => {
+                        let imported = import::commit(state, &meals, *replace)?;
+                        Self::edit_callback_text(&cx, format!("Imported {} meals!", imported), None)
                     }
-                    Err(err) => {
-                        log::warn!("Delete Meal: {}", err);
-                        format!("{}\n\nSomething went wrong!", meal)
+                    None => {
+                        return Err(Error::NotFound("Import (send the file again)".to_string()))
                     }
-                },
-                None,
-            ),
+                }
+            }
+            ButtonKind::CancelImport { import_id } => {
+                state.write().imports.remove(import_id);
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    request.add(RequestKind::DeleteMessage(
+                        cx.bot.delete_message(message.chat_id(), message.id),
+                    ));
+                }
+                request
+            }
+            ButtonKind::ToggleTag { meal_id, tag } => {
+                let toggled = state.write().toggle_meal_tag(meal_id.clone(), tag);
+                match toggled {
+                    // meal that is being created
+                    Ok(meal) => Self::edit_callback_text(
+                        &cx,
+                        format!("{}\n\nChange rating or save your meal!", meal),
+                        Some(
+                            Keyboard::new()
+                                .buttons(new_meal_buttons(
+                                    state,
+                                    meal_id,
+                                    meal.rating.unwrap_or(0),
+                                    Some(&meal),
+                                ))
+                                .save(state)
+                                .inline_keyboard(),
+                        ),
+                    ),
+                    Err(_) => {
+                        let edited = state.write().edit_saved_meal(meal_id, |meal| {
+                            meal.toggle_tag(tag);
+                        })?;
+                        match edited {
+                            Some(meal) => Self::edit_callback_text(
+                                &cx,
+                                format!("{}\n\nToggle tags:", meal),
+                                Some(
+                                    Keyboard::new()
+                                        .buttons(edit_tags_buttons(state, &meal))
+                                        .save(state)
+                                        .inline_keyboard(),
+                                ),
+                            ),
+                            None => return Err(Error::NotFound("Meal".to_string())),
+                        }
+                    }
+                }
+            }
+            ButtonKind::DeleteMeal { meal } => {
+                let removed = state.write().remove_saved_meal(&meal)?;
+                Self::edit_callback_text(
+                    &cx,
+                    if removed {
+                        format!("{}\n\nRemoved!", meal)
+                    } else {
+                        format!("{}\n\nNot Found!", meal)
+                    },
+                    None,
+                )
+            }
             ButtonKind::DisplayPlanMeal { meal, .. } => {
                 let mut request = RequestResult::default();
                 if let Some(message) = &cx.update.message {
@@ -211,7 +451,7 @@ impl ButtonKind {
             }
             ButtonKind::ShowPlan { plan } => Self::edit_callback_text(
                 &cx,
-                format!("Plan:\n(Click to see details)"),
+                plan.calendar(),
                 Some(
                     Keyboard::new()
                         .buttons(poll_plan_buttons(plan.clone()))
@@ -221,58 +461,211 @@ impl ButtonKind {
             ),
             ButtonKind::RerollPlan { plan } => {
                 let mut request = RequestResult::default();
-                let meals = state.read().get_saved_meals();
-                let meal_plan = Plan::gen(meals, plan.days);
                 if let Some(message) = &cx.update.message {
-                    state
-                        .write()
-                        .save_plan(message.chat_id(), meal_plan.clone());
-
-                    let keyboard = Keyboard::new()
-                        .buttons(poll_plan_buttons(meal_plan.clone()))
-                        .save(&state);
-                    request
-                        .add(RequestKind::DeleteMessage(
-                            cx.bot.delete_message(message.chat_id(), message.id),
-                        ))
-                        .add(RequestKind::Poll(
-                            cx.bot
-                                .send_poll(
-                                    message.chat_id(),
-                                    format!("Plan:\n(Click to see details)"),
-                                    meal_plan.answers(),
-                                )
-                                .reply_markup(ReplyMarkup::InlineKeyboardMarkup(
-                                    keyboard.inline_keyboard(),
-                                )),
-                            PollKind::Plan { plan: meal_plan },
-                            keyboard.id,
-                        ));
+                    match current_plan(state, message, &plan.id) {
+                        Some(mut meal_plan) => {
+                            let meals = state.read().get_saved_meals_for(message.chat_id());
+                            let recent = state.read().recent_meal_ids(message.chat_id());
+                            meal_plan.reroll(meals, &recent);
+                            state
+                                .write()
+                                .save_plan(message.chat_id(), meal_plan.clone())?;
+                            request
+                                .add(RequestKind::DeleteMessage(
+                                    cx.bot.delete_message(message.chat_id(), message.id),
+                                ))
+                                .append(plan_request(&cx.bot, message.chat_id(), meal_plan, state));
+                        }
+                        None => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
+                }
+                request
+            }
+            ButtonKind::TogglePlanLock { plan_id, entry } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    match current_plan(state, message, plan_id) {
+                        Some(mut plan) => {
+                            plan.toggle_lock(*entry);
+                            state.write().save_plan(message.chat_id(), plan.clone())?;
+                            request.add(edit_keyboard(cx, message, poll_plan_buttons(plan), state));
+                        }
+                        None => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
+                }
+                request
+            }
+            ButtonKind::SwapPlanEntry { plan_id, entry } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    match current_plan(state, message, plan_id) {
+                        Some(mut plan) => {
+                            let meals = state.read().get_saved_meals_for(message.chat_id());
+                            let recent = state.read().recent_meal_ids(message.chat_id());
+                            plan.reroll_entry(*entry, meals, &recent);
+                            state.write().save_plan(message.chat_id(), plan.clone())?;
+                            request
+                                .add(RequestKind::DeleteMessage(
+                                    cx.bot.delete_message(message.chat_id(), message.id),
+                                ))
+                                .append(plan_request(&cx.bot, message.chat_id(), plan, state));
+                        }
+                        None => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
+                }
+                request
+            }
+            ButtonKind::PickPlanMeal {
+                plan_id,
+                entry,
+                page,
+            } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    match current_plan(state, message, plan_id) {
+                        Some(plan) => {
+                            let meals = state.read().get_saved_meals_for(message.chat_id());
+                            request.add(edit_keyboard(
+                                cx,
+                                message,
+                                pick_meal_buttons(&plan, *entry, *page, meals),
+                                state,
+                            ));
+                        }
+                        None => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
+                }
+                request
+            }
+            ButtonKind::SetPlanMeal {
+                plan_id,
+                entry,
+                meal_id,
+            } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let meal_opt = state.read().get_saved_meal(meal_id.clone());
+                    match (current_plan(state, message, plan_id), meal_opt) {
+                        (Some(mut plan), Some(meal)) => {
+                            plan.set_entry_meal(*entry, meal);
+                            state.write().save_plan(message.chat_id(), plan.clone())?;
+                            request
+                                .add(RequestKind::DeleteMessage(
+                                    cx.bot.delete_message(message.chat_id(), message.id),
+                                ))
+                                .append(plan_request(&cx.bot, message.chat_id(), plan, state));
+                        }
+                        _ => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
+                }
+                request
+            }
+            ButtonKind::PlanKeyboard { plan_id } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    match current_plan(state, message, plan_id) {
+                        Some(plan) => {
+                            request.add(edit_keyboard(cx, message, poll_plan_buttons(plan), state));
+                        }
+                        None => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
+                }
+                request
+            }
+            ButtonKind::ShowPlanHistory { page } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let plans = state.read().get_archived_plans(message.chat_id());
+                    request = Self::edit_callback_text(
+                        &cx,
+                        history::statistics(&plans),
+                        Some(
+                            Keyboard::new()
+                                .buttons(history::history_buttons(&plans, *page))
+                                .save(&state)
+                                .inline_keyboard(),
+                        ),
+                    );
+                }
+                request
+            }
+            ButtonKind::ShowArchivedPlan { plan_id } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let plan_opt = state.read().get_archived_plan(message.chat_id(), plan_id);
+                    request = match plan_opt {
+                        Some(plan) => Self::edit_callback_text(
+                            &cx,
+                            plan.calendar(),
+                            Some(
+                                Keyboard::new()
+                                    .buttons(history::archived_plan_buttons(&plan))
+                                    .save(&state)
+                                    .inline_keyboard(),
+                            ),
+                        ),
+                        None => Self::edit_callback_text(&cx, "Plan not found!".to_string(), None),
+                    };
+                }
+                request
+            }
+            ButtonKind::ReusePlan { plan_id } | ButtonKind::CloneWeek { plan_id } => {
+                let mut request = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let plan_opt = state.read().get_archived_plan(message.chat_id(), plan_id);
+                    match plan_opt {
+                        Some(archived) => {
+                            let today = Local::today().naive_local();
+                            let start = match button {
+                                ButtonKind::CloneWeek { .. } => {
+                                    let after = state
+                                        .read()
+                                        .get_plan(message.chat_id())
+                                        .map(|current| current.end())
+                                        .unwrap_or(today - DateDuration::days(1));
+                                    history::clone_start(&archived, after)
+                                }
+                                _ => today,
+                            };
+                            let plan = archived.reuse(start);
+                            state.write().save_plan(message.chat_id(), plan.clone())?;
+                            request
+                                .add(RequestKind::DeleteMessage(
+                                    cx.bot.delete_message(message.chat_id(), message.id),
+                                ))
+                                .append(plan_request(&cx.bot, message.chat_id(), plan, state));
+                        }
+                        None => {
+                            request.add(outdated_plan_answer(cx));
+                        }
+                    }
                 }
                 request
             }
             ButtonKind::ClearVotes { plan } => {
                 let mut request = RequestResult::default();
                 if let Some(message) = &cx.update.message {
-                    let keyboard = Keyboard::new()
-                        .buttons(poll_plan_buttons(plan.clone()))
-                        .save(&state);
                     request
                         .add(RequestKind::DeleteMessage(
                             cx.bot.delete_message(message.chat_id(), message.id),
                         ))
-                        .add(RequestKind::Poll(
-                            cx.bot
-                                .send_poll(
-                                    message.chat_id(),
-                                    format!("Plan:\n(Click to see details)"),
-                                    plan.answers(),
-                                )
-                                .reply_markup(ReplyMarkup::InlineKeyboardMarkup(
-                                    keyboard.inline_keyboard(),
-                                )),
-                            PollKind::Plan { plan: plan.clone() },
-                            keyboard.id,
+                        .append(plan_request(
+                            &cx.bot,
+                            message.chat_id(),
+                            plan.clone(),
+                            state,
                         ));
                 }
                 request
@@ -288,14 +681,18 @@ impl ButtonKind {
                 Self::edit_callback_text(&cx, format!("{}", meal), Some(keyboard))
             }
             ButtonKind::ShowList => {
-                let meal_btns: Vec<Vec<Button>> = state
-                    .read()
-                    .get_saved_meals()
+                let meals = match &cx.update.message {
+                    Some(message) => state.read().get_saved_meals_for(message.chat_id()),
+                    None => state.read().get_saved_meals(),
+                };
+                let meal_btns: Vec<Vec<Button>> = meals
                     .iter()
                     .map(|meal| {
                         vec![Button::new(
                             meal.name.clone(),
-                            ButtonKind::DisplayListMeal { meal: meal.clone() },
+                            ButtonKind::DisplayListMeal {
+                                meal: meal.embedded(),
+                            },
                         )]
                     })
                     .collect();
@@ -316,7 +713,96 @@ impl ButtonKind {
                     bot: cx.bot.clone(),
                     update: cx.update.message.as_ref().cloned().unwrap(),
                 },
-            ),
+            )?,
+            ButtonKind::CookStep { meal_id, step } => {
+                let meal_opt = state.read().get_saved_meal(meal_id.clone());
+                match meal_opt {
+                    Some(meal) => Self::edit_callback_text(
+                        &cx,
+                        meal.step_text(*step),
+                        Some(
+                            Keyboard::new()
+                                .buttons(cook_step_buttons(&meal, *step))
+                                .save(&state)
+                                .inline_keyboard(),
+                        ),
+                    ),
+                    None => return Err(Error::NotFound("Meal".to_string())),
+                }
+            }
+            ButtonKind::StartTimer {
+                meal_name,
+                step,
+                minutes,
+            } => {
+                let mut result = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let bot = cx.bot.clone();
+                    let state = state.clone();
+                    let chat_id = message.chat_id();
+                    let text = format!(
+                        "Timer for {} (step {}) is up!",
+                        meal_name.to_uppercase(),
+                        step + 1
+                    );
+                    let duration = Duration::from_secs(*minutes as u64 * 60);
+                    tokio::spawn(async move {
+                        tokio::time::delay_for(duration).await;
+                        let _ = outbox::send(
+                            &state,
+                            Limit::Send(chat_id),
+                            &bot.send_message(chat_id, text),
+                            "Timer",
+                        )
+                        .await;
+                    });
+                    result.add(RequestKind::CallbackAnswer(
+                        cx.bot
+                            .answer_callback_query(cx.update.id.clone())
+                            .text(format!("Timer set for {} min.", minutes)),
+                    ));
+                }
+                result
+            }
+            ButtonKind::TickShoppingItem { index } => {
+                let mut result = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let list_opt = state.read().get_shopping_list(message.chat_id());
+                    if let Some(mut list) = list_opt {
+                        let sync = state.read().get_settings(message.chat_id()).pantry_sync;
+                        let mut pantry = state.read().get_pantry(message.chat_id());
+                        let mut pantry_changed = false;
+                        if let Some(item) = list.toggle(*index) {
+                            if item.ticked && sync {
+                                let created = pantry.add(item.ingredient.clone(), None);
+                                // an item without quantity only counts if it was not there before
+                                if created || item.ingredient.quantity.is_some() {
+                                    item.in_pantry = Some(item.ingredient.clone());
+                                }
+                                pantry_changed = true;
+                            } else if let Some(added) = item.in_pantry.take() {
+                                // only what the tick added is taken out again
+                                pantry_changed = pantry.undo_add(&added);
+                            }
+                        }
+                        state.write().save_shopping_list(message.chat_id(), &list)?;
+                        if pantry_changed {
+                            state.write().save_pantry(message.chat_id(), &pantry)?;
+                        }
+                        result = Self::edit_callback_text(
+                            &cx,
+                            list.text(),
+                            Some(
+                                Keyboard::new()
+                                    .buttons(list.buttons())
+                                    .save(&state)
+                                    .inline_keyboard(),
+                            ),
+                        );
+                    }
+                }
+                result
+            }
             ButtonKind::PollRating { meal } => {
                 let mut result = RequestResult::default();
                 if let Some(message) = &cx.update.message {
@@ -330,12 +816,15 @@ impl ButtonKind {
                         format!("{}\n\nVoting...", meal),
                     )));
                     let keyboard = Keyboard::new()
-                        .buttons(vec![vec![Button::new(
-                            "Cancel".to_uppercase(),
-                            ButtonKind::CancelPollRating {
-                                meal_id: meal.id.clone(),
-                            },
-                        )]])
+                        .buttons(vec![
+                            poll_deadline_button_row(&meal.id),
+                            vec![Button::new(
+                                "Cancel".to_uppercase(),
+                                ButtonKind::CancelPollRating {
+                                    meal_id: meal.id.clone(),
+                                },
+                            )],
+                        ])
                         .save(state);
                     result.add(RequestKind::Poll(
                         cx.bot
@@ -375,6 +864,49 @@ impl ButtonKind {
                 }
                 result
             }
+            ButtonKind::SetPollDeadline { meal_id, hours } => {
+                let mut result = RequestResult::default();
+                let poll_opt = state
+                    .read()
+                    .polls()
+                    .values()
+                    .find(|p| match &p.poll_kind {
+                        PollKind::Meal { meal_id: id, .. } => id == meal_id,
+                        _ => false,
+                    })
+                    .cloned();
+                if let Some(poll) = poll_opt {
+                    poll.deadline(*hours).save(state);
+                    result.add(RequestKind::CallbackAnswer(
+                        cx.bot
+                            .answer_callback_query(cx.update.id.clone())
+                            .text(match hours {
+                                Some(hours) => format!("Poll closes after {}h.", hours),
+                                None => "Poll stays open.".to_string(),
+                            }),
+                    ));
+                }
+                result
+            }
+            ButtonKind::StopPoll => {
+                let mut result = RequestResult::default();
+                if let Some(message) = &cx.update.message {
+                    let poll_opt = state
+                        .read()
+                        .polls()
+                        .values()
+                        .find(|p| {
+                            p.chat_id == ChatId::Id(message.chat_id()) && p.message_id == message.id
+                        })
+                        .cloned();
+                    if let Some(poll) = poll_opt {
+                        result.add(RequestKind::StopPoll(
+                            cx.bot.stop_poll(poll.chat_id, poll.message_id),
+                        ));
+                    }
+                }
+                result
+            }
             ButtonKind::CancelPollRating { meal_id } => {
                 let mut result = RequestResult::default();
                 if let Some((_, mut poll)) =
@@ -394,11 +926,35 @@ impl ButtonKind {
                 }
                 result
             }
-        }
+        })
     }
-    pub fn execute(&self, state: &StateLock, cx: &ContextCallback) -> RequestResult {
+
+    pub fn execute(&self, state: &StateLock, cx: &ContextCallback) -> Result<RequestResult, Error> {
         Self::run(self, state, cx)
     }
+
+    /// Meal a button belongs to, used to find the meal of a meal card.
+    pub fn meal_id(&self) -> Option<String> {
+        match self {
+            ButtonKind::DisplayPlanMeal { meal, .. }
+            | ButtonKind::DisplayListMeal { meal }
+            | ButtonKind::DeleteMeal { meal }
+            | ButtonKind::PollRating { meal } => Some(meal.id.clone()),
+            ButtonKind::SaveMeal { meal_id }
+            | ButtonKind::RateMeal { meal_id, .. }
+            | ButtonKind::CancelMeal { meal_id }
+            | ButtonKind::SavePollRating { meal_id }
+            | ButtonKind::CancelPollRating { meal_id }
+            | ButtonKind::SetPollDeadline { meal_id, .. }
+            | ButtonKind::CookStep { meal_id, .. }
+            | ButtonKind::ToggleTag { meal_id, .. }
+            | ButtonKind::ShowPhoto { meal_id, .. }
+            | ButtonKind::SetCoverPhoto { meal_id, .. }
+            | ButtonKind::DeletePhoto { meal_id, .. }
+            | ButtonKind::SendAlbum { meal_id } => Some(meal_id.clone()),
+            _ => None,
+        }
+    }
 }
 
 pub fn rate_meal_button_row(rating: u8, meal_id: &String) -> Vec<Button> {
@@ -416,6 +972,44 @@ pub fn rate_meal_button_row(rating: u8, meal_id: &String) -> Vec<Button> {
         .collect()
 }
 
+/// Rating, tag and save buttons of a meal that is being created.
+pub fn new_meal_buttons(
+    state: &StateLock,
+    meal_id: &String,
+    rating: u8,
+    meal: Option<&Meal>,
+) -> Vec<Vec<Button>> {
+    let mut buttons = vec![rate_meal_button_row(rating, meal_id)];
+    if let Some(meal) = meal {
+        let counts = state.read().tag_counts();
+        buttons.append(&mut tags::tag_buttons(meal, &counts));
+    }
+    buttons.push(save_meal_button_row(meal_id));
+    buttons
+}
+
+/// Poll rating, tag and save buttons of a meal created in one go.
+pub fn new_meal_keyboard(meal: &Meal, counts: &Vec<(String, usize)>) -> Vec<Vec<Button>> {
+    let mut buttons = vec![vec![Button::new(
+        "Rate with Poll".into(),
+        ButtonKind::PollRating { meal: meal.clone() },
+    )]];
+    buttons.append(&mut tags::tag_buttons(meal, counts));
+    buttons.push(save_meal_button_row(&meal.id));
+    buttons
+}
+
+/// Tag buttons of a saved meal.
+pub fn edit_tags_buttons(state: &StateLock, meal: &Meal) -> Vec<Vec<Button>> {
+    let counts = state.read().tag_counts();
+    let mut buttons = tags::tag_buttons(meal, &counts);
+    buttons.push(vec![Button::new(
+        "Done".to_uppercase(),
+        ButtonKind::DeleteMessage,
+    )]);
+    buttons
+}
+
 pub fn save_meal_button_row(meal_id: &String) -> Vec<Button> {
     let save_button = Button::new(
         "Save Meal".to_uppercase(),
@@ -443,17 +1037,305 @@ pub fn save_poll_button_row(meal: &Meal) -> Vec<Button> {
     vec![save_button, cancel_button]
 }
 
-pub fn poll_plan_buttons(plan: Plan) -> Vec<Vec<Button>> {
-    let meal_info = plan.buttons();
-    vec![
-        meal_info.concat(),
-        vec![
+pub fn poll_deadline_button_row(meal_id: &String) -> Vec<Button> {
+    vec![Some(1), Some(24), None]
+        .into_iter()
+        .map(|hours| {
+            Button::new(
+                match hours {
+                    Some(hours) => format!("⏱ {}h", hours),
+                    None => "⏱ Off".to_string(),
+                },
+                ButtonKind::SetPollDeadline {
+                    meal_id: meal_id.clone(),
+                    hours,
+                },
+            )
+        })
+        .collect()
+}
+
+pub fn cook_step_buttons(meal: &Meal, step: usize) -> Vec<Vec<Button>> {
+    let mut navigation = vec![];
+    if step > 0 {
+        navigation.push(Button::new(
+            "Previous".to_string(),
+            ButtonKind::CookStep {
+                meal_id: meal.id.clone(),
+                step: step - 1,
+            },
+        ));
+    }
+    if step < meal.steps.len() {
+        navigation.push(Button::new(
+            if step + 1 < meal.steps.len() {
+                "Next"
+            } else {
+                "Done"
+            }
+            .to_string(),
+            ButtonKind::CookStep {
+                meal_id: meal.id.clone(),
+                step: step + 1,
+            },
+        ));
+    }
+    let mut buttons = vec![navigation];
+    if let Some(minutes) = meal.step_timer(step) {
+        buttons.push(vec![Button::new(
+            format!("Timer {} min", minutes),
+            ButtonKind::StartTimer {
+                meal_name: meal.name.clone(),
+                step,
+                minutes,
+            },
+        )]);
+    }
+    buttons.push(vec![Button::new(
+        "Exit".to_string(),
+        ButtonKind::DeleteMessage,
+    )]);
+    buttons
+}
+
+/// The chat's current plan, if the pressed buttons still belong to it.
+fn current_plan(state: &StateLock, message: &Message, plan_id: &String) -> Option<Plan> {
+    state
+        .read()
+        .get_plan(message.chat_id())
+        .filter(|plan| &plan.id == plan_id)
+}
+
+fn outdated_plan_answer(cx: &ContextCallback) -> RequestKind {
+    RequestKind::CallbackAnswer(
+        cx.bot
+            .answer_callback_query(cx.update.id.clone())
+            .text("Outdated plan!\nPlease rerun command.")
+            .show_alert(true),
+    )
+}
+
+/// Buttons of a meal card browsing the photos of a saved meal.
+pub fn gallery_buttons(meal: &Meal, index: usize) -> Vec<Vec<Button>> {
+    let mut buttons = vec![];
+    let count = meal.photos.len();
+    if count > 1 {
+        buttons.push(vec![
+            Button::new(
+                "◀".to_string(),
+                ButtonKind::ShowPhoto {
+                    meal_id: meal.id.clone(),
+                    index: (index + count - 1) % count,
+                },
+            ),
+            Button::new(
+                "▶".to_string(),
+                ButtonKind::ShowPhoto {
+                    meal_id: meal.id.clone(),
+                    index: (index + 1) % count,
+                },
+            ),
+        ]);
+    }
+    if let Some(photo) = meal.photos.get(index) {
+        buttons.push(vec![
+            Button::new(
+                if meal.cover_index() == Some(index) {
+                    "✓ Cover".to_string()
+                } else {
+                    "Set Cover".to_string()
+                },
+                ButtonKind::SetCoverPhoto {
+                    meal_id: meal.id.clone(),
+                    file_unique_id: photo.file_unique_id.clone(),
+                },
+            ),
             Button::new(
-                "Reroll".to_string(),
-                ButtonKind::RerollPlan { plan: plan.clone() },
+                "Delete Photo".to_string(),
+                ButtonKind::DeletePhoto {
+                    meal_id: meal.id.clone(),
+                    file_unique_id: photo.file_unique_id.clone(),
+                },
+            ),
+        ]);
+    }
+    let mut last_row = vec![];
+    if count > 1 {
+        last_row.push(Button::new(
+            "Album".to_string(),
+            ButtonKind::SendAlbum {
+                meal_id: meal.id.clone(),
+            },
+        ));
+    }
+    last_row.push(Button::new("Cancel".to_string(), ButtonKind::DeleteMessage));
+    buttons.push(last_row);
+    buttons
+}
+
+/// Swaps the photo of a gallery card.
+fn gallery_request(
+    cx: &ContextCallback,
+    state: &StateLock,
+    meal: &Meal,
+    index: usize,
+) -> RequestResult {
+    let mut request = RequestResult::default();
+    let index = index.min(meal.photos.len().saturating_sub(1));
+    let keyboard = Keyboard::new()
+        .buttons(gallery_buttons(meal, index))
+        .save(state)
+        .inline_keyboard();
+    match (meal.photos.get(index), &cx.update.message) {
+        (Some(photo), Some(message)) => {
+            request.add(RequestKind::EditMedia(
+                cx.bot
+                    .edit_message_media(
+                        ChatId::Id(message.chat_id()),
+                        message.id,
+                        InputMedia::Photo(
+                            InputMediaPhoto::new(InputFile::FileId(photo.file_id.clone()))
+                                .caption(format!("{}\n\n{}", meal, meal.photo_position(index))),
+                        ),
+                    )
+                    .reply_markup(keyboard),
+            ));
+        }
+        _ => {
+            request = ButtonKind::edit_callback_text(cx, format!("{}", meal), Some(keyboard));
+        }
+    }
+    request
+}
+
+fn edit_keyboard(
+    cx: &ContextCallback,
+    message: &Message,
+    buttons: Vec<Vec<Button>>,
+    state: &StateLock,
+) -> RequestKind {
+    RequestKind::EditReplyMarkup(
+        cx.bot
+            .edit_message_reply_markup(ChatId::Id(message.chat_id()), message.id)
+            .reply_markup(
+                Keyboard::new()
+                    .buttons(buttons)
+                    .save(state)
+                    .inline_keyboard(),
             ),
-            Button::new("Clear".to_string(), ButtonKind::ClearVotes { plan }),
-            Button::new("Exit".to_string(), ButtonKind::DeleteMessage),
-        ],
-    ]
+    )
+}
+
+const PICK_PAGE_SIZE: usize = 8;
+
+/// Saved meals that are not planned yet, to manually replace a plan entry.
+pub fn pick_meal_buttons(
+    plan: &Plan,
+    entry: usize,
+    page: usize,
+    meals: Vec<Meal>,
+) -> Vec<Vec<Button>> {
+    let planned: Vec<&String> = plan.entries.iter().map(|e| &e.meal.id).collect();
+    let mut candidates: Vec<Meal> = meals
+        .into_iter()
+        .filter(|meal| !planned.contains(&&meal.id))
+        .collect();
+    candidates.sort_by(|a, b| b.rating.cmp(&a.rating).then(a.name.cmp(&b.name)));
+    let pages = (candidates.len() + PICK_PAGE_SIZE - 1) / PICK_PAGE_SIZE;
+    let mut buttons: Vec<Vec<Button>> = candidates
+        .iter()
+        .skip(page * PICK_PAGE_SIZE)
+        .take(PICK_PAGE_SIZE)
+        .map(|meal| {
+            vec![Button::new(
+                format!("{} ({}⭐)", meal.name, meal.rating.unwrap_or(1)),
+                ButtonKind::SetPlanMeal {
+                    plan_id: plan.id.clone(),
+                    entry,
+                    meal_id: meal.id.clone(),
+                },
+            )]
+        })
+        .collect();
+    let mut navigation = vec![];
+    if page > 0 {
+        navigation.push(Button::new(
+            "Previous".to_string(),
+            ButtonKind::PickPlanMeal {
+                plan_id: plan.id.clone(),
+                entry,
+                page: page - 1,
+            },
+        ));
+    }
+    navigation.push(Button::new(
+        "Back".to_string(),
+        ButtonKind::PlanKeyboard {
+            plan_id: plan.id.clone(),
+        },
+    ));
+    if page + 1 < pages {
+        navigation.push(Button::new(
+            "Next".to_string(),
+            ButtonKind::PickPlanMeal {
+                plan_id: plan.id.clone(),
+                entry,
+                page: page + 1,
+            },
+        ));
+    }
+    buttons.push(navigation);
+    buttons
+}
+
+pub fn poll_plan_buttons(plan: Plan) -> Vec<Vec<Button>> {
+    let mut buttons = plan.buttons();
+    buttons.push(vec![
+        Button::new(
+            "Reroll".to_string(),
+            ButtonKind::RerollPlan { plan: plan.clone() },
+        ),
+        Button::new("Clear".to_string(), ButtonKind::ClearVotes { plan }),
+        Button::new("Exit".to_string(), ButtonKind::DeleteMessage),
+    ]);
+    buttons
+}
+
+/// Sends the plan as the chat's live plan message, as poll or as calendar message
+/// if it has too many entries for a poll. The previous plan message is deleted.
+pub fn plan_request(bot: &Bot, chat_id: i64, plan: Plan, state: &StateLock) -> RequestResult {
+    let keyboard = Keyboard::new()
+        .buttons(poll_plan_buttons(plan.clone()))
+        .save(state);
+    let answers = plan.answers();
+    let mut request = RequestResult::default();
+    if answers.len() >= 2 && answers.len() <= 10 {
+        request.add(RequestKind::Poll(
+            bot.send_poll(
+                chat_id,
+                format!("{}\n(Click to see details)", plan.title()),
+                answers,
+            )
+            .reply_markup(ReplyMarkup::InlineKeyboardMarkup(
+                keyboard.inline_keyboard(),
+            )),
+            PollKind::Plan { plan },
+            keyboard.id.clone(),
+        ));
+    } else {
+        request.add(RequestKind::Message(
+            bot.send_message(chat_id, plan.calendar()).reply_markup(
+                ReplyMarkup::InlineKeyboardMarkup(keyboard.inline_keyboard()),
+            ),
+            false,
+        ));
+    }
+    request.hook(Hook::PlanMessage);
+    let previous = state.read().plan_message(chat_id);
+    if let Some(message_id) = previous {
+        request.add(RequestKind::DeleteMessage(
+            bot.delete_message(chat_id, message_id),
+        ));
+    }
+    request
 }
@@ -1,17 +1,35 @@
+use chrono::{Local, NaiveDate, NaiveTime, Timelike};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use teloxide::prelude::GetChatId;
-use teloxide::types::{ReplyMarkup, User};
+use teloxide::types::{ChatId, InputFile, ReplyMarkup, User};
 use teloxide::utils::command::{BotCommand, ParseError};
 
+use crate::auth::{self, Invite, Role, DEFAULT_INVITE_HOURS};
 use crate::button;
-use crate::button::{poll_plan_buttons, Button, ButtonKind};
+use crate::button::{Button, ButtonKind};
+use crate::diet::{self, Label};
+use crate::error::Error;
+use crate::export::{self, ExportFormat};
+use crate::history;
+use crate::ics;
+use crate::ingredient::Ingredient;
 use crate::keyboard::Keyboard;
 use crate::meal::Meal;
-use crate::plan::Plan;
-use crate::poll::PollKind;
+use crate::nutrition::{self, NutritionAction};
+use crate::pantry::{self, PantryAction};
+use crate::plan::{Plan, PlanAction, Slot};
+use crate::poll::{PollKind, TONIGHT_DEADLINE_HOURS};
+use crate::rating::RatingStrategy;
 use crate::request::{RequestKind, RequestResult};
+use crate::schedule::{Cron, Job, JobAction, ScheduleAction};
+use crate::shopping::ShoppingList;
+use crate::tags;
 use crate::{ContextMessage, StateLock, VERSION};
 
+/// Bots can only delete messages younger than 48 hours, with a margin for the rate limit.
+const DELETABLE_SECONDS: i64 = 47 * 60 * 60;
+
 fn create_command(
     input: String,
 ) -> Result<(String, Option<u8>, Option<Vec<String>>, Option<String>), ParseError> {
@@ -114,19 +132,487 @@ fn rate_meal_command(input: String) -> Result<(String, u8), ParseError> {
     ))
 }
 
-fn plan_command(input: String) -> Result<(Option<usize>,), ParseError> {
+fn invite_command(input: String) -> Result<(Role, Option<i64>), ParseError> {
     let args: Vec<_> = input.split(",").collect();
-    Ok((if let Some(rating_str) = args.get(0) {
-        if let Ok(rating) = rating_str.trim().parse::<usize>() {
-            Some(rating)
+    Ok((
+        match args.get(0).map(|role| role.trim()) {
+            Some(role) if role.len() > 0 => match Role::parse(role) {
+                Some(role) => role,
+                None => {
+                    return Err(ParseError::Custom(
+                        "Role (1st argument) has to be member or admin!".into(),
+                    ))
+                }
+            },
+            _ => Role::Member,
+        },
+        if let Some(hours_str) = args.get(1) {
+            if let Ok(hours) = hours_str.trim().parse::<i64>() {
+                Some(hours)
+            } else {
+                return Err(ParseError::Custom(
+                    "Expiry (2nd argument) has to be a number of hours!".into(),
+                ));
+            }
         } else {
             None
+        },
+    ))
+}
+
+fn meal_list_command(input: String, items: &str) -> Result<(String, Vec<String>), ParseError> {
+    let mut args = input.splitn(2, ",");
+    Ok((
+        match args.next().map(|name| name.trim()) {
+            Some(name) if name.len() > 0 => name.to_string(),
+            _ => return Err(ParseError::Custom("Provide a meal name!".into())),
+        },
+        if let Some(list_str) = args.next() {
+            list_str
+                .split(|c| c == '|' || c == '\n')
+                .map(|s| s.trim().to_string())
+                .filter(|s| s.len() > 0)
+                .collect()
+        } else {
+            return Err(ParseError::Custom(
+                format!("Provide {} separated by | or new lines!", items).into(),
+            ));
+        },
+    ))
+}
+
+fn steps_command(input: String) -> Result<(String, Vec<String>), ParseError> {
+    meal_list_command(input, "steps")
+}
+
+fn recipe_info_command(
+    input: String,
+) -> Result<(String, Option<u32>, Option<u32>, Option<u8>), ParseError> {
+    let args: Vec<_> = input.split(",").collect();
+    let number = |pos: usize, name: &str| -> Result<Option<u32>, ParseError> {
+        match args.get(pos).map(|arg| arg.trim()) {
+            Some(arg) if arg.len() > 0 => match arg.parse::<u32>() {
+                Ok(number) => Ok(Some(number)),
+                Err(_) => Err(ParseError::Custom(format!("{} has to be a number!", name))),
+            },
+            _ => Ok(None),
+        }
+    };
+    Ok((
+        match args.get(0).map(|name| name.trim()) {
+            Some(name) if name.len() > 0 => name.to_string(),
+            _ => return Err(ParseError::Custom("Provide a meal name!".into())),
+        },
+        number(1, "Prep time (2nd argument)")?,
+        number(2, "Cook time (3rd argument)")?,
+        number(3, "Servings (4th argument)")?.map(|servings| servings as u8),
+    ))
+}
+
+fn ingredients_command(input: String) -> Result<(String, Vec<Ingredient>), ParseError> {
+    let (meal_name, items) = meal_list_command(input, "ingredients")?;
+    Ok((
+        meal_name,
+        items
+            .iter()
+            .filter_map(|item| Ingredient::parse(item))
+            .collect(),
+    ))
+}
+
+fn recipe_command(input: String) -> Result<(String, Option<u8>), ParseError> {
+    let mut servings = None;
+    let mut name_parts = vec![];
+    for word in input.split_whitespace() {
+        if word.to_lowercase().starts_with("servings=") {
+            match word["servings=".len()..].parse::<u8>() {
+                Ok(number) => servings = Some(number),
+                Err(_) => return Err(ParseError::Custom("Servings have to be a number!".into())),
+            }
+        } else {
+            name_parts.push(word);
+        }
+    }
+    if name_parts.len() == 0 {
+        return Err(ParseError::Custom("Provide a meal name!".into()));
+    }
+    Ok((name_parts.join(" "), servings))
+}
+
+fn servings_command(input: String) -> Result<(usize, Option<u8>), ParseError> {
+    let args: Vec<_> = input.split(",").collect();
+    Ok((
+        match args.get(0).map(|day| day.trim().parse::<usize>()) {
+            Some(Ok(day)) if day > 0 => day,
+            _ => {
+                return Err(ParseError::Custom(
+                    "Day (1st argument) has to be a number!".into(),
+                ))
+            }
+        },
+        match args.get(1).map(|servings| servings.trim()) {
+            Some(servings) if servings.len() > 0 => match servings.parse::<u8>() {
+                Ok(servings) => Some(servings),
+                Err(_) => {
+                    return Err(ParseError::Custom(
+                        "Servings (2nd argument) have to be a number!".into(),
+                    ))
+                }
+            },
+            _ => None,
+        },
+    ))
+}
+
+fn plan_command(input: String) -> Result<(PlanAction,), ParseError> {
+    let args: Vec<_> = input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|arg| arg.len() > 0)
+        .collect();
+    let day = |pos: usize| -> Result<usize, ParseError> {
+        match args.get(pos).map(|arg| arg.parse::<usize>()) {
+            Some(Ok(day)) => Ok(day),
+            _ => Err(ParseError::Custom(
+                "Provide two days, e.g. /plan swap 1 3".into(),
+            )),
+        }
+    };
+    Ok((match args.get(0) {
+        None => PlanAction::Show,
+        Some(&"swap") => PlanAction::Swap(day(1)?, day(2)?),
+        Some(&"move") => PlanAction::Move(day(1)?, day(2)?),
+        Some(&"export") => PlanAction::Export,
+        Some(days_str) => match days_str.parse::<usize>() {
+            Ok(days) => PlanAction::Gen {
+                days,
+                start: match args.get(1) {
+                    Some(date_str) => match NaiveDate::parse_from_str(date_str, "%Y-%m-%d") {
+                        Ok(date) => Some(date),
+                        Err(_) => {
+                            return Err(ParseError::Custom(
+                                "Start date has to look like 2020-10-26!".into(),
+                            ))
+                        }
+                    },
+                    None => None,
+                },
+            },
+            Err(_) => {
+                return Err(ParseError::Custom(
+                    "Use /plan [days] [YYYY-MM-DD], /plan swap <day> <day>, /plan move <day> <day> or /plan export".into(),
+                ))
+            }
+        },
+    },))
+}
+
+fn plans_command(input: String) -> Result<(Option<usize>,), ParseError> {
+    let args: Vec<_> = input.split_whitespace().collect();
+    match args.as_slice() {
+        [] => Ok((None,)),
+        ["penalize", n] => match n.parse::<usize>() {
+            Ok(n) => Ok((Some(n),)),
+            Err(_) => Err(ParseError::Custom(
+                "Provide a number of plans, e.g. /plans penalize 2".into(),
+            )),
+        },
+        _ => Err(ParseError::Custom(
+            "Use /plans or /plans penalize <n>".into(),
+        )),
+    }
+}
+
+fn schedule_command(input: String) -> Result<(ScheduleAction,), ParseError> {
+    let args: Vec<_> = input.split_whitespace().collect();
+    let action = match args.as_slice() {
+        [] | ["list"] => ScheduleAction::List,
+        ["remove", id] => ScheduleAction::Remove(id.to_string()),
+        ["add", rest @ ..] => {
+            let fields = match rest.get(0) {
+                Some(field) if field.starts_with('@') => 1,
+                _ => 5,
+            };
+            if rest.len() <= fields {
+                return Err(ParseError::Custom(
+                    "Use /schedule add <minute hour day month weekday> <action>".into(),
+                ));
+            }
+            let expression = rest[..fields].join(" ");
+            ScheduleAction::Add {
+                cron: Cron::parse(&expression).map_err(|err| ParseError::Custom(err.into()))?,
+                action: JobAction::parse(&rest[fields..])
+                    .map_err(|err| ParseError::Custom(err.into()))?,
+                expression,
+            }
+        }
+        _ => return Err(ParseError::Custom("Use /schedule add|list|remove".into())),
+    };
+    Ok((action,))
+}
+
+fn poll_timeout_command(input: String) -> Result<(Option<i64>,), ParseError> {
+    match input.trim() {
+        "off" | "0" => Ok((None,)),
+        hours => match hours.parse::<i64>() {
+            Ok(hours) if hours > 0 => Ok((Some(hours),)),
+            _ => Err(ParseError::Custom(
+                "Provide hours or off, e.g. /poll-timeout 24".into(),
+            )),
+        },
+    }
+}
+
+fn tonight_voters_command(input: String) -> Result<(Option<i32>,), ParseError> {
+    match input.trim() {
+        "off" | "0" => Ok((None,)),
+        voters => match voters.parse::<i32>() {
+            Ok(voters) if voters > 0 => Ok((Some(voters),)),
+            _ => Err(ParseError::Custom(
+                "Provide a number of voters or off, e.g. /tonight-voters 3".into(),
+            )),
+        },
+    }
+}
+
+fn rating_strategy_command(input: String) -> Result<(RatingStrategy,), ParseError> {
+    match RatingStrategy::parse(&input) {
+        Some(strategy) => Ok((strategy,)),
+        None => Err(ParseError::Custom(
+            "Strategy has to be replace, mean or recency!".into(),
+        )),
+    }
+}
+
+fn tonight_command(input: String) -> Result<(usize, Vec<String>), ParseError> {
+    let mut args: Vec<&str> = input.split_whitespace().collect();
+    let amount = match args.get(0).map(|arg| arg.parse::<usize>()) {
+        Some(Ok(amount)) => {
+            args.remove(0);
+            if amount < 2 || amount > 10 {
+                return Err(ParseError::Custom(
+                    "A poll needs between 2 and 10 meals!".into(),
+                ));
+            }
+            amount
+        }
+        _ => 3,
+    };
+    Ok((
+        amount,
+        args.into_iter().map(|arg| arg.to_string()).collect(),
+    ))
+}
+
+fn nutrition_command(input: String) -> Result<(NutritionAction,), ParseError> {
+    let input = input.trim();
+    if input.len() == 0 {
+        Ok((NutritionAction::Unmatched,))
+    } else if input.starts_with("map ") {
+        let args: Vec<_> = input["map ".len()..].split(",").collect();
+        match args.as_slice() {
+            [ingredient, food] if ingredient.trim().len() > 0 && food.trim().len() > 0 => Ok((
+                NutritionAction::Map(ingredient.trim().to_lowercase(), food.trim().to_lowercase()),
+            )),
+            _ => Err(ParseError::Custom(
+                "Use /nutrition map <ingredient>, <food>".into(),
+            )),
         }
     } else {
-        None
+        Ok((NutritionAction::Meal(input.to_string()),))
+    }
+}
+
+fn parse_labels(input: &str) -> Result<Vec<Label>, ParseError> {
+    input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| word.len() > 0)
+        .map(|word| {
+            Label::parse(word).ok_or_else(|| {
+                ParseError::Custom(
+                    format!(
+                        "Unknown label {} (vegetarian, vegan, gluten-free, lactose-free, nuts, eggs, fish, shellfish, soy)",
+                        word
+                    )
+                    .into(),
+                )
+            })
+        })
+        .collect()
+}
+
+fn labels_command(input: String) -> Result<(String, Option<Vec<Label>>), ParseError> {
+    let args: Vec<_> = input.splitn(2, ",").collect();
+    let name = match args.get(0) {
+        Some(name) if name.trim().len() > 0 => name.trim().to_string(),
+        _ => return Err(ParseError::Custom("Provide a meal name!".into())),
+    };
+    match args.get(1).map(|labels| labels.trim()) {
+        None | Some("auto") => Ok((name, None)),
+        Some(labels) => Ok((name, Some(parse_labels(labels)?))),
+    }
+}
+
+fn diet_command(input: String) -> Result<(Option<Vec<Label>>,), ParseError> {
+    match input.trim() {
+        "" => Ok((None,)),
+        "off" => Ok((Some(vec![]),)),
+        labels => {
+            let mut labels = parse_labels(labels)?;
+            labels.sort();
+            labels.dedup();
+            Ok((Some(labels),))
+        }
+    }
+}
+
+fn diet_unlabeled_command(input: String) -> Result<(bool,), ParseError> {
+    match input.trim() {
+        "on" => Ok((true,)),
+        "off" => Ok((false,)),
+        _ => Err(ParseError::Custom(
+            "Provide on or off, e.g. /diet-unlabeled on".into(),
+        )),
+    }
+}
+
+fn tag_pair_command(input: String) -> Result<(String, String), ParseError> {
+    let args: Vec<_> = input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(|arg| tags::normalize(arg))
+        .filter(|arg| arg.len() > 0)
+        .collect();
+    match args.as_slice() {
+        [first, second] => Ok((first.clone(), second.clone())),
+        _ => Err(ParseError::Custom(
+            "Provide two tags, e.g. veggie vegetarian".into(),
+        )),
+    }
+}
+
+fn tag_alias_command(input: String) -> Result<(String, Option<String>), ParseError> {
+    match tag_pair_command(input.clone()) {
+        Ok((alias, tag)) => Ok((alias, Some(tag))),
+        Err(err) => match tags::normalize(&input) {
+            alias if alias.len() > 0 && !alias.contains(char::is_whitespace) => Ok((alias, None)),
+            _ => Err(err),
+        },
+    }
+}
+
+fn pantry_command(input: String) -> Result<(PantryAction,), ParseError> {
+    let input = input.trim();
+    let (action, args) = match input.find(char::is_whitespace) {
+        Some(pos) => (&input[..pos], input[pos..].trim()),
+        None => (input, ""),
+    };
+    let ingredient = |input: &str| {
+        Ingredient::parse(input).ok_or_else(|| {
+            ParseError::Custom("Provide an ingredient, e.g. /pantry add 500 g rice".into())
+        })
+    };
+    Ok((match action {
+        "" | "list" => PantryAction::List,
+        "add" => {
+            let args: Vec<_> = args.splitn(2, ",").collect();
+            PantryAction::Add(
+                ingredient(args[0])?,
+                match args.get(1) {
+                    Some(date_str) => {
+                        match NaiveDate::parse_from_str(date_str.trim(), "%Y-%m-%d") {
+                            Ok(date) => Some(date),
+                            Err(_) => {
+                                return Err(ParseError::Custom(
+                                    "Expiry date has to look like 2020-10-26!".into(),
+                                ))
+                            }
+                        }
+                    }
+                    None => None,
+                },
+            )
+        }
+        "remove" => PantryAction::Remove(ingredient(args)?),
+        "sync" => match args {
+            "on" => PantryAction::Sync(true),
+            "off" => PantryAction::Sync(false),
+            _ => return Err(ParseError::Custom("Use /pantry sync on|off".into())),
+        },
+        "warn" => match args {
+            "off" => PantryAction::Warn(None),
+            time => match NaiveTime::parse_from_str(time, "%H:%M") {
+                Ok(time) => PantryAction::Warn(Some(time)),
+                Err(_) => return Err(ParseError::Custom("Use /pantry warn <HH:MM>|off".into())),
+            },
+        },
+        _ => {
+            return Err(ParseError::Custom(
+                "Use /pantry add <ingredient>[, expiry] | remove <ingredient> | list | sync on|off | warn <HH:MM>|off"
+                    .into(),
+            ))
+        }
     },))
 }
 
+fn export_command(input: String) -> Result<(ExportFormat,), ParseError> {
+    match ExportFormat::parse(&input) {
+        Some(format) => Ok((format,)),
+        None => Err(ParseError::Custom("Use /export json|csv|md".into())),
+    }
+}
+
+fn slots_command(input: String) -> Result<(Vec<Slot>,), ParseError> {
+    let mut slots = vec![];
+    for word in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        if word.len() == 0 {
+            continue;
+        }
+        match Slot::parse(word) {
+            Some(slot) => {
+                if !slots.contains(&slot) {
+                    slots.push(slot);
+                }
+            }
+            None => {
+                return Err(ParseError::Custom(
+                    format!("Unknown slot {}, use breakfast, lunch or dinner!", word).into(),
+                ))
+            }
+        }
+    }
+    slots.sort();
+    Ok((slots,))
+}
+
+/// Replaces every saved meal named `meal_name` with an updated copy
+/// and answers with the updated meal.
+fn update_saved_meals<F>(
+    state: &StateLock,
+    cx: &ContextMessage,
+    request: &mut RequestResult,
+    meal_name: &String,
+    sub_text: String,
+    update: F,
+) -> Result<(), Error>
+where
+    F: Fn(&mut Meal),
+{
+    let meals = state.read().get_saved_meals_by_name(meal_name.clone());
+    if meals.len() == 0 {
+        return Err(Error::NotFound(format!("Meal {}", meal_name)));
+    }
+    for meal in meals {
+        let removed = state.write().remove_saved_meal(&meal)?;
+        if removed {
+            let mut new_meal = meal.clone();
+            update(&mut new_meal);
+            state.write().save_meal(&new_meal)?;
+            request.add(new_meal.request(&cx, Some(sub_text.clone()), None));
+            log::info!("Updated meal {}: {}", meal_name, sub_text);
+        }
+    }
+    Ok(())
+}
+
 #[derive(BotCommand, Debug, Clone, Serialize, Deserialize)]
 #[command(rename = "lowercase", description = "These commands are supported:")]
 pub enum Command {
@@ -142,18 +628,94 @@ pub enum Command {
         url: Option<String>,
     },
     #[command(
-        description = "Plan meals for given days.",
+        description = "Plan meals <days> [start YYYY-MM-DD] | swap <day> <day> | move <day> <day> | export.",
         parse_with = "plan_command"
     )]
-    Plan(Option<usize>),
+    Plan(PlanAction),
+    #[command(
+        description = "Browse archived plans [penalize <n> last plans when generating].",
+        parse_with = "plans_command"
+    )]
+    Plans(Option<usize>),
+    #[command(
+        description = "Schedule jobs: add <minute hour day month weekday> <tomorrow | plan [days] | closepolls [hours] | remind <text> | expiring [days]>, list, remove <id>.",
+        parse_with = "schedule_command"
+    )]
+    Schedule(ScheduleAction),
+    #[command(
+        rename = "kebab-case",
+        description = "Close rating polls of this chat automatically after <hours> | off.",
+        parse_with = "poll_timeout_command"
+    )]
+    PollTimeout(Option<i64>),
+    #[command(
+        rename = "kebab-case",
+        description = "Set how poll votes update ratings: replace | mean | recency.",
+        parse_with = "rating_strategy_command"
+    )]
+    RatingStrategy(RatingStrategy),
+    #[command(
+        description = "Vote what to cook tonight [meals] [tags, -tag to exclude].",
+        parse_with = "tonight_command"
+    )]
+    Tonight(usize, Vec<String>),
+    #[command(
+        rename = "kebab-case",
+        description = "Close tonight polls once <voters> voted | off to wait for the deadline.",
+        parse_with = "tonight_voters_command"
+    )]
+    TonightVoters(Option<i32>),
+    #[command(
+        description = "Nutrition of a meal <name> | map <ingredient>, <food> | unmatched ingredients.",
+        parse_with = "nutrition_command"
+    )]
+    Nutrition(NutritionAction),
+    #[command(
+        description = "Set diet labels <name>, <vegan gluten-free nuts ...> | auto to infer from ingredients.",
+        parse_with = "labels_command"
+    )]
+    Labels(String, Option<Vec<Label>>),
+    #[command(
+        description = "Show or set the diet profile of this chat [vegetarian gluten-free nuts ...] | off.",
+        parse_with = "diet_command"
+    )]
+    Diet(Option<Vec<Label>>),
+    #[command(
+        rename = "kebab-case",
+        description = "Let meals without diet information fit the diet profile on|off.",
+        parse_with = "diet_unlabeled_command"
+    )]
+    DietUnlabeled(bool),
+    #[command(
+        description = "Pantry of this chat: add <500 g rice>[, YYYY-MM-DD expiry] | remove <ingredient> | list | sync on|off (add ticked shopping items) | warn <HH:MM>|off (daily expiry warning).",
+        parse_with = "pantry_command"
+    )]
+    Pantry(PantryAction),
+    #[command(description = "Meals you can cook with the pantry and what is missing.")]
+    CanCook,
+    #[command(
+        description = "Set planned meal slots of this chat (breakfast, lunch, dinner).",
+        parse_with = "slots_command"
+    )]
+    Slots(Vec<Slot>),
     #[command(description = "Get a saved meal's info.")]
     Get(String),
     #[command(description = "Remove a meal by name.")]
     Remove(String),
     #[command(description = "Get a list of all meals.")]
     List,
-    #[command(description = "Whitelist user.", parse_with = "meal_name_command")]
+    #[command(
+        description = "Whitelist user (private chat only).",
+        parse_with = "meal_name_command"
+    )]
     Op(String, String),
+    #[command(
+        description = "Create a single use invite link [member|admin] [, hours].",
+        parse_with = "invite_command"
+    )]
+    Invite(Role, Option<i64>),
+    #[command(description = "Start the bot or accept an invite.")]
+    Start(String),
     #[command(
         description = "Rename existing meal.",
         parse_with = "meal_name_command"
@@ -165,7 +727,7 @@ pub enum Command {
     )]
     Rate(String, u8),
     #[command(
-        description = "Add tags to existing meal.",
+        description = "Add tags to existing meal <name>, <tag> <tag> ... | pick tags of <name>.",
         parse_with = "tag_meal_command"
     )]
     Tag(String, Vec<String>),
@@ -174,6 +736,26 @@ pub enum Command {
         parse_with = "tag_meal_command"
     )]
     TagRemove(String, Vec<String>),
+    #[command(description = "List all tags with their number of meals and aliases.")]
+    Tags,
+    #[command(
+        rename = "kebab-case",
+        description = "Rename a tag in all meals <old> <new>.",
+        parse_with = "tag_pair_command"
+    )]
+    TagRename(String, String),
+    #[command(
+        rename = "kebab-case",
+        description = "Merge a tag into another <from> <into>, <from> stays as alias.",
+        parse_with = "tag_pair_command"
+    )]
+    TagMerge(String, String),
+    #[command(
+        rename = "kebab-case",
+        description = "Let <alias> stand for <tag> | remove <alias>.",
+        parse_with = "tag_alias_command"
+    )]
+    TagAlias(String, Option<String>),
     #[command(
         description = "Edit reference of existing meal.",
         parse_with = "meal_name_command"
@@ -181,23 +763,142 @@ pub enum Command {
     Ref(String, String),
     #[command(description = "Add Photo to existing meal.")]
     Photo(String),
+    #[command(
+        description = "Show recipe of a meal and start cooking [servings=<n>].",
+        parse_with = "recipe_command"
+    )]
+    Recipe(String, Option<u8>),
+    #[command(
+        description = "Set recipe steps <name>, <step> | <step> ...",
+        parse_with = "steps_command"
+    )]
+    Steps(String, Vec<String>),
+    #[command(
+        description = "Set <name>, <prep min>, <cook min>, <servings> of a recipe.",
+        parse_with = "recipe_info_command"
+    )]
+    RecipeInfo(String, Option<u32>, Option<u32>, Option<u8>),
+    #[command(
+        description = "Set ingredients <name>, <500 g rice> | <2 onions> ...",
+        parse_with = "ingredients_command"
+    )]
+    Ingredients(String, Vec<Ingredient>),
+    #[command(
+        description = "Set servings <day>, <servings> of the current plan.",
+        parse_with = "servings_command"
+    )]
+    Servings(usize, Option<u8>),
+    #[command(
+        description = "Import meals from a JSON, CSV or schema.org Recipe file sent with this caption."
+    )]
+    Import,
+    #[command(
+        description = "Export all meals as json (with photos, can be imported) | csv | md cookbook.",
+        parse_with = "export_command"
+    )]
+    Export(ExportFormat),
+    #[command(description = "Get shopping list of the current plan.")]
+    Shopping,
     #[command(description = "Get bot version.")]
     Version,
+    #[command(description = "Get counters of the requests sent to telegram (admins only).")]
+    Metrics,
+    #[command(
+        description = "Delete the bot's messages of the last two days, except the plan and open polls (admins only)."
+    )]
+    Clean,
+}
+
+/// Username of the user if it is whitelisted, checked before anything is changed for a user.
+pub fn whitelisted_user(state: &StateLock, user: Option<&User>) -> Result<String, Error> {
+    match user {
+        Some(User {
+            username: Some(username),
+            ..
+        }) => {
+            if state.read().get_whitelisted_users().contains(username) {
+                Ok(username.clone())
+            } else {
+                Err(Error::Permission("User not whitelisted!".to_string()))
+            }
+        }
+        _ => Err(Error::NotFound("User".to_string())),
+    }
 }
 
 impl Command {
-    pub fn run(command: &Command, state: &StateLock, cx: &ContextMessage) -> RequestResult {
+    pub fn run(
+        command: &Command,
+        state: &StateLock,
+        cx: &ContextMessage,
+    ) -> Result<RequestResult, Error> {
         let mut request = RequestResult::default();
         let user = cx.update.from();
         let config = state.read().config.clone();
         match command {
             Command::Op(username, password) => {
-                request.message(cx.answer(if password == &config.password {
-                    state.write().whitelist_user(username.clone());
-                    format!("Added user {} to whitelist.\nEnjoy!", username)
+                request.add(RequestKind::DeleteMessage(cx.delete_message()));
+                if !cx.update.chat.is_private() {
+                    request.message(cx.answer(format!("Use /op in a private chat with the bot!")));
+                    return Ok(request);
+                }
+                let user_id = match user {
+                    Some(user) => user.id,
+                    None => {
+                        request.message(cx.answer(format!("No user found!")));
+                        return Ok(request);
+                    }
+                };
+                if state.read().login_attempts.is_locked(user_id) {
+                    request
+                        .message(cx.answer(format!("Too many failed attempts, try again later!")));
+                } else if auth::verify_password(&config.password_hash, password) {
+                    state.write().login_attempts.reset(user_id);
+                    state
+                        .write()
+                        .whitelist_user(username.clone(), Role::Admin)?;
+                    request.message(
+                        cx.answer(format!("Added user {} to whitelist.\nEnjoy!", username)),
+                    );
                 } else {
-                    format!("Wrong password: {}", password)
-                }));
+                    state.write().login_attempts.fail(user_id);
+                    log::warn!("Failed /op attempt by user {}", user_id);
+                    request.message(cx.answer(format!("Wrong password!")));
+                }
+                return Ok(request);
+            }
+            Command::Start(code) if code.trim().len() > 0 => {
+                request.add(RequestKind::DeleteMessage(cx.delete_message()));
+                match user {
+                    Some(User {
+                        username: Some(username),
+                        ..
+                    }) => {
+                        let invite = state.write().take_invite(&code.trim().to_string())?;
+                        match invite {
+                            Some(invite) => {
+                                state
+                                    .write()
+                                    .whitelist_user(username.clone(), invite.role)?;
+                                request.message(cx.answer(format!(
+                                    "Welcome {}! You joined as {}.\nSee /help for commands.",
+                                    username, invite.role
+                                )));
+                            }
+                            None => {
+                                request.message(
+                                    cx.answer(format!("Invite code is invalid or expired!")),
+                                );
+                            }
+                        }
+                    }
+                    _ => {
+                        request.message(
+                            cx.answer(format!("Set a telegram username to accept invites!")),
+                        );
+                    }
+                }
+                return Ok(request);
             }
             _ => {}
         }
@@ -208,26 +909,46 @@ impl Command {
                 ..
             }) => {
                 if !whitelist.contains(&username.clone()) {
-                    request.message(cx.answer(format!("User not whitelisted!")));
-                    return request;
+                    return Err(Error::Permission("User not whitelisted!".to_string()));
                 } else {
                     match command {
                         Command::Op { .. } => {}
-                        Command::Help => {
+                        Command::Help | Command::Start(_) => {
                             request.message(cx.answer(Command::descriptions()));
                         }
+                        Command::Invite(role, hours) => {
+                            if !state.read().is_admin(username) {
+                                return Err(Error::Permission(
+                                    "Only admins can create invites!".to_string(),
+                                ));
+                            } else {
+                                let hours = hours.unwrap_or(DEFAULT_INVITE_HOURS);
+                                let invite = Invite::new(*role, hours, username.clone());
+                                state.write().save_invite(&invite)?;
+                                request.message(cx.answer(format!(
+                                    "Invite as {} (single use, valid for {}h):\n{}",
+                                    role,
+                                    hours,
+                                    invite.link(&config.name)
+                                )));
+                            }
+                        }
                         Command::NewMeal(meal_name) => {
                             let meal = Meal::new(meal_name);
                             meal.save(&state);
+                            let counts = state.read().tag_counts();
                             request.add(
                                 meal.request(
                                     &cx,
                                     Some("How did it taste?".to_string()),
                                     Some(
                                         Keyboard::new()
-                                            .buttons(vec![button::rate_meal_button_row(
-                                                0, &meal.id,
-                                            )])
+                                            .buttons(
+                                                vec![button::rate_meal_button_row(0, &meal.id)]
+                                                    .into_iter()
+                                                    .chain(tags::tag_buttons(&meal, &counts))
+                                                    .collect(),
+                                            )
                                             .save(&state),
                                     ),
                                 ),
@@ -244,19 +965,14 @@ impl Command {
                                 .tag(tags.clone())
                                 .url(url.clone())
                                 .save(&state);
+                            let counts = state.read().tag_counts();
                             request.add(
                                 meal.request(
                                     &cx,
                                     None,
                                     Some(
                                         Keyboard::new()
-                                            .buttons(vec![
-                                                vec![Button::new(
-                                                    "Rate with Poll".into(),
-                                                    ButtonKind::PollRating { meal: meal.clone() },
-                                                )],
-                                                button::save_meal_button_row(&meal.id),
-                                            ])
+                                            .buttons(button::new_meal_keyboard(&meal, &counts))
                                             .save(&state),
                                     ),
                                 ),
@@ -265,89 +981,510 @@ impl Command {
                         Command::Get(meal_name) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             for meal in meals {
-                                request.add(
-                                    meal.request(
-                                        &cx,
+                                // meals with photos can be browsed on their card
+                                let (sub_text, buttons) = match meal.cover_index() {
+                                    Some(index) => (
+                                        Some(meal.photo_position(index)),
+                                        button::gallery_buttons(&meal, index),
+                                    ),
+                                    None => (
                                         None,
-                                        Some(
-                                            Keyboard::new()
-                                                .buttons(vec![vec![Button::new(
-                                                    "Cancel".to_uppercase(),
-                                                    ButtonKind::DeleteMessage,
-                                                )]])
-                                                .save(&state),
-                                        ),
+                                        vec![vec![Button::new(
+                                            "Cancel".to_uppercase(),
+                                            ButtonKind::DeleteMessage,
+                                        )]],
                                     ),
-                                );
+                                };
+                                request.add(meal.request(
+                                    &cx,
+                                    sub_text,
+                                    Some(Keyboard::new().buttons(buttons).save(&state)),
+                                ));
                             }
                         }
                         Command::Remove(meal_name) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             if meals.len() == 0 {
-                                request.message(
-                                    cx.answer(format!("No meal with name {} found!", meal_name)),
-                                );
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
                             }
                             for meal in meals {
-                                if let Err(err) = state.write().remove_saved_meal(&meal) {
-                                    log::warn!("{}", err);
-                                }
+                                state.write().remove_saved_meal(&meal)?;
                                 request.add(meal.request(&cx, Some(format!("Deleted!")), None));
                             }
                         }
-                        Command::Plan(days_opt) => {
-                            let meals = state.read().get_saved_meals();
-                            let meal_count = meals.len();
-                            let meal_plan = if let Some(days) = days_opt {
-                                Plan::gen(meals, *days)
+                        Command::Plan(PlanAction::Export) => {
+                            let plan_opt = state.read().get_plan(cx.chat_id());
+                            match plan_opt {
+                                Some(plan) => {
+                                    let caption = match &config.calendar_url {
+                                        Some(url) => {
+                                            let token = state.write().ics_token(cx.chat_id())?;
+                                            format!(
+                                                "{}\n\nSubscribe: {}{}",
+                                                plan.title(),
+                                                url.trim_end_matches('/'),
+                                                ics::subscription_path(&token)
+                                            )
+                                        }
+                                        None => plan.title(),
+                                    };
+                                    request.add(RequestKind::Document(
+                                        cx.bot
+                                            .send_document(
+                                                cx.chat_id(),
+                                                InputFile::Memory {
+                                                    file_name: format!(
+                                                        "plan-{}.ics",
+                                                        plan.start.format("%Y-%m-%d")
+                                                    ),
+                                                    data: Cow::Owned(
+                                                        ics::calendar(&plan, "Meal Plan")
+                                                            .into_bytes(),
+                                                    ),
+                                                },
+                                            )
+                                            .caption(caption),
+                                    ));
+                                }
+                                None => {
+                                    request.message(
+                                        cx.answer(format!("No Plan found, add with /plan <days>!")),
+                                    );
+                                }
+                            }
+                        }
+                        Command::Plan(action) => {
+                            let plan_opt = state.read().get_plan(cx.chat_id());
+                            let plan_res: Result<Plan, String> = match action {
+                                PlanAction::Show | PlanAction::Export => {
+                                    plan_opt.ok_or(format!("No Plan found, add with /plan <days>!"))
+                                }
+                                PlanAction::Gen { days, start } => {
+                                    let meals = state.read().get_saved_meals_for(cx.chat_id());
+                                    if meals.len() == 0 || *days == 0 {
+                                        Err(format!("Not enough Meals to generate plan!"))
+                                    } else {
+                                        let slots = state.read().get_settings(cx.chat_id()).slots;
+                                        let recent = state.read().recent_meal_ids(cx.chat_id());
+                                        Ok(Plan::gen(
+                                            meals,
+                                            *days,
+                                            start.unwrap_or(Local::today().naive_local()),
+                                            slots,
+                                            &recent,
+                                        ))
+                                    }
+                                }
+                                PlanAction::Swap(a, b) | PlanAction::Move(a, b) => match plan_opt {
+                                    Some(mut plan) => {
+                                        if *a == 0 || *b == 0 || *a > plan.days || *b > plan.days {
+                                            Err(format!(
+                                                "Days have to be between 1 and {}!",
+                                                plan.days
+                                            ))
+                                        } else {
+                                            if let PlanAction::Swap(..) = action {
+                                                plan.swap_days(a - 1, b - 1);
+                                            } else {
+                                                plan.move_day(a - 1, b - 1);
+                                            }
+                                            Ok(plan)
+                                        }
+                                    }
+                                    None => Err(format!("No Plan found, add with /plan <days>!")),
+                                },
+                            };
+                            match plan_res {
+                                Ok(meal_plan) => {
+                                    state.write().save_plan(cx.chat_id(), meal_plan.clone())?;
+                                    request.append(button::plan_request(
+                                        &cx.bot,
+                                        cx.chat_id(),
+                                        meal_plan,
+                                        &state,
+                                    ));
+                                }
+                                Err(text) => {
+                                    request.message(cx.answer(text));
+                                }
+                            }
+                        }
+                        Command::Plans(Some(penalty)) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.history_penalty = *penalty;
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            request.message(cx.answer(format!(
+                                "Meals of the last {} archived plans are picked less often.",
+                                penalty
+                            )));
+                        }
+                        Command::Plans(None) => {
+                            let plans = state.read().get_archived_plans(cx.chat_id());
+                            request.message(
+                                cx.answer(history::statistics(&plans)).reply_markup(
+                                    Keyboard::new()
+                                        .buttons(history::history_buttons(&plans, 0))
+                                        .save(&state)
+                                        .inline_keyboard(),
+                                ),
+                            );
+                        }
+                        Command::Schedule(ScheduleAction::Add {
+                            expression,
+                            cron,
+                            action,
+                        }) => {
+                            let job = Job::new(
+                                cx.chat_id(),
+                                expression.clone(),
+                                cron.clone(),
+                                action.clone(),
+                                Local::now(),
+                            );
+                            state.write().jobs_mut().insert(job.id.clone(), job.clone());
+                            request.message(cx.answer(format!("Scheduled job {}", job)));
+                        }
+                        Command::Schedule(ScheduleAction::List) => {
+                            let mut jobs: Vec<Job> = state
+                                .read()
+                                .jobs()
+                                .values()
+                                .filter(|job| job.chat_id == cx.chat_id())
+                                .cloned()
+                                .collect();
+                            jobs.sort_by_key(|job| job.next_run());
+                            request.message(cx.answer(if jobs.len() == 0 {
+                                format!("No scheduled jobs, add with /schedule add!")
                             } else {
-                                state
-                                    .read()
-                                    .get_plan(cx.chat_id())
-                                    .unwrap_or(Plan::new(vec![]))
+                                format!(
+                                    "Scheduled jobs:\n{}",
+                                    jobs.iter()
+                                        .map(|job| job.to_string())
+                                        .collect::<Vec<_>>()
+                                        .join("\n")
+                                )
+                            }));
+                        }
+                        Command::Schedule(ScheduleAction::Remove(id)) => {
+                            let removed = {
+                                let mut state = state.write();
+                                let own_job = state
+                                    .jobs()
+                                    .get(id)
+                                    .map(|job| job.chat_id == cx.chat_id())
+                                    .unwrap_or(false);
+                                if own_job {
+                                    state.jobs_mut().remove(id)
+                                } else {
+                                    None
+                                }
                             };
-                            state.write().save_plan(cx.chat_id(), meal_plan.clone());
-                            let keyboard = Keyboard::new()
-                                .buttons(poll_plan_buttons(meal_plan.clone()))
-                                .save(&state);
-                            if meal_plan.days > 0 {
+                            request.message(cx.answer(match removed {
+                                Some(job) => format!("Removed job {}", job),
+                                None => format!("No job with id {} found!", id),
+                            }));
+                        }
+                        Command::PollTimeout(hours) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.poll_timeout = *hours;
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            request.message(cx.answer(match hours {
+                                Some(hours) => {
+                                    format!("Rating polls are closed after {}h.", hours)
+                                }
+                                None => format!("Rating polls stay open until saved."),
+                            }));
+                        }
+                        Command::TonightVoters(voters) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.tonight_voters = *voters;
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            request.message(cx.answer(match voters {
+                                Some(voters) => {
+                                    format!("Tonight polls are closed after {} votes.", voters)
+                                }
+                                None => format!(
+                                    "Tonight polls are closed after {}h.",
+                                    TONIGHT_DEADLINE_HOURS
+                                ),
+                            }));
+                        }
+                        Command::RatingStrategy(strategy) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.rating_strategy = *strategy;
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            request.message(
+                                cx.answer(format!("Poll votes are merged with {}.", strategy)),
+                            );
+                        }
+                        Command::Tonight(amount, filters) => {
+                            let cooked = state
+                                .read()
+                                .recently_cooked_ids(cx.chat_id(), history::RECENTLY_COOKED_DAYS);
+                            let recent = state.read().recent_meal_ids(cx.chat_id());
+                            let filters: Vec<String> = filters
+                                .iter()
+                                .map(|filter| {
+                                    if filter.starts_with('-') {
+                                        format!("-{}", state.read().resolve_tag(&filter[1..]))
+                                    } else {
+                                        state.read().resolve_tag(filter)
+                                    }
+                                })
+                                .collect();
+                            let meals: Vec<Meal> = state
+                                .read()
+                                .get_saved_meals_for(cx.chat_id())
+                                .into_iter()
+                                .filter(|meal| {
+                                    !cooked.contains(&meal.id) && meal.matches_tags(&filters)
+                                })
+                                .collect();
+                            let candidates = Plan::choose_distinct(meals, *amount, &recent);
+                            if candidates.len() < 2 {
+                                request.message(
+                                    cx.answer(format!("Not enough meals for tonight's poll!")),
+                                );
+                            } else {
+                                let keyboard = Keyboard::new()
+                                    .buttons(vec![vec![Button::new(
+                                        "Decide now".to_string(),
+                                        ButtonKind::StopPoll,
+                                    )]])
+                                    .save(&state);
                                 request.add(RequestKind::Poll(
                                     cx.bot
                                         .send_poll(
                                             cx.chat_id(),
-                                            format!("Plan:\n(Click to see details)"),
-                                            meal_plan.answers(),
+                                            "What's for dinner tonight?",
+                                            candidates
+                                                .iter()
+                                                .map(|meal| {
+                                                    format!(
+                                                        "{} ({}⭐)",
+                                                        meal.name.to_uppercase(),
+                                                        meal.rating.unwrap_or(1)
+                                                    )
+                                                })
+                                                .collect(),
                                         )
+                                        .is_anonymous(false)
                                         .reply_markup(ReplyMarkup::InlineKeyboardMarkup(
                                             keyboard.inline_keyboard(),
                                         )),
-                                    PollKind::Plan { plan: meal_plan },
+                                    PollKind::Tonight {
+                                        meals: candidates.iter().map(Meal::embedded).collect(),
+                                    },
                                     keyboard.id,
                                 ));
-                            } else {
-                                if meal_count < days_opt.unwrap_or(0) {
-                                    request.message(cx.bot.send_message(
-                                        cx.chat_id(),
-                                        format!("Not enough Meals to generate plan!"),
-                                    ));
+                            }
+                        }
+                        Command::Nutrition(NutritionAction::Meal(meal_name)) => {
+                            let meals = state.read().get_saved_meals_by_name(meal_name.clone());
+                            if meals.len() == 0 {
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
+                            }
+                            for meal in meals {
+                                let nutrition = state.read().meal_nutrition(&meal);
+                                request.message(cx.answer(if meal.ingredients.len() == 0 {
+                                    format!(
+                                        "{} has no ingredients!\n(Add with /ingredients)",
+                                        meal.name.to_uppercase()
+                                    )
                                 } else {
-                                    request.message(cx.bot.send_message(
-                                        cx.chat_id(),
-                                        format!("No Plan found, add with /plan <days>!"),
-                                    ));
+                                    nutrition::meal_text(&meal, &nutrition)
+                                }));
+                            }
+                        }
+                        Command::Nutrition(NutritionAction::Map(ingredient, food)) => {
+                            let food_opt =
+                                state.read().foods.find(food).map(|food| food.name.clone());
+                            match food_opt {
+                                Some(food) => {
+                                    state.write().map_food(ingredient, &food)?;
+                                    request.message(
+                                        cx.answer(format!("Mapped {} to {}.", ingredient, food)),
+                                    );
+                                }
+                                None => {
+                                    request.message(
+                                        cx.answer(format!("No food {} in the table!", food)),
+                                    );
+                                }
+                            }
+                        }
+                        Command::Nutrition(NutritionAction::Unmatched) => {
+                            let mut unmatched: Vec<String> = vec![];
+                            let meals = state.read().get_saved_meals();
+                            for meal in meals {
+                                for ingredient in state.read().meal_nutrition(&meal).unmatched {
+                                    if !unmatched.contains(&ingredient.name) {
+                                        unmatched.push(ingredient.name);
+                                    }
+                                }
+                            }
+                            unmatched.sort();
+                            request.message(cx.answer(if unmatched.len() == 0 {
+                                format!("All ingredients are counted!")
+                            } else {
+                                format!(
+                                    "Not counted (no food or no amount):\n{}\n\nMap with /nutrition map <ingredient>, <food>",
+                                    unmatched.join("\n")
+                                )
+                            }));
+                        }
+                        Command::Labels(meal_name, labels) => {
+                            update_saved_meals(
+                                state,
+                                cx,
+                                &mut request,
+                                meal_name,
+                                match labels {
+                                    Some(_) => format!("Saved diet labels!"),
+                                    None => format!("Inferred diet labels from ingredients!"),
+                                },
+                                |meal| {
+                                    meal.label(labels.clone());
+                                },
+                            )?;
+                        }
+                        Command::Diet(None) => {
+                            let profile = state.read().get_settings(cx.chat_id()).diet;
+                            request.message(cx.answer(diet::profile_text(&profile)));
+                        }
+                        Command::Diet(Some(profile)) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.diet = profile.clone();
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            let fitting = state.read().get_saved_meals_for(cx.chat_id()).len();
+                            request.message(cx.answer(format!(
+                                "{}\n{} of {} meals fit.",
+                                diet::profile_text(&profile),
+                                fitting,
+                                state.read().get_saved_meals().len()
+                            )));
+                        }
+                        Command::DietUnlabeled(include) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.diet_unlabeled = *include;
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            let fitting = state.read().get_saved_meals_for(cx.chat_id()).len();
+                            request.message(cx.answer(format!(
+                                "Meals without diet information are {} by the diet profile.\n{} of {} meals fit.",
+                                if *include { "included" } else { "excluded" },
+                                fitting,
+                                state.read().get_saved_meals().len()
+                            )));
+                        }
+                        Command::Pantry(PantryAction::List) => {
+                            let pantry = state.read().get_pantry(cx.chat_id());
+                            request.message(cx.answer(pantry.text(Local::today().naive_local())));
+                        }
+                        Command::Pantry(PantryAction::Add(ingredient, expires)) => {
+                            let mut pantry = state.read().get_pantry(cx.chat_id());
+                            pantry.add(ingredient.clone(), *expires);
+                            state.write().save_pantry(cx.chat_id(), &pantry)?;
+                            request
+                                .message(cx.answer(format!("Added {} to the pantry.", ingredient)));
+                        }
+                        Command::Pantry(PantryAction::Remove(ingredient)) => {
+                            let mut pantry = state.read().get_pantry(cx.chat_id());
+                            if pantry.take(ingredient) {
+                                state.write().save_pantry(cx.chat_id(), &pantry)?;
+                                request.message(
+                                    cx.answer(format!("Removed {} from the pantry.", ingredient)),
+                                );
+                            } else {
+                                request.message(
+                                    cx.answer(format!("No {} in the pantry!", ingredient.name)),
+                                );
+                            }
+                        }
+                        Command::Pantry(PantryAction::Sync(sync)) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            settings.pantry_sync = *sync;
+                            state.write().save_settings(cx.chat_id(), &settings)?;
+                            request.message(cx.answer(if *sync {
+                                "Ticked shopping items are added to the pantry."
+                            } else {
+                                "Shopping list and pantry are no longer synced."
+                            }));
+                        }
+                        Command::Pantry(PantryAction::Warn(time)) => {
+                            let job = time.and_then(|time| {
+                                let expression = format!("{} {} * * *", time.minute(), time.hour());
+                                let cron = Cron::parse(&expression).ok()?;
+                                Some(Job::new(
+                                    cx.chat_id(),
+                                    expression,
+                                    cron,
+                                    JobAction::WarnExpiring {
+                                        days: pantry::EXPIRY_WARNING_DAYS,
+                                    },
+                                    Local::now(),
+                                ))
+                            });
+                            {
+                                // a chat has a single daily expiry warning
+                                let mut state = state.write();
+                                let replaced: Vec<String> = state
+                                    .jobs()
+                                    .values()
+                                    .filter(|job| {
+                                        job.chat_id == cx.chat_id()
+                                            && match job.action {
+                                                JobAction::WarnExpiring { .. } => true,
+                                                _ => false,
+                                            }
+                                    })
+                                    .map(|job| job.id.clone())
+                                    .collect();
+                                for id in replaced {
+                                    state.jobs_mut().remove(&id);
+                                }
+                                if let Some(job) = &job {
+                                    state.jobs_mut().insert(job.id.clone(), job.clone());
                                 }
                             }
+                            request.message(cx.answer(match job {
+                                Some(job) => format!("Scheduled job {}", job),
+                                None => format!("Expiry warnings are off."),
+                            }));
+                        }
+                        Command::CanCook => {
+                            let pantry = state.read().get_pantry(cx.chat_id());
+                            let meals = state.read().get_saved_meals_for(cx.chat_id());
+                            request
+                                .message(cx.answer(pantry::cancook_text(&pantry.cancook(meals))));
+                        }
+                        Command::Slots(slots) => {
+                            let mut settings = state.read().get_settings(cx.chat_id());
+                            if slots.len() > 0 {
+                                settings.slots = slots.clone();
+                                state.write().save_settings(cx.chat_id(), &settings)?;
+                            }
+                            request.message(cx.answer(format!(
+                                "Planned slots: {}",
+                                settings
+                                    .slots
+                                    .iter()
+                                    .map(|slot| slot.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            )));
                         }
 
                         Command::List => {
                             let meal_btns: Vec<Vec<Button>> = state
                                 .read()
-                                .get_saved_meals()
+                                .get_saved_meals_for(cx.chat_id())
                                 .iter()
                                 .map(|meal| {
                                     vec![Button::new(
                                         meal.name.clone(),
-                                        ButtonKind::DisplayListMeal { meal: meal.clone() },
+                                        ButtonKind::DisplayListMeal {
+                                            meal: meal.embedded(),
+                                        },
                                     )]
                                 })
                                 .collect();
@@ -363,8 +1500,7 @@ impl Command {
                         Command::Rename(meal_name, new_name) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             if meals.len() == 0 {
-                                request
-                                    .message(cx.answer(format!("No meal with name {}", meal_name)));
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
                             }
                             for meal in meals {
                                 let res = state.write().remove_saved_meal(&meal);
@@ -373,7 +1509,7 @@ impl Command {
                                         if rem {
                                             let mut new_meal = meal.clone();
                                             new_meal.name = new_name.clone();
-                                            state.write().save_meal(&new_meal);
+                                            state.write().save_meal(&new_meal)?;
                                             request.add(meal.request(
                                                 &cx,
                                                 Some(format!(
@@ -385,15 +1521,14 @@ impl Command {
                                             log::info!("Renamed meal {} to {}", meal_name, new_name)
                                         }
                                     }
-                                    Err(err) => log::warn!("{}", err),
+                                    Err(err) => return Err(err),
                                 }
                             }
                         }
                         Command::Rate(meal_name, new_rating) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             if meals.len() == 0 {
-                                request
-                                    .message(cx.answer(format!("No meal with name {}", meal_name)));
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
                             }
                             for meal in meals {
                                 let res = state.write().remove_saved_meal(&meal);
@@ -401,8 +1536,8 @@ impl Command {
                                     Ok(rem) => {
                                         if rem {
                                             let mut new_meal = meal.clone();
-                                            new_meal.rating = Some(new_rating.clone());
-                                            state.write().save_meal(&new_meal);
+                                            new_meal.rate(Some(new_rating.clone()));
+                                            state.write().save_meal(&new_meal)?;
                                             request.add(meal.request(
                                                 &cx,
                                                 Some(format!(
@@ -418,15 +1553,35 @@ impl Command {
                                             )
                                         }
                                     }
-                                    Err(err) => log::warn!("{}", err),
+                                    Err(err) => return Err(err),
                                 }
                             }
                         }
+                        Command::Tag(meal_name, new_tags)
+                            if tags::normalize_all(new_tags).len() == 0 =>
+                        {
+                            let meals = state.read().get_saved_meals_by_name(meal_name.clone());
+                            if meals.len() == 0 {
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
+                            }
+                            for meal in meals {
+                                request.add(
+                                    meal.request(
+                                        &cx,
+                                        Some("Toggle tags:".to_string()),
+                                        Some(
+                                            Keyboard::new()
+                                                .buttons(button::edit_tags_buttons(state, &meal))
+                                                .save(&state),
+                                        ),
+                                    ),
+                                );
+                            }
+                        }
                         Command::Tag(meal_name, new_tags) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             if meals.len() == 0 {
-                                request
-                                    .message(cx.answer(format!("No meal with name {}", meal_name)));
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
                             }
                             for meal in meals {
                                 let res = state.write().remove_saved_meal(&meal);
@@ -435,7 +1590,7 @@ impl Command {
                                         if rem {
                                             let mut new_meal = meal.clone();
                                             new_meal.tag(Some(new_tags.clone()));
-                                            state.write().save_meal(&new_meal);
+                                            state.write().save_meal(&new_meal)?;
                                             request.add(meal.request(
                                                 &cx,
                                                 Some(format!(
@@ -451,31 +1606,27 @@ impl Command {
                                             )
                                         }
                                     }
-                                    Err(err) => log::warn!("{}", err),
+                                    Err(err) => return Err(err),
                                 }
                             }
                         }
                         Command::TagRemove(meal_name, rem_tags) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             if meals.len() == 0 {
-                                request
-                                    .message(cx.answer(format!("No meal with name {}", meal_name)));
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
                             }
                             for meal in meals {
                                 let res = state.write().remove_saved_meal(&meal);
                                 match res {
                                     Ok(rem) => {
                                         if rem {
+                                            let rem_tags: Vec<String> = rem_tags
+                                                .iter()
+                                                .map(|tag| state.read().resolve_tag(tag))
+                                                .collect();
                                             let mut new_meal = meal.clone();
-                                            let meal_tags = meal.tags.clone();
-                                            let mut new_tags = vec![];
-                                            for tag in meal_tags {
-                                                if !rem_tags.contains(&tag) {
-                                                    new_tags.push(tag);
-                                                }
-                                            }
-                                            new_meal.tags = new_tags.clone();
-                                            state.write().save_meal(&new_meal);
+                                            new_meal.untag(&rem_tags);
+                                            state.write().save_meal(&new_meal)?;
                                             request.add(meal.request(
                                                 &cx,
                                                 Some(format!(
@@ -491,15 +1642,58 @@ impl Command {
                                             )
                                         }
                                     }
-                                    Err(err) => log::warn!("{}", err),
+                                    Err(err) => return Err(err),
                                 }
                             }
                         }
+                        Command::Tags => {
+                            let counts = state.read().tag_counts();
+                            let aliases = state.read().tag_aliases();
+                            request.message(cx.answer(tags::tags_text(&counts, &aliases)));
+                        }
+                        Command::TagRename(old, new) => {
+                            let renamed = state.write().rename_tag(old, new)?;
+                            request.message(cx.answer(format!(
+                                "Renamed {} to {} in {} meals.",
+                                old, new, renamed
+                            )));
+                        }
+                        Command::TagMerge(from, into) => {
+                            let merged = state.write().merge_tags(from, into)?;
+                            request.message(cx.answer(format!(
+                                "Merged {} into {} in {} meals, {} is now an alias.",
+                                from, into, merged, from
+                            )));
+                        }
+                        Command::TagAlias(alias, tag) => {
+                            request.message(cx.answer(match tag {
+                                Some(tag) => {
+                                    // an alias only maps new input, tags in use are merged
+                                    let used = state
+                                        .read()
+                                        .tag_counts()
+                                        .into_iter()
+                                        .find(|(name, _)| name == &tags::normalize(alias))
+                                        .map(|(_, count)| count);
+                                    if let Some(count) = used {
+                                        return Err(Error::Parse(format!(
+                                            "{} is a tag of {} meals, use /tag-merge {} {}",
+                                            alias, count, alias, tag
+                                        )));
+                                    }
+                                    state.write().alias_tag(alias, Some(tag))?;
+                                    format!("{} now stands for {}.", alias, tag)
+                                }
+                                None => {
+                                    state.write().alias_tag(alias, None)?;
+                                    format!("Removed alias {}.", alias)
+                                }
+                            }));
+                        }
                         Command::Ref(meal_name, new_reference) => {
                             let meals = state.read().get_saved_meals_by_name(meal_name.clone());
                             if meals.len() == 0 {
-                                request
-                                    .message(cx.answer(format!("No meal with name {}", meal_name)));
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
                             }
                             for meal in meals {
                                 let res = state.write().remove_saved_meal(&meal);
@@ -508,7 +1702,7 @@ impl Command {
                                         if rem {
                                             let mut new_meal = meal.clone();
                                             new_meal.url = Some(new_reference.clone());
-                                            state.write().save_meal(&new_meal);
+                                            state.write().save_meal(&new_meal)?;
                                             request.add(meal.request(
                                                 &cx,
                                                 Some(format!(
@@ -524,7 +1718,177 @@ impl Command {
                                             )
                                         }
                                     }
-                                    Err(err) => log::warn!("{}", err),
+                                    Err(err) => return Err(err),
+                                }
+                            }
+                        }
+                        Command::Recipe(meal_name, servings) => {
+                            let meals = state.read().get_saved_meals_by_name(meal_name.clone());
+                            if meals.len() == 0 {
+                                return Err(Error::NotFound(format!("Meal {}", meal_name)));
+                            }
+                            for meal in meals {
+                                let mut buttons = vec![];
+                                if meal.steps.len() > 0 {
+                                    buttons.push(Button::new(
+                                        "Cook".to_string(),
+                                        ButtonKind::CookStep {
+                                            meal_id: meal.id.clone(),
+                                            step: 0,
+                                        },
+                                    ));
+                                }
+                                buttons.push(Button::new(
+                                    "Exit".to_string(),
+                                    ButtonKind::DeleteMessage,
+                                ));
+                                request.message(
+                                    cx.answer(meal.recipe_text(*servings)).reply_markup(
+                                        Keyboard::new()
+                                            .buttons(vec![buttons])
+                                            .save(&state)
+                                            .inline_keyboard(),
+                                    ),
+                                );
+                            }
+                        }
+                        Command::Steps(meal_name, steps) => {
+                            update_saved_meals(
+                                state,
+                                cx,
+                                &mut request,
+                                meal_name,
+                                format!("Saved {} steps!", steps.len()),
+                                |meal| {
+                                    meal.steps(steps.clone());
+                                },
+                            )?;
+                        }
+                        Command::RecipeInfo(meal_name, prep_time, cook_time, servings) => {
+                            update_saved_meals(
+                                state,
+                                cx,
+                                &mut request,
+                                meal_name,
+                                format!("Saved recipe info!"),
+                                |meal| {
+                                    meal.recipe_info(*prep_time, *cook_time, *servings);
+                                },
+                            )?;
+                        }
+                        Command::Ingredients(meal_name, ingredients) => {
+                            update_saved_meals(
+                                state,
+                                cx,
+                                &mut request,
+                                meal_name,
+                                format!("Saved {} ingredients!", ingredients.len()),
+                                |meal| {
+                                    meal.ingredients(ingredients.clone());
+                                },
+                            )?;
+                        }
+                        Command::Servings(day, servings) => {
+                            let plan_opt = state.read().get_plan(cx.chat_id());
+                            match plan_opt {
+                                Some(mut plan) if *day <= plan.days => {
+                                    for entry in plan.entries.iter_mut() {
+                                        if entry.day == day - 1 {
+                                            entry.servings = *servings;
+                                        }
+                                    }
+                                    state.write().save_plan(cx.chat_id(), plan.clone())?;
+                                    request.message(cx.answer(format!(
+                                        "{} now has {} servings.",
+                                        plan.date(day - 1).format("%a %d"),
+                                        servings
+                                            .map(|servings| servings.to_string())
+                                            .unwrap_or("default".to_string())
+                                    )));
+                                }
+                                Some(_) => {
+                                    request.message(cx.answer(format!("No day {} in plan!", day)));
+                                }
+                                None => {
+                                    request.message(
+                                        cx.answer(format!("No Plan found, add with /plan <days>!")),
+                                    );
+                                }
+                            }
+                        }
+                        Command::Shopping => {
+                            let plan_opt = state.read().get_plan(cx.chat_id());
+                            match plan_opt {
+                                Some(plan) => {
+                                    let previous = state
+                                        .read()
+                                        .get_shopping_list(cx.chat_id())
+                                        .filter(|list| list.plan_id == plan.id);
+                                    let saved = state.read().get_saved_meals();
+                                    let list = ShoppingList::from_plan(&plan, &saved, previous);
+                                    state.write().save_shopping_list(cx.chat_id(), &list)?;
+                                    request.message(
+                                        cx.answer(list.text()).reply_markup(
+                                            Keyboard::new()
+                                                .buttons(list.buttons())
+                                                .save(&state)
+                                                .inline_keyboard(),
+                                        ),
+                                    );
+                                }
+                                None => {
+                                    request.message(
+                                        cx.answer(format!("No Plan found, add with /plan <days>!")),
+                                    );
+                                }
+                            }
+                        }
+                        Command::Import => {
+                            request.message(cx.answer(format!(
+                                "Send a JSON, CSV (name, rating, tags, url, ingredients) or HTML recipe file with the caption /import!"
+                            )));
+                        }
+                        Command::Export(format) => {
+                            let meals = state.read().get_saved_meals();
+                            request.add(RequestKind::Document(
+                                cx.bot
+                                    .send_document(
+                                        cx.chat_id(),
+                                        InputFile::Memory {
+                                            file_name: format.file_name().to_string(),
+                                            data: Cow::Owned(format.export(&meals).into_bytes()),
+                                        },
+                                    )
+                                    .caption(format!("{} meals", meals.len())),
+                            ));
+                            if *format == ExportFormat::Json {
+                                if let Some(zip) = export::images_zip(&meals) {
+                                    request.add(RequestKind::Document(
+                                        cx.bot
+                                            .send_document(
+                                                cx.chat_id(),
+                                                InputFile::Memory {
+                                                    file_name: "images.zip".to_string(),
+                                                    data: Cow::Owned(zip),
+                                                },
+                                            )
+                                            .caption("Photos, named by content hash"),
+                                    ));
+                                }
+                            }
+                            if *format == ExportFormat::Markdown {
+                                if let Some(zip) = export::thumbnails_zip(&meals) {
+                                    request.add(RequestKind::Document(
+                                        cx.bot
+                                            .send_document(
+                                                cx.chat_id(),
+                                                InputFile::Memory {
+                                                    file_name: "thumbnails.zip".to_string(),
+                                                    data: Cow::Owned(zip),
+                                                },
+                                            )
+                                            .caption("Cover photos, unzip next to cookbook.md"),
+                                    ));
                                 }
                             }
                         }
@@ -536,19 +1900,57 @@ impl Command {
                                 cx.answer(format!("Bot version: {}", VERSION.unwrap_or("unknown"))),
                             );
                         }
+                        Command::Clean => {
+                            if !state.read().is_admin(username) {
+                                return Err(Error::Permission(
+                                    "Only admins can clean the chat!".to_string(),
+                                ));
+                            }
+                            let chat_id = cx.chat_id();
+                            let plan_message = state.read().plan_message(chat_id);
+                            let open_polls: Vec<i32> = state
+                                .read()
+                                .polls()
+                                .values()
+                                .filter(|poll| poll.chat_id == ChatId::Id(chat_id))
+                                .map(|poll| poll.message_id)
+                                .collect();
+                            let deletable_after = Local::now().timestamp() - DELETABLE_SECONDS;
+                            let sent_messages = state.read().sent_messages(chat_id);
+                            for sent in sent_messages {
+                                if Some(sent.message_id) == plan_message
+                                    || open_polls.contains(&sent.message_id)
+                                {
+                                    continue;
+                                }
+                                if (sent.date as i64) > deletable_after {
+                                    request.add(RequestKind::DeleteMessage(
+                                        cx.bot.delete_message(chat_id, sent.message_id),
+                                    ));
+                                } else {
+                                    state.write().forget_message(chat_id, sent.message_id);
+                                }
+                            }
+                        }
+                        Command::Metrics => {
+                            if !state.read().is_admin(username) {
+                                return Err(Error::Permission(
+                                    "Only admins can see the metrics!".to_string(),
+                                ));
+                            }
+                            let metrics = state.read().outbox.metrics.clone();
+                            request.message(cx.answer(metrics.to_string()));
+                        }
                     }
                 }
             }
-            _ => {
-                request.message(cx.answer(format!("No user found!")));
-                return request;
-            }
+            _ => return Err(Error::NotFound("User".to_string())),
         }
         request.add(RequestKind::DeleteMessage(cx.delete_message()));
-        request
+        Ok(request)
     }
 
-    pub fn execute(&self, state: &StateLock, cx: &ContextMessage) -> RequestResult {
+    pub fn execute(&self, state: &StateLock, cx: &ContextMessage) -> Result<RequestResult, Error> {
         Command::run(self, state, cx)
     }
 }
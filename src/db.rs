@@ -3,13 +3,23 @@ use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
 use std::fmt;
 
 use crate::meal::Meal;
+use crate::migration;
 
 #[derive(Debug)]
 pub enum DBKeys {
     Meals,
     Whitelist,
+    Admins,
+    Invites,
     State,
     Plans,
+    Shopping,
+    Settings,
+    History,
+    Cooking,
+    Nutrition,
+    Tags,
+    Pantry,
 }
 
 impl fmt::Display for DBKeys {
@@ -22,6 +32,13 @@ pub struct StoreHandler {
     pub db: pickledb::PickleDb,
     pub state_db: pickledb::PickleDb,
     pub plan_db: pickledb::PickleDb,
+    pub shopping_db: pickledb::PickleDb,
+    pub settings_db: pickledb::PickleDb,
+    pub history_db: pickledb::PickleDb,
+    pub cooking_db: pickledb::PickleDb,
+    pub nutrition_db: pickledb::PickleDb,
+    pub tags_db: pickledb::PickleDb,
+    pub pantry_db: pickledb::PickleDb,
 }
 
 impl StoreHandler {
@@ -30,23 +47,46 @@ impl StoreHandler {
             db: Self::create(DBKeys::Meals),
             state_db: Self::create_json(format!("database/{}.db", DBKeys::State)),
             plan_db: Self::create(DBKeys::Plans),
+            shopping_db: Self::create(DBKeys::Shopping),
+            settings_db: Self::create(DBKeys::Settings),
+            history_db: Self::create(DBKeys::History),
+            cooking_db: Self::create(DBKeys::Cooking),
+            nutrition_db: Self::create(DBKeys::Nutrition),
+            tags_db: Self::create(DBKeys::Tags),
+            pantry_db: Self::create(DBKeys::Pantry),
         };
         sh.create_list(DBKeys::Whitelist);
+        if sh.create_list(DBKeys::Admins) {
+            migration::seed_admins(&mut sh.db);
+        }
+        sh.create_list(DBKeys::Invites);
+        migration::upgrade_plans(&mut sh.plan_db);
+        migration::upgrade_state(&mut sh.state_db);
         sh.create_list(DBKeys::Meals);
+        migration::normalize_tags(&mut sh.db);
+        migration::store_images(&mut sh.db);
         if do_backup {
             sh.backup(DBKeys::Meals);
         }
         sh
     }
 
-    fn create_list(&mut self, key: DBKeys) {
+    /// Creates the list if it does not exist yet, returns whether it was created.
+    fn create_list(&mut self, key: DBKeys) -> bool {
         if !self.db.lexists(&key.to_string()) {
             match self.db.lcreate(&key.to_string()) {
-                Ok(_) => log::info!("Created new list: {}", key),
-                Err(err) => log::warn!("{}", err),
+                Ok(_) => {
+                    log::info!("Created new list: {}", key);
+                    true
+                }
+                Err(err) => {
+                    log::warn!("{}", err);
+                    false
+                }
             }
         } else {
             log::info!("Found existing list: {}", key);
+            false
         }
     }
 
@@ -71,21 +111,12 @@ impl StoreHandler {
 
     fn create(key: DBKeys) -> PickleDb {
         let path = format!("database/{}.db", key.to_string().to_lowercase());
-        match PickleDb::load(
-            path.clone(),
-            PickleDbDumpPolicy::AutoDump,
-            SerializationMethod::Bin,
-        ) {
-            Ok(db) => {
-                log::info!("Found existing {} database!", path);
-                db
-            }
-            Err(err) => {
-                log::warn!("{}", err);
-                log::info!("Creating new {} database!", path);
-                PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
-            }
+        match key {
+            DBKeys::Meals => migration::migrate_meals_db(&path),
+            DBKeys::Plans => migration::migrate_plans_db(&path),
+            _ => {}
         }
+        Self::create_json(path)
     }
 
     fn create_json(path: String) -> PickleDb {
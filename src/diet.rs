@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::ingredient::{singular, Ingredient};
+
+const MEAT: [&str; 12] = [
+    "chicken", "beef", "pork", "bacon", "ham", "sausage", "lamb", "turkey", "salami", "veal",
+    "duck", "chorizo",
+];
+const FISH: [&str; 6] = ["fish", "salmon", "tuna", "cod", "anchovy", "sardine"];
+const SHELLFISH: [&str; 6] = ["shrimp", "prawn", "crab", "lobster", "mussel", "clam"];
+const DAIRY: [&str; 10] = [
+    "milk",
+    "cheese",
+    "butter",
+    "buttermilk",
+    "cream",
+    "yogurt",
+    "parmesan",
+    "mozzarella",
+    "feta",
+    "ricotta",
+];
+const EGGS: [&str; 1] = ["egg"];
+const GLUTEN: [&str; 10] = [
+    "flour",
+    "bread",
+    "pasta",
+    "spaghetti",
+    "noodle",
+    "tortilla",
+    "couscous",
+    "barley",
+    "lasagne",
+    "breadcrumb",
+];
+const NUTS: [&str; 8] = [
+    "nut",
+    "peanut",
+    "almond",
+    "cashew",
+    "pistachio",
+    "pecan",
+    "hazelnut",
+    "walnut",
+];
+const SOY: [&str; 3] = ["soy", "tofu", "edamame"];
+
+/// Structured diet and allergen labels of a meal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Label {
+    Vegetarian,
+    Vegan,
+    GlutenFree,
+    LactoseFree,
+    ContainsNuts,
+    ContainsEggs,
+    ContainsFish,
+    ContainsShellfish,
+    ContainsSoy,
+}
+
+/// Compounds whose words would match the wrong list, replaced before matching.
+const COMPOUNDS: [(&str, &str); 6] = [
+    ("peanut butter", "peanut"),
+    ("almond milk", "almond"),
+    ("soy milk", "soy"),
+    ("oat milk", "oat"),
+    ("coconut milk", ""),
+    ("coconut cream", ""),
+];
+
+/// Whether an ingredient name contains one of the words as a whole word,
+/// "ham" matches "smoked ham" and "hams" but not "champignons".
+fn contains_any(ingredients: &Vec<Ingredient>, words: &[&str]) -> bool {
+    ingredients.iter().any(|ingredient| {
+        let name = COMPOUNDS
+            .iter()
+            .fold(ingredient.name.clone(), |name, (compound, replacement)| {
+                name.replace(compound, replacement)
+            });
+        name.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() > 0)
+            .map(singular)
+            .any(|word| words.contains(&word.as_str()))
+    })
+}
+
+impl Label {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "vegetarian" | "veggie" | "veg" => Some(Label::Vegetarian),
+            "vegan" => Some(Label::Vegan),
+            "gluten-free" | "glutenfree" | "gf" => Some(Label::GlutenFree),
+            "lactose-free" | "lactosefree" | "dairy-free" | "lf" => Some(Label::LactoseFree),
+            "nuts" | "contains-nuts" => Some(Label::ContainsNuts),
+            "eggs" | "contains-eggs" => Some(Label::ContainsEggs),
+            "fish" | "contains-fish" => Some(Label::ContainsFish),
+            "shellfish" | "contains-shellfish" => Some(Label::ContainsShellfish),
+            "soy" | "contains-soy" => Some(Label::ContainsSoy),
+            _ => None,
+        }
+    }
+
+    /// Allergen labels mark what a meal contains, the others what it is free of.
+    pub fn is_allergen(&self) -> bool {
+        match self {
+            Label::ContainsNuts
+            | Label::ContainsEggs
+            | Label::ContainsFish
+            | Label::ContainsShellfish
+            | Label::ContainsSoy => true,
+            _ => false,
+        }
+    }
+
+    /// Allergens found in the ingredient names.
+    pub fn allergens(ingredients: &Vec<Ingredient>) -> Vec<Label> {
+        vec![
+            (Label::ContainsNuts, &NUTS[..]),
+            (Label::ContainsEggs, &EGGS[..]),
+            (Label::ContainsFish, &FISH[..]),
+            (Label::ContainsShellfish, &SHELLFISH[..]),
+            (Label::ContainsSoy, &SOY[..]),
+        ]
+        .into_iter()
+        .filter(|(_, words)| contains_any(ingredients, words))
+        .map(|(label, _)| label)
+        .collect()
+    }
+
+    /// All labels that follow from a complete ingredient list.
+    pub fn infer(ingredients: &Vec<Ingredient>) -> Vec<Label> {
+        let mut labels = Self::allergens(ingredients);
+        let animal = contains_any(ingredients, &MEAT)
+            || contains_any(ingredients, &FISH)
+            || contains_any(ingredients, &SHELLFISH);
+        let dairy = contains_any(ingredients, &DAIRY);
+        if !animal {
+            labels.push(Label::Vegetarian);
+            if !dairy && !contains_any(ingredients, &EGGS) && !contains_any(ingredients, &["honey"])
+            {
+                labels.push(Label::Vegan);
+            }
+        }
+        if !contains_any(ingredients, &GLUTEN) {
+            labels.push(Label::GlutenFree);
+        }
+        if !dairy {
+            labels.push(Label::LactoseFree);
+        }
+        labels.sort();
+        labels
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Label::Vegetarian => write!(f, "🥕 vegetarian"),
+            Label::Vegan => write!(f, "🌱 vegan"),
+            Label::GlutenFree => write!(f, "🌾 gluten-free"),
+            Label::LactoseFree => write!(f, "🥛 lactose-free"),
+            Label::ContainsNuts => write!(f, "🥜 nuts"),
+            Label::ContainsEggs => write!(f, "🥚 eggs"),
+            Label::ContainsFish => write!(f, "🐟 fish"),
+            Label::ContainsShellfish => write!(f, "🦐 shellfish"),
+            Label::ContainsSoy => write!(f, "🫘 soy"),
+        }
+    }
+}
+
+/// Diet profile of a chat: meals need all diet labels and must contain none of the allergens.
+pub fn fits(labels: &Vec<Label>, profile: &Vec<Label>) -> bool {
+    profile.iter().all(|required| {
+        if required.is_allergen() {
+            !labels.contains(required)
+        } else {
+            labels.contains(required)
+                // vegan meals are vegetarian and lactose-free too
+                || (labels.contains(&Label::Vegan)
+                    && (*required == Label::Vegetarian || *required == Label::LactoseFree))
+        }
+    })
+}
+
+pub fn profile_text(profile: &Vec<Label>) -> String {
+    if profile.len() == 0 {
+        return "No diet profile, all meals are planned.".to_string();
+    }
+    let only: Vec<String> = profile
+        .iter()
+        .filter(|label| !label.is_allergen())
+        .map(|label| label.to_string())
+        .collect();
+    let never: Vec<String> = profile
+        .iter()
+        .filter(|label| label.is_allergen())
+        .map(|label| label.to_string())
+        .collect();
+    let mut lines = vec![];
+    if only.len() > 0 {
+        lines.push(format!("Only: {}", only.join(", ")));
+    }
+    if never.len() > 0 {
+        lines.push(format!("Never: {}", never.join(", ")));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingredients(names: &[&str]) -> Vec<Ingredient> {
+        names
+            .iter()
+            .map(|name| Ingredient::new(name, None, None))
+            .collect()
+    }
+
+    #[test]
+    fn matches_whole_words() {
+        assert!(contains_any(&ingredients(&["smoked ham"]), &MEAT));
+        assert!(contains_any(&ingredients(&["hams"]), &MEAT));
+        assert!(!contains_any(&ingredients(&["champignons"]), &MEAT));
+        assert!(!contains_any(&ingredients(&["donut"]), &NUTS));
+        assert!(!contains_any(&ingredients(&["nutmeg", "coconut"]), &NUTS));
+        assert!(contains_any(&ingredients(&["mixed nuts"]), &NUTS));
+        assert!(contains_any(&ingredients(&["anchovies"]), &FISH));
+        assert!(!contains_any(&ingredients(&["eggplant"]), &EGGS));
+    }
+
+    #[test]
+    fn infers_labels() {
+        assert_eq!(
+            Label::infer(&ingredients(&["rice", "tofu", "champignons"])),
+            vec![
+                Label::Vegetarian,
+                Label::Vegan,
+                Label::GlutenFree,
+                Label::LactoseFree,
+                Label::ContainsSoy
+            ]
+        );
+        assert_eq!(
+            Label::infer(&ingredients(&["spaghetti", "eggs", "parmesan", "bacon"])),
+            vec![Label::ContainsEggs]
+        );
+        assert_eq!(
+            Label::infer(&ingredients(&["peanut butter", "bread"])),
+            vec![
+                Label::Vegetarian,
+                Label::Vegan,
+                Label::LactoseFree,
+                Label::ContainsNuts
+            ]
+        );
+    }
+
+    #[test]
+    fn fits_profile() {
+        let vegan = vec![Label::Vegan, Label::GlutenFree];
+        assert!(fits(&vegan, &vec![Label::Vegetarian, Label::LactoseFree]));
+        assert!(!fits(&vec![Label::Vegetarian], &vec![Label::Vegan]));
+        assert!(!fits(
+            &vec![Label::ContainsNuts],
+            &vec![Label::ContainsNuts]
+        ));
+        assert!(fits(&vec![], &vec![Label::ContainsNuts]));
+    }
+}
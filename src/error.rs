@@ -0,0 +1,114 @@
+use nanoid::nanoid;
+use std::fmt;
+use teloxide::prelude::*;
+use teloxide::utils::command::ParseError;
+use teloxide::{DownloadError, RequestError};
+
+use crate::request::{RequestKind, RequestResult};
+use crate::{ContextCallback, ContextMessage};
+
+/// Length of the reference id shown to users and written to the log.
+const REFERENCE_LEN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Reading or writing a database failed.
+    Storage(String),
+    /// Input of the user could not be understood, the message is shown to the user.
+    Parse(String),
+    /// The user is not allowed to do this, the message is shown to the user.
+    Permission(String),
+    /// A request to telegram failed.
+    Telegram(String),
+    /// What could not be found, e.g. "Meal pasta".
+    NotFound(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Storage(err) => write!(f, "Storage: {}", err),
+            Error::Parse(err) => write!(f, "Parse: {}", err),
+            Error::Permission(err) => write!(f, "Permission: {}", err),
+            Error::Telegram(err) => write!(f, "Telegram: {}", err),
+            Error::NotFound(what) => write!(f, "Not found: {}", what),
+        }
+    }
+}
+
+impl From<pickledb::error::Error> for Error {
+    fn from(err: pickledb::error::Error) -> Self {
+        Error::Storage(err.to_string())
+    }
+}
+
+impl From<RequestError> for Error {
+    fn from(err: RequestError) -> Self {
+        Error::Telegram(err.to_string())
+    }
+}
+
+impl From<DownloadError> for Error {
+    fn from(err: DownloadError) -> Self {
+        Error::Telegram(err.to_string())
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl Error {
+    /// Short reply for the user, the details stay in the log.
+    pub fn user_text(&self) -> String {
+        match self {
+            Error::Storage(_) => "Could not save your changes, please try again later!".to_string(),
+            Error::Parse(err) | Error::Permission(err) => err.clone(),
+            Error::Telegram(_) => {
+                "Telegram did not accept the request, please try again!".to_string()
+            }
+            Error::NotFound(what) => format!("{} not found!", what),
+        }
+    }
+
+    /// Logs the error with what was being done, returns the reference id of the log entry.
+    pub fn log(&self, context: &str) -> String {
+        let reference = nanoid!(REFERENCE_LEN);
+        match self {
+            Error::Storage(_) | Error::Telegram(_) => {
+                log::error!("[{}] {}: {}", reference, context, self)
+            }
+            _ => log::info!("[{}] {}: {}", reference, context, self),
+        }
+        reference
+    }
+
+    /// Logs the error and returns the reply for the user.
+    pub fn report(&self, context: &str) -> String {
+        let reference = self.log(context);
+        format!("{}\n(ref {})", self.user_text(), reference)
+    }
+
+    /// Answers a message that could not be handled.
+    pub fn message_request(&self, cx: &ContextMessage, context: &str) -> RequestResult {
+        let mut request = RequestResult::default();
+        request.message(cx.answer(self.report(context)));
+        request
+    }
+
+    /// Answers a button press that could not be handled with an alert.
+    pub fn callback_request(&self, cx: &ContextCallback, context: &str) -> RequestResult {
+        let mut request = RequestResult::default();
+        request.add(RequestKind::CallbackAnswer(
+            cx.bot
+                .answer_callback_query(cx.update.id.clone())
+                .text(self.report(context))
+                .show_alert(true),
+        ));
+        request
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use zip::write::{FileOptions, ZipWriter};
+use zip::CompressionMethod;
+
+use crate::meal::Meal;
+
+/// Section of meals without tags in the cookbook.
+const UNTAGGED: &str = "other";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    /// Lossless, can be imported again.
+    Json,
+    Csv,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            _ => None,
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "meals.json",
+            ExportFormat::Csv => "meals.csv",
+            ExportFormat::Markdown => "cookbook.md",
+        }
+    }
+
+    pub fn export(&self, meals: &Vec<Meal>) -> String {
+        match self {
+            ExportFormat::Json => serde_json::to_string_pretty(meals).unwrap_or_else(|err| {
+                log::warn!("{}", err);
+                "[]".to_string()
+            }),
+            ExportFormat::Csv => csv(meals),
+            ExportFormat::Markdown => markdown(meals),
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Same columns as the CSV import: name, rating, tags, url, ingredients.
+fn csv(meals: &Vec<Meal>) -> String {
+    let mut lines = vec!["name,rating,tags,url,ingredients".to_string()];
+    for meal in meals.iter() {
+        lines.push(
+            vec![
+                meal.name.clone(),
+                meal.rating.map(|r| r.to_string()).unwrap_or_default(),
+                meal.tags.join(" "),
+                meal.url.clone().unwrap_or_default(),
+                meal.ingredients
+                    .iter()
+                    .map(|ingredient| ingredient.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            ]
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>()
+            .join(","),
+        );
+    }
+    lines.join("\n") + "\n"
+}
+
+fn markdown_meal(meal: &Meal) -> String {
+    let mut text = format!("### {}\n", meal.name);
+    if let Some(image) = meal.cover_photo().and_then(|photo| meal.image_of(photo)) {
+        text.push_str(&format!("\n![{}]({})\n", meal.name, image.thumbnail_name()));
+    }
+    let mut info = vec![];
+    if let Some(rating) = meal.rating {
+        info.push("⭐".repeat(rating as usize));
+    }
+    if let Some(servings) = meal.servings {
+        info.push(format!("Servings: {}", servings));
+    }
+    if let Some(prep) = meal.prep_time {
+        info.push(format!("Prep: {} min", prep));
+    }
+    if let Some(cook) = meal.cook_time {
+        info.push(format!("Cook: {} min", cook));
+    }
+    if info.len() > 0 {
+        text.push_str(&format!("\n{}\n", info.join(" | ")));
+    }
+    if meal.tags.len() > 0 {
+        text.push_str(&format!(
+            "\n{}\n",
+            meal.tags
+                .iter()
+                .map(|tag| format!("`#{}`", tag))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
+    }
+    if let Some(url) = &meal.url {
+        text.push_str(&format!("\n<{}>\n", url));
+    }
+    if meal.ingredients.len() > 0 {
+        text.push_str("\n#### Ingredients\n\n");
+        for ingredient in meal.ingredients.iter() {
+            text.push_str(&format!("- {}\n", ingredient));
+        }
+    }
+    if meal.steps.len() > 0 {
+        text.push_str("\n#### Steps\n\n");
+        for (i, step) in meal.steps.iter().enumerate() {
+            text.push_str(&format!("{}. {}\n", i + 1, step));
+        }
+    }
+    text
+}
+
+/// Cookbook with one section per meal, grouped by the first tag of each meal.
+fn markdown(meals: &Vec<Meal>) -> String {
+    let mut groups: BTreeMap<String, Vec<&Meal>> = BTreeMap::new();
+    for meal in meals.iter() {
+        let tag = meal.tags.get(0).cloned().unwrap_or(UNTAGGED.to_string());
+        groups.entry(tag).or_insert(vec![]).push(meal);
+    }
+    // meals without tags come last
+    let untagged = groups.remove(UNTAGGED);
+    let mut text = "# Cookbook\n".to_string();
+    for (tag, mut meals) in groups.into_iter().chain(
+        untagged
+            .map(|meals| (UNTAGGED.to_string(), meals))
+            .into_iter(),
+    ) {
+        meals.sort_by_key(|meal| meal.name.to_uppercase());
+        text.push_str(&format!("\n## {}\n", tag));
+        for meal in meals {
+            text.push_str(&format!("\n{}", markdown_meal(meal)));
+        }
+    }
+    text
+}
+
+/// Zip of the stored photos of all meals, named by their content hash.
+/// `None` if no photo is stored.
+pub fn images_zip(meals: &Vec<Meal>) -> Option<Vec<u8>> {
+    zip_files(
+        meals
+            .iter()
+            .flat_map(|meal| meal.images.iter())
+            .map(|image| (image.file_name(), image.path()))
+            .collect(),
+    )
+}
+
+/// Zip of the cover thumbnails shown in the cookbook, `None` if no meal has a stored cover.
+pub fn thumbnails_zip(meals: &Vec<Meal>) -> Option<Vec<u8>> {
+    zip_files(
+        meals
+            .iter()
+            .filter_map(|meal| meal.cover_photo().and_then(|photo| meal.image_of(photo)))
+            .filter_map(|image| Some((image.thumbnail_name(), image.thumbnail()?)))
+            .collect(),
+    )
+}
+
+/// Zip of the readable files by their name in the zip, duplicates are added once.
+fn zip_files(files: Vec<(String, PathBuf)>) -> Option<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    // photos are compressed already
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut added = HashSet::new();
+    for (name, path) in files {
+        if added.contains(&name) {
+            continue;
+        }
+        if let Ok(bytes) = std::fs::read(path) {
+            let written = zip
+                .start_file(name.as_str(), options)
+                .map_err(|err| err.to_string())
+                .and_then(|_| zip.write_all(&bytes).map_err(|err| err.to_string()));
+            match written {
+                Ok(()) => {
+                    added.insert(name);
+                }
+                Err(err) => log::warn!("{}", err),
+            }
+        }
+    }
+    if added.len() == 0 {
+        return None;
+    }
+    match zip.finish() {
+        Ok(cursor) => Some(cursor.into_inner()),
+        Err(err) => {
+            log::warn!("{}", err);
+            None
+        }
+    }
+}
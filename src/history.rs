@@ -0,0 +1,122 @@
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::button::{Button, ButtonKind};
+use crate::meal::Meal;
+use crate::plan::Plan;
+
+const HISTORY_PAGE_SIZE: usize = 8;
+/// Meals cooked during the last days are not suggested for tonight.
+pub const RECENTLY_COOKED_DAYS: i64 = 7;
+
+/// A meal that was cooked in a chat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cooked {
+    pub meal_id: String,
+    pub name: String,
+    /// Unix timestamp of when the meal was cooked.
+    pub timestamp: i64,
+}
+
+impl Cooked {
+    pub fn new(meal: &Meal) -> Self {
+        Self {
+            meal_id: meal.id.clone(),
+            name: meal.name.clone(),
+            timestamp: Utc::now().timestamp(),
+        }
+    }
+}
+
+/// Number of archived plans and the most planned meals.
+pub fn statistics(plans: &Vec<Plan>) -> String {
+    if plans.len() == 0 {
+        return "No archived plans yet!\n(Plans are archived when a new plan is created)"
+            .to_string();
+    }
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for entry in plans.iter().flat_map(|plan| plan.entries.iter()) {
+        *counts.entry(entry.meal.name.to_uppercase()).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    format!(
+        "Archived plans: {}\nPlanned meals: {}\nMost planned: {}",
+        plans.len(),
+        plans.iter().map(|plan| plan.entries.len()).sum::<usize>(),
+        counts
+            .iter()
+            .take(5)
+            .map(|(name, count)| format!("{} ({}x)", name, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Archived plans, newest first.
+pub fn history_buttons(plans: &Vec<Plan>, page: usize) -> Vec<Vec<Button>> {
+    let pages = (plans.len() + HISTORY_PAGE_SIZE - 1) / HISTORY_PAGE_SIZE;
+    let mut buttons: Vec<Vec<Button>> = plans
+        .iter()
+        .rev()
+        .skip(page * HISTORY_PAGE_SIZE)
+        .take(HISTORY_PAGE_SIZE)
+        .map(|plan| {
+            vec![Button::new(
+                plan.title(),
+                ButtonKind::ShowArchivedPlan {
+                    plan_id: plan.id.clone(),
+                },
+            )]
+        })
+        .collect();
+    let mut navigation = vec![];
+    if page > 0 {
+        navigation.push(Button::new(
+            "Previous".to_string(),
+            ButtonKind::ShowPlanHistory { page: page - 1 },
+        ));
+    }
+    navigation.push(Button::new("Exit".to_string(), ButtonKind::DeleteMessage));
+    if page + 1 < pages {
+        navigation.push(Button::new(
+            "Next".to_string(),
+            ButtonKind::ShowPlanHistory { page: page + 1 },
+        ));
+    }
+    buttons.push(navigation);
+    buttons
+}
+
+pub fn archived_plan_buttons(plan: &Plan) -> Vec<Vec<Button>> {
+    vec![
+        vec![
+            Button::new(
+                "Reuse".to_string(),
+                ButtonKind::ReusePlan {
+                    plan_id: plan.id.clone(),
+                },
+            ),
+            Button::new(
+                "Clone week".to_string(),
+                ButtonKind::CloneWeek {
+                    plan_id: plan.id.clone(),
+                },
+            ),
+        ],
+        vec![
+            Button::new("Back".to_string(), ButtonKind::ShowPlanHistory { page: 0 }),
+            Button::new("Exit".to_string(), ButtonKind::DeleteMessage),
+        ],
+    ]
+}
+
+/// First day after `after` on the same weekday as the start of `plan`.
+pub fn clone_start(plan: &Plan, after: NaiveDate) -> NaiveDate {
+    let mut start = after + Duration::days(1);
+    while start.weekday() != plan.start.weekday() {
+        start = start + Duration::days(1);
+    }
+    start
+}
@@ -0,0 +1,210 @@
+use chrono::{NaiveTime, Utc};
+use std::thread;
+use tiny_http::{Header, Response, Server};
+
+use crate::plan::{Plan, Slot};
+use crate::StateLock;
+
+fn slot_time(slot: Slot) -> (NaiveTime, NaiveTime) {
+    match slot {
+        Slot::Breakfast => (NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(9, 0, 0)),
+        Slot::Lunch => (NaiveTime::from_hms(12, 0, 0), NaiveTime::from_hms(13, 0, 0)),
+        Slot::Dinner => (NaiveTime::from_hms(18, 0, 0), NaiveTime::from_hms(19, 0, 0)),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Folds content lines longer than 75 octets as required by RFC 5545.
+fn fold(line: String) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+/// One VEVENT per planned meal, times are floating local times of the meal's slot.
+pub fn calendar(plan: &Plan, name: &str) -> String {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//ate//meal plan//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape(name)),
+    ];
+    for (index, entry) in plan.sorted_entries() {
+        let date = plan.date(entry.day);
+        let (start, end) = slot_time(entry.slot);
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}-{}@ate", plan.id, index));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!(
+            "DTSTART:{}",
+            date.and_time(start).format("%Y%m%dT%H%M%S")
+        ));
+        lines.push(format!(
+            "DTEND:{}",
+            date.and_time(end).format("%Y%m%dT%H%M%S")
+        ));
+        lines.push(format!(
+            "SUMMARY:{}",
+            escape(&format!("{}: {}", entry.slot, entry.meal.name))
+        ));
+        lines.push(format!(
+            "DESCRIPTION:{}",
+            escape(&format!("{}", entry.meal))
+        ));
+        if entry.meal.tags.len() > 0 {
+            lines.push(format!(
+                "CATEGORIES:{}",
+                entry
+                    .meal
+                    .tags
+                    .iter()
+                    .map(|tag| escape(tag))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ));
+        }
+        if let Some(url) = &entry.meal.url {
+            lines.push(format!("URL:{}", url));
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.into_iter().map(fold).collect::<Vec<_>>().join("\r\n") + "\r\n"
+}
+
+fn token_from_path(path: &str) -> Option<&str> {
+    let path = path.split('?').next().unwrap_or("");
+    if path.starts_with("/calendar/") && path.ends_with(".ics") {
+        Some(&path["/calendar/".len()..path.len() - ".ics".len()])
+    } else {
+        None
+    }
+}
+
+pub fn subscription_path(token: &str) -> String {
+    format!("/calendar/{}.ics", token)
+}
+
+/// Serves the plan of every chat as `/calendar/<token>.ics`.
+pub fn serve(state: StateLock, address: String) {
+    thread::spawn(move || {
+        let server = match Server::http(&address) {
+            Ok(server) => server,
+            Err(err) => {
+                log::warn!("Calendar server: {}", err);
+                return;
+            }
+        };
+        log::info!("Serving calendars on {}", address);
+        for request in server.incoming_requests() {
+            let plan = token_from_path(request.url()).and_then(|token| {
+                let state = state.read();
+                state
+                    .find_chat_by_ics_token(token)
+                    .and_then(|chat_id| state.get_plan(chat_id))
+            });
+            let result = match plan {
+                Some(plan) => request.respond(
+                    Response::from_string(calendar(&plan, "Meal Plan")).with_header(
+                        Header::from_bytes(
+                            &b"Content-Type"[..],
+                            &b"text/calendar; charset=utf-8"[..],
+                        )
+                        .unwrap(),
+                    ),
+                ),
+                None => request.respond(Response::from_string("Not found").with_status_code(404)),
+            };
+            if let Err(err) = result {
+                log::warn!("Calendar server: {}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::meal::Meal;
+    use crate::plan::PlanEntry;
+
+    #[test]
+    fn escapes_text_values() {
+        assert_eq!(escape("a,b;c\\d\ne"), r"a\,b\;c\\d\ne");
+    }
+
+    #[test]
+    fn folds_long_lines_by_octets() {
+        let folded = fold("x".repeat(80));
+        assert_eq!(folded, format!("{}\r\n {}", "x".repeat(75), "x".repeat(5)));
+        // multi byte characters are never split
+        let folded = fold("ä".repeat(40));
+        let lines: Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(lines[0], "ä".repeat(37));
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), "ä".repeat(40));
+    }
+
+    #[test]
+    fn calendar_has_one_event_per_entry() {
+        let mut plan = Plan::new(NaiveDate::from_ymd(2021, 3, 1), 2, vec![Slot::Dinner]);
+        let mut meal = Meal::new(&"Pasta, Pesto".to_string());
+        meal.tag(Some(vec!["Italian".to_string(), "quick".to_string()]))
+            .url(Some("https://example.com/pasta".to_string()));
+        plan.entries.push(PlanEntry::new(1, Slot::Dinner, meal));
+        plan.entries.push(PlanEntry::new(
+            0,
+            Slot::Lunch,
+            Meal::new(&"Soup".to_string()),
+        ));
+        let ics = calendar(&plan, "Meal Plan");
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+        let unfolded = ics.replace("\r\n ", "");
+        let lines: Vec<&str> = unfolded.split("\r\n").collect();
+        assert_eq!(
+            lines.iter().filter(|line| **line == "BEGIN:VEVENT").count(),
+            2
+        );
+        // events are ordered by day, the soup comes first
+        let soup = lines.iter().position(|line| *line == "SUMMARY:Lunch: Soup");
+        let pasta = lines
+            .iter()
+            .position(|line| *line == "SUMMARY:Dinner: Pasta\\, Pesto");
+        assert!(soup.unwrap() < pasta.unwrap());
+        assert!(lines.contains(&"DTSTART:20210301T120000"));
+        assert!(lines.contains(&"DTSTART:20210302T180000"));
+        assert!(lines.contains(&"DTEND:20210302T190000"));
+        assert!(lines.contains(&"CATEGORIES:italian,quick"));
+        assert!(lines.contains(&"URL:https://example.com/pasta"));
+        assert!(lines.contains(&format!("UID:{}-0@ate", plan.id).as_str()));
+    }
+
+    #[test]
+    fn token_only_from_calendar_paths() {
+        let path = subscription_path("abc");
+        assert_eq!(token_from_path(&path), Some("abc"));
+        assert_eq!(token_from_path("/calendar/abc.ics?x=1"), Some("abc"));
+        assert_eq!(token_from_path("/other/abc.ics"), None);
+        assert_eq!(token_from_path("/calendar/abc"), None);
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use teloxide::prelude::*;
+use teloxide::requests::Request;
+use teloxide::types::PhotoSize;
+
+/// Directory of the photos of saved meals, named by the sha256 of their content.
+pub const IMAGES_DIR: &str = "./images";
+const THUMBNAILS_DIR: &str = "thumbnails";
+/// Longest side of a thumbnail in pixels.
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// Photo of a meal stored on disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredImage {
+    pub hash: String,
+    pub extension: String,
+    /// Telegram photo the image belongs to.
+    pub file_unique_id: String,
+}
+
+impl StoredImage {
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.hash, self.extension)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        Path::new(IMAGES_DIR).join(self.file_name())
+    }
+
+    pub fn thumbnail_path(&self) -> PathBuf {
+        Path::new(IMAGES_DIR).join(self.thumbnail_name())
+    }
+
+    /// Path of the thumbnail relative to the images directory, also used in the cookbook export.
+    pub fn thumbnail_name(&self) -> String {
+        format!("{}/{}.jpg", THUMBNAILS_DIR, self.hash)
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path().exists()
+    }
+
+    /// Thumbnail of the image, created if it is missing.
+    pub fn thumbnail(&self) -> Option<PathBuf> {
+        let path = self.thumbnail_path();
+        if !path.exists() {
+            let bytes = fs::read(self.path()).ok()?;
+            if let Err(err) = write_thumbnail(&bytes, &path) {
+                log::warn!("Thumbnail of {}: {}", self.file_name(), err);
+                return None;
+            }
+        }
+        Some(path)
+    }
+}
+
+/// File extension from the magic bytes of an image, telegram photos are jpeg.
+pub fn extension(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G']) {
+        "png"
+    } else if bytes.starts_with(b"GIF8") {
+        "gif"
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        "webp"
+    } else {
+        "jpg"
+    }
+}
+
+pub fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn write_thumbnail(bytes: &[u8], path: &Path) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    let image = image::load_from_memory(bytes).map_err(|err| err.to_string())?;
+    image::DynamicImage::ImageRgb8(image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8())
+        .save_with_format(path, image::ImageFormat::Jpeg)
+        .map_err(|err| err.to_string())
+}
+
+/// Saves an image by its content hash, identical images are stored once.
+pub fn store(bytes: &[u8], file_unique_id: &str) -> Result<StoredImage, String> {
+    fs::create_dir_all(IMAGES_DIR).map_err(|err| err.to_string())?;
+    let image = StoredImage {
+        hash: hash(bytes),
+        extension: extension(bytes).to_string(),
+        file_unique_id: file_unique_id.to_string(),
+    };
+    let is_new = !image.exists();
+    if is_new {
+        fs::write(image.path(), bytes).map_err(|err| err.to_string())?;
+        log::info!("Stored image {}", image.file_name());
+    }
+    if is_new || !image.thumbnail_path().exists() {
+        if let Err(err) = write_thumbnail(bytes, &image.thumbnail_path()) {
+            log::warn!("Thumbnail of {}: {}", image.file_name(), err);
+        }
+    }
+    Ok(image)
+}
+
+/// Downloads a telegram photo into the store.
+pub async fn download(bot: &Bot, photo: &PhotoSize) -> Result<StoredImage, String> {
+    let file = bot
+        .get_file(photo.file_id.clone())
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut bytes: Vec<u8> = vec![];
+    bot.download_file(&file.file_path, &mut bytes)
+        .await
+        .map_err(|err| err.to_string())?;
+    log::info!(
+        "Downloaded File: {} | Size: {}",
+        file.file_path,
+        file.file_size
+    );
+    store(&bytes, &photo.file_unique_id)
+}
@@ -0,0 +1,578 @@
+use nanoid::nanoid;
+use serde_json::Value;
+use std::fmt;
+use teloxide::requests::Request;
+use teloxide::types::{Document, File as TgFile};
+
+use crate::button::{Button, ButtonKind};
+use crate::command;
+use crate::error::Error;
+use crate::ingredient::Ingredient;
+use crate::keyboard::Keyboard;
+use crate::meal::Meal;
+use crate::request::RequestResult;
+use crate::{ContextMessage, StateLock};
+
+/// Larger documents are rejected before downloading.
+const MAX_IMPORT_BYTES: u32 = 2_000_000;
+/// Number of meals listed in the preview.
+const PREVIEW_MEALS: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Meals as written by /export json.
+    Json,
+    /// Rows of name, rating, tags, url, ingredients.
+    Csv,
+    /// schema.org Recipe as JSON-LD, standalone or embedded in HTML.
+    Recipe,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "JSON"),
+            Format::Csv => write!(f, "CSV"),
+            Format::Recipe => write!(f, "schema.org Recipe"),
+        }
+    }
+}
+
+/// Meals of a document and the detected format, all meals get new ids.
+pub fn parse(content: &str) -> Result<(Format, Vec<Meal>), String> {
+    let content = content.trim_start_matches('\u{feff}').trim();
+    let (format, mut meals) = if content.starts_with('<') {
+        (Format::Recipe, parse_html(content)?)
+    } else if content.starts_with('[') || content.starts_with('{') {
+        match serde_json::from_str::<Vec<Meal>>(content) {
+            Ok(meals) => (Format::Json, meals),
+            Err(err) => match serde_json::from_str::<Value>(content).map(|value| recipes(&value)) {
+                Ok(meals) if meals.len() > 0 => (Format::Recipe, meals),
+                _ => {
+                    return Err(format!(
+                        "Neither exported meals nor a schema.org Recipe: {}",
+                        err
+                    ))
+                }
+            },
+        }
+    } else {
+        (Format::Csv, parse_csv(content)?)
+    };
+    if meals.len() == 0 {
+        return Err(format!("No meals found in the {} document!", format));
+    }
+    for meal in meals.iter_mut() {
+        meal.id = nanoid!();
+    }
+    Ok((format, meals))
+}
+
+/// Fields of all records, quoted fields may contain commas, quotes ("") and line breaks.
+fn csv_records(content: &str) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().len() == 0 => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            ('\r', false) => {}
+            (c, _) => field.push(c),
+        }
+    }
+    if field.len() > 0 || record.len() > 0 {
+        record.push(field);
+        records.push(record);
+    }
+    records
+        .into_iter()
+        .filter(|record| record.iter().any(|field| field.trim().len() > 0))
+        .collect()
+}
+
+fn parse_csv(content: &str) -> Result<Vec<Meal>, String> {
+    let mut meals = vec![];
+    for (line, record) in csv_records(content).into_iter().enumerate() {
+        let field = |index: usize| {
+            record
+                .get(index)
+                .map(|field| field.trim())
+                .filter(|field| field.len() > 0)
+        };
+        let name = match field(0) {
+            Some(name) if line == 0 && name.to_lowercase() == "name" => continue,
+            Some(name) => name.to_string(),
+            None => return Err(format!("Row {} has no meal name!", line + 1)),
+        };
+        let rating = match field(1).map(|rating| rating.parse::<u8>()) {
+            Some(Ok(rating)) if rating >= 1 && rating <= 5 => Some(rating),
+            Some(_) => return Err(format!("Row {}: rating has to be 1-5!", line + 1)),
+            None => None,
+        };
+        let mut meal = Meal::new(&name);
+        meal.rate(rating)
+            .tag(field(2).map(|tags| {
+                tags.split(|c: char| c == ';' || c.is_whitespace())
+                    .map(|tag| tag.to_string())
+                    .collect()
+            }))
+            .url(field(3).map(|url| url.to_string()))
+            .ingredients(
+                field(4)
+                    .map(|ingredients| {
+                        ingredients
+                            .split(|c: char| c == ';' || c == '|')
+                            .filter_map(Ingredient::parse)
+                            .collect()
+                    })
+                    .unwrap_or(vec![]),
+            );
+        meals.push(meal);
+    }
+    Ok(meals)
+}
+
+/// Recipes of all JSON-LD scripts of an HTML page.
+fn parse_html(content: &str) -> Result<Vec<Meal>, String> {
+    let mut meals = vec![];
+    let mut rest = content;
+    while let Some(pos) = rest.find("application/ld+json") {
+        rest = &rest[pos..];
+        let start = match rest.find('>') {
+            Some(start) => start + 1,
+            None => break,
+        };
+        let end = match rest[start..].find("</script>") {
+            Some(end) => start + end,
+            None => break,
+        };
+        match serde_json::from_str::<Value>(rest[start..end].trim()) {
+            Ok(value) => meals.append(&mut recipes(&value)),
+            Err(err) => log::warn!("Invalid JSON-LD: {}", err),
+        }
+        rest = &rest[end..];
+    }
+    if meals.len() == 0 {
+        return Err("No schema.org Recipe found in the HTML document!".to_string());
+    }
+    Ok(meals)
+}
+
+fn is_recipe(value: &Value) -> bool {
+    match value.get("@type") {
+        Some(Value::String(kind)) => kind == "Recipe",
+        Some(Value::Array(kinds)) => kinds.iter().any(|kind| kind == "Recipe"),
+        _ => false,
+    }
+}
+
+/// All schema.org Recipes in a JSON-LD value, also inside "@graph" and lists.
+fn recipes(value: &Value) -> Vec<Meal> {
+    match value {
+        Value::Object(object) if is_recipe(value) => recipe(object).into_iter().collect(),
+        Value::Object(object) => object.values().flat_map(recipes).collect(),
+        Value::Array(values) => values.iter().flat_map(recipes).collect(),
+        _ => vec![],
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(decode_entities(text.trim())),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Array(values) => values.iter().find_map(text),
+        Value::Object(object) => object
+            .get("text")
+            .or(object.get("name"))
+            .or(object.get("@id"))
+            .and_then(text),
+        _ => None,
+    }
+}
+
+/// Strings of a value that may be a single string, a comma separated list or an array.
+fn texts(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values.iter().filter_map(text).collect(),
+        value => text(value)
+            .map(|text| text.split(',').map(|s| s.trim().to_string()).collect())
+            .unwrap_or(vec![]),
+    }
+}
+
+/// Steps of "recipeInstructions", which may be text, HowToSteps or HowToSections.
+fn instructions(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(text) => text
+            .lines()
+            .map(|line| decode_entities(line.trim()))
+            .filter(|line| line.len() > 0)
+            .collect(),
+        Value::Array(values) => values.iter().flat_map(instructions).collect(),
+        Value::Object(object) => match object.get("itemListElement") {
+            Some(steps) => instructions(steps),
+            None => text(value).into_iter().collect(),
+        },
+        _ => vec![],
+    }
+}
+
+/// Minutes of an ISO 8601 duration like "PT1H30M".
+fn minutes(duration: &str) -> Option<u32> {
+    let mut minutes = 0.0;
+    let mut number = String::new();
+    let mut time = false;
+    for c in duration.trim().chars().skip_while(|c| *c == 'P') {
+        match c {
+            'T' => time = true,
+            '0'..='9' | '.' => number.push(c),
+            unit => {
+                let value = number.parse::<f64>().ok()?;
+                number.clear();
+                minutes += value
+                    * match (unit, time) {
+                        ('D', false) => 24.0 * 60.0,
+                        ('H', true) => 60.0,
+                        ('M', true) => 1.0,
+                        ('S', true) => 1.0 / 60.0,
+                        _ => return None,
+                    };
+            }
+        }
+    }
+    Some(minutes.round() as u32)
+}
+
+fn recipe(object: &serde_json::Map<String, Value>) -> Option<Meal> {
+    let name = object.get("name").and_then(text)?;
+    let mut meal = Meal::new(&name);
+    let mut tags = vec![];
+    for key in ["keywords", "recipeCategory", "recipeCuisine"].iter() {
+        if let Some(value) = object.get(*key) {
+            tags.extend(texts(value).into_iter().map(|tag| tag.replace(' ', "-")));
+        }
+    }
+    let servings = object
+        .get("recipeYield")
+        .and_then(text)
+        .and_then(|servings| {
+            servings
+                .split(|c: char| !c.is_ascii_digit())
+                .find(|number| number.len() > 0)
+                .and_then(|number| number.parse::<u8>().ok())
+        });
+    meal.tag(Some(tags))
+        .url(object.get("url").and_then(text))
+        .steps(
+            object
+                .get("recipeInstructions")
+                .map(instructions)
+                .unwrap_or(vec![]),
+        )
+        .recipe_info(
+            object
+                .get("prepTime")
+                .and_then(text)
+                .and_then(|time| minutes(&time)),
+            object
+                .get("cookTime")
+                .and_then(text)
+                .and_then(|time| minutes(&time)),
+            servings,
+        )
+        .ingredients(
+            object
+                .get("recipeIngredient")
+                .or(object.get("ingredients"))
+                .map(|ingredients| {
+                    texts(ingredients)
+                        .iter()
+                        .filter_map(|ingredient| Ingredient::parse(ingredient))
+                        .collect()
+                })
+                .unwrap_or(vec![]),
+        );
+    Some(meal)
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#039;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Names of imported meals that are already saved.
+pub fn duplicates(meals: &Vec<Meal>, saved: &Vec<Meal>) -> Vec<String> {
+    let mut duplicates: Vec<String> = meals
+        .iter()
+        .map(|meal| meal.name.to_uppercase())
+        .filter(|name| saved.iter().any(|meal| &meal.name.to_uppercase() == name))
+        .collect();
+    duplicates.sort();
+    duplicates.dedup();
+    duplicates
+}
+
+pub fn preview_text(
+    file_name: &str,
+    format: Format,
+    meals: &Vec<Meal>,
+    duplicates: &Vec<String>,
+) -> String {
+    let mut text = format!(
+        "Import {} ({}):\n{} meals, {} with ingredients, {} with steps",
+        file_name,
+        format,
+        meals.len(),
+        meals
+            .iter()
+            .filter(|meal| meal.ingredients.len() > 0)
+            .count(),
+        meals.iter().filter(|meal| meal.steps.len() > 0).count()
+    );
+    if duplicates.len() > 0 {
+        text.push_str(&format!(
+            "\n{} already saved: {}",
+            duplicates.len(),
+            duplicates.join(", ")
+        ));
+    }
+    text.push('\n');
+    for meal in meals.iter().take(PREVIEW_MEALS) {
+        text.push_str(&format!("\n- {}", meal.name.to_uppercase()));
+    }
+    if meals.len() > PREVIEW_MEALS {
+        text.push_str(&format!("\n… and {} more", meals.len() - PREVIEW_MEALS));
+    }
+    text
+}
+
+pub fn preview_buttons(import_id: &str, has_duplicates: bool) -> Vec<Vec<Button>> {
+    let confirm = |text: &str, replace: bool| {
+        vec![Button::new(
+            text.to_string(),
+            ButtonKind::ConfirmImport {
+                import_id: import_id.to_string(),
+                replace,
+            },
+        )]
+    };
+    let mut buttons = if has_duplicates {
+        vec![
+            confirm("Import, skip duplicates", false),
+            confirm("Import, replace duplicates", true),
+        ]
+    } else {
+        vec![confirm("Import", false)]
+    };
+    buttons.push(vec![Button::new(
+        "Cancel".to_uppercase(),
+        ButtonKind::CancelImport {
+            import_id: import_id.to_string(),
+        },
+    )]);
+    buttons
+}
+
+/// Saves imported meals, duplicates are skipped or replace the saved meals.
+/// Returns the number of saved meals.
+pub fn commit(state: &StateLock, meals: &Vec<Meal>, replace: bool) -> Result<usize, Error> {
+    let saved = state.read().get_saved_meals();
+    let duplicates = duplicates(meals, &saved);
+    let mut imported = 0;
+    for meal in meals.iter() {
+        if duplicates.contains(&meal.name.to_uppercase()) {
+            if !replace {
+                continue;
+            }
+            for old in saved
+                .iter()
+                .filter(|old| old.name.to_uppercase() == meal.name.to_uppercase())
+            {
+                state.write().remove_saved_meal(old)?;
+            }
+        }
+        state.write().save_meal(meal)?;
+        imported += 1;
+    }
+    log::info!("Imported {} meals", imported);
+    Ok(imported)
+}
+
+/// Downloads a document sent with /import and answers with a preview.
+pub async fn document_request(
+    cx: &ContextMessage,
+    state: &StateLock,
+    document: &Document,
+) -> Result<RequestResult, Error> {
+    let mut request = RequestResult::default();
+    command::whitelisted_user(state, cx.update.from())?;
+    if document.file_size.unwrap_or(0) > MAX_IMPORT_BYTES {
+        return Err(Error::Parse(format!(
+            "File is too large, imports are limited to {} MB!",
+            MAX_IMPORT_BYTES / 1_000_000
+        )));
+    }
+    let mut content: Vec<u8> = vec![];
+    let TgFile { file_path, .. } = cx.bot.get_file(document.file_id.clone()).send().await?;
+    cx.bot.download_file(&file_path, &mut content).await?;
+    let content = match String::from_utf8(content) {
+        Ok(content) if content.len() > 0 => content,
+        _ => {
+            return Err(Error::Parse(
+                "Could not read the file, it has to be UTF-8 text!".to_string(),
+            ))
+        }
+    };
+    let (format, meals) = parse(&content).map_err(Error::Parse)?;
+    let duplicates = duplicates(&meals, &state.read().get_saved_meals());
+    let import_id = nanoid!();
+    let text = preview_text(
+        document.file_name.as_deref().unwrap_or("document"),
+        format,
+        &meals,
+        &duplicates,
+    );
+    state.write().imports.insert(import_id.clone(), meals);
+    request.message(
+        cx.answer(text).reply_markup(
+            Keyboard::new()
+                .buttons(preview_buttons(&import_id, duplicates.len() > 0))
+                .save(state)
+                .inline_keyboard(),
+        ),
+    );
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_records_with_quotes_and_line_breaks() {
+        let records =
+            csv_records("name,tags\r\n\"Pasta, red\",\"a \"\"b\"\"\"\n\n\"multi\nline\",x");
+        assert_eq!(
+            records,
+            vec![
+                vec!["name", "tags"],
+                vec!["Pasta, red", "a \"b\""],
+                vec!["multi\nline", "x"],
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_csv_rows() {
+        let (format, meals) = parse(
+            "name,rating,tags,url,ingredients\n\
+             Pasta,4,Italian;quick,https://example.com,500 g pasta|2 tomatoes\n\
+             Soup,,,,\n",
+        )
+        .unwrap();
+        assert_eq!(format, Format::Csv);
+        assert_eq!(meals.len(), 2);
+        assert_eq!(meals[0].name, "Pasta");
+        assert_eq!(meals[0].rating, Some(4));
+        assert_eq!(meals[0].tags, vec!["italian", "quick"]);
+        assert_eq!(meals[0].url, Some("https://example.com".to_string()));
+        assert_eq!(
+            meals[0].ingredients,
+            vec![
+                Ingredient::new("pasta", Some(500.0), Some("g".to_string())),
+                Ingredient::new("tomatoes", Some(2.0), None),
+            ]
+        );
+        assert_eq!(meals[1].rating, None);
+        assert_eq!(meals[1].ingredients.len(), 0);
+        assert!(parse("Pasta,7").is_err());
+        assert!(parse(",4").is_err());
+    }
+
+    #[test]
+    fn parses_json_ld_recipes_in_html() {
+        let html = r#"<html><head>
+            <script type="application/ld+json">{"@type": "WebSite", "name": "Blog"}</script>
+            <script type="application/ld+json">{
+                "@context": "https://schema.org",
+                "@graph": [{"@type": "WebPage"}, {
+                    "@type": ["Recipe"],
+                    "name": "Chili &amp; Beans",
+                    "url": "https://example.com/chili",
+                    "keywords": "spicy, Tex Mex",
+                    "recipeYield": ["4 servings"],
+                    "prepTime": "PT15M",
+                    "cookTime": "PT1H30M",
+                    "recipeIngredient": ["500 g beans", "1 onion"],
+                    "recipeInstructions": [{
+                        "@type": "HowToSection",
+                        "itemListElement": [
+                            {"@type": "HowToStep", "text": "Chop"},
+                            {"@type": "HowToStep", "text": "Cook"}
+                        ]
+                    }]
+                }]
+            }</script>
+        </head></html>"#;
+        let (format, meals) = parse(html).unwrap();
+        assert_eq!(format, Format::Recipe);
+        assert_eq!(meals.len(), 1);
+        let meal = &meals[0];
+        assert_eq!(meal.name, "Chili & Beans");
+        assert_eq!(meal.url, Some("https://example.com/chili".to_string()));
+        assert_eq!(meal.tags, vec!["spicy", "tex-mex"]);
+        assert_eq!(meal.servings, Some(4));
+        assert_eq!((meal.prep_time, meal.cook_time), (Some(15), Some(90)));
+        assert_eq!(meal.steps, vec!["Chop", "Cook"]);
+        assert_eq!(meal.ingredients.len(), 2);
+        assert!(parse("<html></html>").is_err());
+    }
+
+    #[test]
+    fn tells_exports_and_recipes_apart() {
+        let exported = Meal::new(&"Toast".to_string());
+        let json = serde_json::to_string(&vec![exported.clone()]).unwrap();
+        let (format, meals) = parse(&json).unwrap();
+        assert_eq!(format, Format::Json);
+        assert_eq!(meals[0].name, "Toast");
+        assert_ne!(meals[0].id, exported.id);
+        let (format, meals) = parse(r#"{"@type": "Recipe", "name": "Toast"}"#).unwrap();
+        assert_eq!(format, Format::Recipe);
+        assert_eq!(meals[0].name, "Toast");
+        assert!(parse(r#"{"name": "Toast"}"#).is_err());
+        assert!(parse("[]").is_err());
+    }
+
+    #[test]
+    fn iso_8601_durations_in_minutes() {
+        assert_eq!(minutes("PT1H30M"), Some(90));
+        assert_eq!(minutes("PT15M"), Some(15));
+        assert_eq!(minutes("PT0.5H"), Some(30));
+        assert_eq!(minutes("PT45S"), Some(1));
+        assert_eq!(minutes("P1DT2H"), Some(26 * 60));
+        assert_eq!(minutes("PT"), Some(0));
+        assert_eq!(minutes("PT5X"), None);
+        assert_eq!(minutes("1 hour"), None);
+    }
+}
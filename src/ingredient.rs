@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const UNITS: [&str; 16] = [
+    "mg", "g", "kg", "ml", "cl", "dl", "l", "tsp", "tbsp", "cup", "cups", "pinch", "can", "cans",
+    "pack", "packs",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ingredient {
+    pub name: String,
+    pub quantity: Option<f64>,
+    pub unit: Option<String>,
+}
+
+impl Ingredient {
+    pub fn new(name: &str, quantity: Option<f64>, unit: Option<String>) -> Self {
+        Self {
+            name: name.trim().to_lowercase(),
+            quantity,
+            unit,
+        }
+    }
+
+    /// Parses inputs like "500 g rice", "500g rice", "1/2 onion" or "salt".
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut words: Vec<String> = input.split_whitespace().map(|w| w.to_string()).collect();
+        if words.len() == 0 {
+            return None;
+        }
+        let first = words[0].clone();
+        let number_len = first
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == ',' || *c == '/')
+            .count();
+        // only a unit may be attached to the number, "7up" is a name
+        let attached = first[number_len..].to_lowercase();
+        let quantity = if attached.len() == 0 || UNITS.contains(&attached.as_str()) {
+            parse_quantity(&first[..number_len])
+        } else {
+            None
+        };
+        let mut unit = None;
+        if quantity.is_some() {
+            words.remove(0);
+            if attached.len() > 0 {
+                unit = Some(attached);
+            } else if let Some(word) = words.get(0) {
+                if UNITS.contains(&word.to_lowercase().as_str()) {
+                    unit = Some(words.remove(0).to_lowercase());
+                }
+            }
+        }
+        let name = words.join(" ");
+        if name.trim().len() == 0 {
+            return None;
+        }
+        Some(Self::new(&name, quantity, unit))
+    }
+
+    pub fn scaled(&self, factor: f64) -> Self {
+        Self {
+            name: self.name.clone(),
+            quantity: self
+                .quantity
+                .map(|quantity| round_quantity(quantity * factor, self.unit.as_deref())),
+            unit: self.unit.clone(),
+        }
+    }
+
+    /// Whether both ingredients can be added up in a shopping list.
+    pub fn same_kind(&self, other: &Ingredient) -> bool {
+        self.name == other.name && self.unit == other.unit
+    }
+
+    /// Name of the food without plural, "onions" and "onion" have the same key.
+    pub fn food_key(&self) -> String {
+        match self.name.rfind(' ') {
+            Some(pos) => format!(
+                "{}{}",
+                &self.name[..pos + 1],
+                singular(&self.name[pos + 1..])
+            ),
+            None => singular(&self.name),
+        }
+    }
+
+    /// Whether both ingredients name the same food, ignoring plurals like "onions".
+    pub fn same_food(&self, other: &Ingredient) -> bool {
+        self.food_key() == other.food_key()
+    }
+
+    /// Quantity converted to the unit of `other`, if both units measure the same.
+    pub fn quantity_in(&self, other: &Ingredient) -> Option<f64> {
+        let quantity = self.quantity?;
+        let (unit, factor) = base_unit(self.unit.as_deref());
+        let (other_unit, other_factor) = base_unit(other.unit.as_deref());
+        if unit == other_unit {
+            Some(quantity * factor / other_factor)
+        } else {
+            None
+        }
+    }
+}
+
+/// Words ending like a plural that are not, or whose singular the rules below get wrong.
+const SINGULARS: [(&str, &str); 10] = [
+    ("hummus", "hummus"),
+    ("asparagus", "asparagus"),
+    ("couscous", "couscous"),
+    ("molasses", "molasses"),
+    ("swiss", "swiss"),
+    ("cookies", "cookie"),
+    ("brownies", "brownie"),
+    ("leaves", "leaf"),
+    ("halves", "half"),
+    ("loaves", "loaf"),
+];
+
+/// Singular of an english food word, "tomatoes" is "tomato" and "berries" is "berry".
+pub fn singular(word: &str) -> String {
+    if let Some((_, singular)) = SINGULARS
+        .iter()
+        .find(|(plural, singular)| *plural == word || *singular == word)
+    {
+        return singular.to_string();
+    }
+    if word.len() <= 3 || word.ends_with("ss") || word.ends_with("us") || word.ends_with("is") {
+        word.to_string()
+    } else if word.len() > 4 && word.ends_with("ies") {
+        format!("{}y", &word[..word.len() - 3])
+    } else if ["oes", "ches", "shes", "xes", "zes"]
+        .iter()
+        .any(|ending| word.ends_with(ending))
+    {
+        word[..word.len() - 2].to_string()
+    } else if word.ends_with('s') {
+        word[..word.len() - 1].to_string()
+    } else {
+        word.to_string()
+    }
+}
+
+/// Unit that quantities can be converted to and the conversion factor.
+fn base_unit(unit: Option<&str>) -> (Option<&str>, f64) {
+    match unit {
+        Some("mg") => (Some("g"), 0.001),
+        Some("kg") => (Some("g"), 1000.0),
+        Some("cl") => (Some("ml"), 10.0),
+        Some("dl") => (Some("ml"), 100.0),
+        Some("l") => (Some("ml"), 1000.0),
+        Some("cups") => (Some("cup"), 1.0),
+        Some("cans") => (Some("can"), 1.0),
+        Some("packs") => (Some("pack"), 1.0),
+        unit => (unit, 1.0),
+    }
+}
+
+fn parse_quantity(input: &str) -> Option<f64> {
+    let input = input.replace(",", ".");
+    if let [numerator, denominator] = input.split('/').collect::<Vec<_>>().as_slice() {
+        match (numerator.parse::<f64>(), denominator.parse::<f64>()) {
+            (Ok(n), Ok(d)) if d > 0.0 => Some(n / d),
+            _ => None,
+        }
+    } else {
+        input.parse::<f64>().ok()
+    }
+}
+
+/// Rounds a scaled quantity to steps that make sense for its unit.
+pub fn round_quantity(quantity: f64, unit: Option<&str>) -> f64 {
+    let step = match unit {
+        Some("g") | Some("ml") => {
+            if quantity < 20.0 {
+                1.0
+            } else if quantity < 200.0 {
+                5.0
+            } else {
+                10.0
+            }
+        }
+        Some("mg") => 10.0,
+        Some("kg") | Some("l") => 0.05,
+        Some("cl") | Some("dl") => 0.5,
+        Some("tsp") | Some("tbsp") | Some("cup") | Some("cups") => 0.25,
+        _ => 0.5,
+    };
+    let rounded = (quantity / step).round() * step;
+    if rounded <= 0.0 && quantity > 0.0 {
+        step
+    } else {
+        rounded
+    }
+}
+
+pub fn format_quantity(quantity: f64) -> String {
+    if (quantity - quantity.round()).abs() < 0.001 {
+        format!("{}", quantity.round() as i64)
+    } else {
+        format!("{:.2}", quantity)
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+impl fmt::Display for Ingredient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.quantity, &self.unit) {
+            (Some(quantity), Some(unit)) => {
+                write!(f, "{} {} {}", format_quantity(quantity), unit, self.name)
+            }
+            (Some(quantity), None) => write!(f, "{} {}", format_quantity(quantity), self.name),
+            _ => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> String {
+        Ingredient::new(name, None, None).food_key()
+    }
+
+    #[test]
+    fn food_key_removes_plurals() {
+        assert_eq!(key("onions"), "onion");
+        assert_eq!(key("tomatoes"), "tomato");
+        assert_eq!(key("berries"), "berry");
+        assert_eq!(key("peaches"), "peach");
+        assert_eq!(key("cheeses"), "cheese");
+        assert_eq!(key("red onions"), "red onion");
+        assert_eq!(key("bay leaves"), "bay leaf");
+        assert_eq!(key("cookies"), key("cookie"));
+    }
+
+    #[test]
+    fn food_key_keeps_singulars() {
+        assert_eq!(key("hummus"), "hummus");
+        assert_eq!(key("asparagus"), "asparagus");
+        assert_eq!(key("couscous"), "couscous");
+        assert_eq!(key("swiss chard"), "swiss chard");
+        assert_eq!(key("tomato"), "tomato");
+        assert_eq!(key("peas"), "pea");
+        assert_eq!(key("gas"), "gas");
+        assert_eq!(key("pies"), "pie");
+    }
+
+    fn parse(input: &str) -> (String, Option<f64>, Option<String>) {
+        let ingredient = Ingredient::parse(input).unwrap();
+        (ingredient.name, ingredient.quantity, ingredient.unit)
+    }
+
+    #[test]
+    fn parses_quantity_and_unit() {
+        let rice = ("rice".to_string(), Some(500.0), Some("g".to_string()));
+        assert_eq!(parse("500 g rice"), rice);
+        assert_eq!(parse("500g rice"), rice);
+        assert_eq!(parse("500G Rice"), rice);
+        assert_eq!(parse("1/2 onion"), ("onion".to_string(), Some(0.5), None));
+        assert_eq!(
+            parse("1,5 l milk"),
+            ("milk".to_string(), Some(1.5), Some("l".to_string()))
+        );
+        assert_eq!(parse("salt"), ("salt".to_string(), None, None));
+        assert!(Ingredient::parse("500 g").is_none());
+        assert!(Ingredient::parse("  ").is_none());
+    }
+
+    #[test]
+    fn keeps_names_starting_with_a_number() {
+        assert_eq!(parse("7up"), ("7up".to_string(), None, None));
+        assert_eq!(parse("2 7up"), ("7up".to_string(), Some(2.0), None));
+        assert_eq!(parse("1/0 eggs"), ("1/0 eggs".to_string(), None, None));
+    }
+
+    #[test]
+    fn scales_to_sensible_steps() {
+        let rice = Ingredient::parse("500 g rice").unwrap();
+        assert_eq!(rice.scaled(0.5).quantity, Some(250.0));
+        assert_eq!(rice.scaled(0.333).quantity, Some(165.0));
+        let onion = Ingredient::parse("1 onion").unwrap();
+        assert_eq!(onion.scaled(1.5).quantity, Some(1.5));
+        assert_eq!(onion.scaled(0.1).quantity, Some(0.5));
+        assert_eq!(
+            Ingredient::parse("salt").unwrap().scaled(2.0).quantity,
+            None
+        );
+        assert_eq!(format!("{}", onion.scaled(1.5)), "1.5 onion");
+    }
+
+    #[test]
+    fn same_food_ignores_plurals() {
+        let tomatoes = Ingredient::new("tomatoes", Some(2.0), None);
+        assert!(tomatoes.same_food(&Ingredient::new("tomato", None, None)));
+        assert!(
+            !Ingredient::new("hummus", None, None).same_food(&Ingredient::new("hummu", None, None))
+        );
+    }
+}
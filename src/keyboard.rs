@@ -9,6 +9,9 @@ use crate::StateLock;
 pub struct Keyboard {
     pub id: String,
     pub buttons: Vec<Vec<Button>>,
+    /// Chat id and message id of the message showing the keyboard, if it was remembered.
+    #[serde(default)]
+    pub message: Option<(i64, i32)>,
 }
 
 impl Keyboard {
@@ -16,6 +19,7 @@ impl Keyboard {
         Self {
             id: nanoid!(),
             buttons: vec![],
+            message: None,
         }
     }
 
@@ -0,0 +1,79 @@
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use teloxide::dispatching::UpdateWithCx;
+use teloxide::types::{CallbackQuery, Message};
+
+pub mod auth;
+pub mod button;
+pub mod command;
+pub mod db;
+pub mod diet;
+pub mod error;
+pub mod export;
+pub mod history;
+pub mod ics;
+pub mod images;
+pub mod import;
+pub mod ingredient;
+pub mod keyboard;
+pub mod meal;
+pub mod migration;
+pub mod nutrition;
+pub mod outbox;
+pub mod pantry;
+pub mod photo;
+pub mod plan;
+pub mod poll;
+pub mod rating;
+pub mod request;
+pub mod schedule;
+pub mod settings;
+pub mod shopping;
+pub mod state;
+pub mod tags;
+
+use state::State;
+
+pub const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+pub type StateLock = Arc<RwLock<State>>;
+pub type ContextCallback = UpdateWithCx<CallbackQuery>;
+pub type ContextMessage = UpdateWithCx<Message>;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Config {
+    /// Argon2 hash of the admin password, created with `ate hash <password>`.
+    #[serde(default)]
+    pub password_hash: String,
+    /// Plain admin password of config files before v0.5, hashed on startup.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    pub token: String,
+    pub name: String,
+    pub backup: bool,
+    /// Address to serve calendar subscriptions on, e.g. "0.0.0.0:8080".
+    #[serde(default)]
+    pub calendar_address: Option<String>,
+    /// Public url of the calendar server, used in subscription links.
+    #[serde(default)]
+    pub calendar_url: Option<String>,
+}
+
+impl Config {
+    /// Hashes the plain password of an old config file, fails if there is no password at all.
+    pub fn upgrade(mut self) -> Result<Self, String> {
+        if self.password_hash.is_empty() {
+            let password = self.password.take().ok_or(
+                "config.json needs a \"password_hash\", create it with `ate hash <password>`!",
+            )?;
+            self.password_hash = auth::hash_password(&password).map_err(|err| err.to_string())?;
+            log::warn!(
+                "config.json contains a plain \"password\", replace it with \"password_hash\": \"{}\"",
+                self.password_hash
+            );
+        }
+        self.password = None;
+        Ok(self)
+    }
+}
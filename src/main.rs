@@ -1,34 +1,33 @@
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use parking_lot::RwLock;
-use serde::{Deserialize, Serialize};
 use std::fs;
 use std::sync::Arc;
-use teloxide::types::File as TgFile;
 use teloxide::{dispatching::*, prelude::*, types::*, utils::command::BotCommand, BotBuilder};
-use tokio::fs::File;
 
-mod button;
-use button::{Button, ButtonKind};
-mod db;
-mod meal;
-use meal::Meal;
-mod command;
-use command::Command;
-mod keyboard;
-use keyboard::Keyboard;
-mod state;
-use state::State;
-mod poll;
-mod request;
-use request::{RequestKind, RequestResult};
-mod plan;
+use ate::command::Command;
+use ate::error::Error;
+use ate::meal::Meal;
+use ate::request::{RequestKind, RequestResult};
+use ate::state::State;
+use ate::{auth, ics, import, photo, schedule, Config, ContextMessage, StateLock};
 
-pub const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
-
-pub type StateLock = Arc<RwLock<State>>;
-pub type ContextCallback = UpdateWithCx<CallbackQuery>;
-pub type ContextMessage = UpdateWithCx<Message>;
+/// Sends the answer of a handler, or a friendly error reply with a reference to the log.
+async fn send_result(
+    result: Result<RequestResult, Error>,
+    cx: &ContextMessage,
+    state: &StateLock,
+    context: &str,
+) {
+    let err = match result {
+        Ok(request) => match request.send(state).await.errors.into_iter().next() {
+            Some(err) => err,
+            None => return,
+        },
+        Err(err) => err,
+    };
+    err.message_request(cx, context).send(state).await;
+}
 
 async fn handle_message(state: StateLock, rx: DispatcherHandlerRx<Message>) {
     rx.map(|cx| (cx, state.clone()))
@@ -38,162 +37,23 @@ async fn handle_message(state: StateLock, rx: DispatcherHandlerRx<Message>) {
                 if !text.starts_with("/") {
                     return;
                 }
-                let parsed = Command::parse(text, bot_name);
-                match parsed {
-                    Ok(command) => command.execute(&state, &cx).send(&state).await,
-                    Err(err) => {
-                        if let Err(err) = cx.answer(err.to_string()).send().await {
-                            log::warn!("{}", err);
-                        }
-                    }
-                }
-            } else if let Some(photos) = cx.update.photo() {
-                if let Some(last_photo) = photos.last() {
-                    if let Some(caption) = cx.update.caption() {
-                        if !caption.starts_with("/") {
-                            return;
-                        }
-                        let parsed = Command::parse(caption, bot_name);
-                        match parsed {
-                            Ok(command) => match &command {
-                                Command::New {
-                                    meal_name,
-                                    rating,
-                                    tags,
-                                    url,
-                                } => {
-                                    if let Ok(TgFile {
-                                        file_path,
-                                        file_unique_id,
-                                        file_size,
-                                        ..
-                                    }) = cx.bot.get_file(last_photo.file_id.clone()).send().await
-                                    {
-                                        let file_r = File::create(format!(
-                                            "./images/{}.png",
-                                            file_unique_id
-                                        ))
-                                        .await;
-                                        if let Ok(mut file) = file_r {
-                                            match cx.bot.download_file(&file_path, &mut file).await
-                                            {
-                                                Ok(_) => log::info!(
-                                                    "Downloading File: {} | Size: {} ...",
-                                                    file_path,
-                                                    file_size
-                                                ),
-                                                Err(err) => log::warn!("{}", err),
-                                            }
-                                            let mut meal = Meal::new(meal_name);
-                                            meal.rate(rating.clone())
-                                                .tag(tags.clone())
-                                                .url(url.clone())
-                                                .photo(last_photo.clone())
-                                                .save(&state);
-                                            RequestResult::default()
-                                                .add(
-                                                    meal.request(
-                                                        &cx,
-                                                        None,
-                                                        Some(
-                                                            Keyboard::new()
-                                                                .buttons(vec![
-                                                                    vec![Button::new(
-                                                                        "Rate with Poll".into(),
-                                                                        ButtonKind::PollRating {
-                                                                            meal: meal.clone(),
-                                                                        },
-                                                                    )],
-                                                                    button::save_meal_button_row(
-                                                                        &meal.id,
-                                                                    ),
-                                                                ])
-                                                                .save(&state),
-                                                        ),
-                                                    ),
-                                                )
-                                                .send(&state)
-                                                .await;
-                                        }
-                                    }
-                                }
-                                Command::Photo(meal_name) => {
-                                    if let Ok(TgFile {
-                                        file_path,
-                                        file_unique_id,
-                                        file_size,
-                                        ..
-                                    }) = cx.bot.get_file(last_photo.file_id.clone()).send().await
-                                    {
-                                        let file_r = File::create(format!(
-                                            "./images/{}.png",
-                                            file_unique_id
-                                        ))
-                                        .await;
-                                        if let Ok(mut file) = file_r {
-                                            match cx.bot.download_file(&file_path, &mut file).await
-                                            {
-                                                Ok(_) => log::info!(
-                                                    "Downloading File: {} | Size: {} ...",
-                                                    file_path,
-                                                    file_size
-                                                ),
-                                                Err(err) => log::warn!("{}", err),
-                                            }
-                                            let meals = state
-                                                .read()
-                                                .get_saved_meals_by_name(meal_name.clone());
-                                            if meals.len() == 0 {
-                                                RequestResult::default()
-                                                    .message(cx.answer(format!(
-                                                        "No meal with name {}",
-                                                        meal_name
-                                                    )))
-                                                    .send(&state)
-                                                    .await;
-                                            }
-                                            for meal in meals {
-                                                let res = state.write().remove_saved_meal(&meal);
-                                                match res {
-                                                    Ok(rem) => {
-                                                        if rem {
-                                                            let mut new_meal = meal.clone();
-                                                            new_meal.photo(last_photo.clone());
-                                                            state.write().save_meal(&new_meal);
-                                                            RequestResult::default()
-                                                                .add(
-                                                                    new_meal.request(
-                                                                        &cx,
-                                                                        Some(
-                                                                            "Saved new photo!"
-                                                                                .to_string(),
-                                                                        ),
-                                                                        None,
-                                                                    ),
-                                                                )
-                                                                .send(&state)
-                                                                .await;
-                                                            log::info!(
-                                                                "Added photo to meal {}",
-                                                                meal_name,
-                                                            );
-                                                        }
-                                                    }
-                                                    Err(err) => log::warn!("{}", err),
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            },
-                            Err(err) => {
-                                if let Err(err) = cx.answer(err.to_string()).send().await {
-                                    log::warn!("{}", err);
-                                }
-                            }
+                let result = Command::parse(text, bot_name)
+                    .map_err(Error::from)
+                    .and_then(|command| command.execute(&state, &cx));
+                send_result(result, &cx, &state, text).await;
+            } else if cx.update.photo().is_some() {
+                let result = photo::message_request(&cx, &state).await;
+                send_result(result, &cx, &state, "Photo").await;
+            } else if let Some(document) = cx.update.document() {
+                if let Some(caption) = cx.update.caption() {
+                    let result = match Command::parse(caption, bot_name) {
+                        Ok(Command::Import) => {
+                            import::document_request(&cx, &state, document).await
                         }
-                    }
+                        Ok(_) => return,
+                        Err(err) => Err(Error::from(err)),
+                    };
+                    send_result(result, &cx, &state, caption).await;
                 }
             } else {
                 log::warn!("Unhandled update!");
@@ -218,7 +78,15 @@ async fn handle_callback(state: StateLock, rx: DispatcherHandlerRx<CallbackQuery
                         [keyboard_id, button_id] => match keyboards.get(keyboard_id) {
                             Some(keyboard) => {
                                 if let Some(button) = keyboard.get_btn(button_id.to_string()) {
-                                    button.kind.execute(&state, &cx).send(&state).await;
+                                    let failed = match button.kind.execute(&state, &cx) {
+                                        Ok(request) => {
+                                            request.send(&state).await.errors.into_iter().next()
+                                        }
+                                        Err(err) => Some(err),
+                                    };
+                                    if let Some(err) = failed {
+                                        err.callback_request(&cx, &button.text).send(&state).await;
+                                    }
                                 }
                                 // state.write().keyboards_mut().remove(keyboard_id);
                             }
@@ -253,7 +121,7 @@ async fn handle_callback(state: StateLock, rx: DispatcherHandlerRx<CallbackQuery
 }
 
 fn meal_inline(meal: Meal) -> InlineQueryResult {
-    if let Some(photo) = meal.photos.get(0) {
+    if let Some(photo) = meal.cover_photo() {
         InlineQueryResult::CachedPhoto(
             InlineQueryResultCachedPhoto::new(meal.id.to_string(), photo.file_id.clone())
                 .caption(format!("{}", meal))
@@ -276,7 +144,8 @@ async fn handle_inline(state: StateLock, rx: DispatcherHandlerRx<InlineQuery>) {
         .for_each_concurrent(None, |(cx, state)| async move {
             let query = cx.update.query;
             let mut results: Vec<InlineQueryResult> = vec![];
-            let meals_db: Vec<Meal> = state.read().get_saved_meals();
+            // inline queries have no chat, use the profile of the private chat with the user
+            let meals_db: Vec<Meal> = state.read().get_saved_meals_for(cx.update.from.id as i64);
             meals_db.iter().for_each(|meal| {
                 let matcher = SkimMatcherV2::default();
                 if matcher.fuzzy_match(&meal.name, &query).is_some() || query.len() == 0 {
@@ -310,7 +179,7 @@ async fn handle_polls(state: StateLock, rx: DispatcherHandlerRx<Poll>) {
             };
             match poll_opt {
                 Some(poll) => {
-                    poll.handle_votes(&state, &cx).send(&state).await;
+                    poll.handle_update(&state, &cx.bot, &cx.update).await;
                 }
                 None => {
                     log::warn!("No poll with id: {}", cx.update.id);
@@ -325,21 +194,34 @@ async fn main() {
     run().await;
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Config {
-    password: String,
-    token: String,
-    name: String,
-    backup: bool,
-}
-
 async fn run() {
     teloxide::enable_logging!();
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, cmd, password] = args.as_slice() {
+        if cmd == "hash" {
+            match auth::hash_password(password) {
+                Ok(hash) => println!("{}", hash),
+                Err(err) => log::error!("{}", err),
+            }
+            return;
+        }
+    }
     log::info!("Reading Config...");
     let config_str = fs::read_to_string("./config.json").expect("No config file found!");
     let config: Config = serde_json::from_str(&config_str).expect("Wrong config file!");
+    let config = match config.upgrade() {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            return;
+        }
+    };
     let state = Arc::new(RwLock::new(State::new(config.clone())));
+    if let Some(address) = config.calendar_address.clone() {
+        ics::serve(state.clone(), address);
+    }
     let bot = BotBuilder::new().token(config.token).build();
+    schedule::spawn(bot.clone(), state.clone(), schedule::SystemClock);
     let state_2 = state.clone();
     let state_3 = state.clone();
     let state_4 = state.clone();
@@ -1,12 +1,23 @@
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use teloxide::types::{InputFile, PhotoSize, ReplyMarkup};
+use teloxide::prelude::GetChatId;
+use teloxide::types::{ChatId, InputFile, PhotoSize, ReplyMarkup};
+use teloxide::Bot;
 
+use crate::diet::{self, Label};
+use crate::images::StoredImage;
+use crate::ingredient::Ingredient;
 use crate::keyboard::Keyboard;
-use crate::request::RequestKind;
+use crate::nutrition::Nutrients;
+use crate::rating::{RatingStrategy, Score, Vote};
+use crate::request::{PhotoFallback, RequestKind};
+use crate::tags;
 use crate::{ContextMessage, StateLock};
 
+/// Telegram albums hold up to 10 photos.
+pub const MAX_PHOTOS: usize = 10;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Meal {
     pub name: String,
@@ -15,6 +26,32 @@ pub struct Meal {
     pub url: Option<String>,
     pub tags: Vec<String>,
     pub photos: Vec<PhotoSize>,
+    #[serde(default)]
+    pub steps: Vec<String>,
+    #[serde(default)]
+    pub prep_time: Option<u32>,
+    #[serde(default)]
+    pub cook_time: Option<u32>,
+    #[serde(default)]
+    pub servings: Option<u8>,
+    #[serde(default)]
+    pub ingredients: Vec<Ingredient>,
+    /// Votes of all rating polls of this meal.
+    #[serde(default)]
+    pub votes: Vec<Vote>,
+    #[serde(default)]
+    pub score: Option<Score>,
+    /// Nutrients per serving, updated when the meal is saved.
+    #[serde(default)]
+    pub nutrition: Option<Nutrients>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    /// Stored copies of the photos, linked by their file_unique_id.
+    #[serde(default)]
+    pub images: Vec<StoredImage>,
+    /// file_unique_id of the photo shown on the meal card, the latest photo if unset.
+    #[serde(default)]
+    pub cover: Option<String>,
 }
 
 impl Meal {
@@ -26,29 +63,364 @@ impl Meal {
             url: None,
             tags: vec![],
             photos: vec![],
+            steps: vec![],
+            prep_time: None,
+            cook_time: None,
+            servings: None,
+            ingredients: vec![],
+            votes: vec![],
+            score: None,
+            nutrition: None,
+            labels: vec![],
+            images: vec![],
+            cover: None,
         }
     }
 
+    /// Sets the rating by hand, the votes of earlier polls are dropped
+    /// and the new rating counts as one vote of the next poll.
     pub fn rate(&mut self, rating: Option<u8>) -> &mut Self {
         self.rating = rating;
+        self.score = None;
+        self.votes = vec![];
+        self
+    }
+
+    /// Copy for plans, polls and buttons, the vote history is only kept by the saved meal.
+    pub fn embedded(&self) -> Self {
+        Self {
+            votes: vec![],
+            ..self.clone()
+        }
+    }
+
+    /// Merges the votes of a closed poll into the rating history.
+    /// A rating given before the first poll counts as one vote.
+    pub fn add_votes(&mut self, votes: Vec<Vote>, strategy: RatingStrategy, now: i64) -> &mut Self {
+        if self.votes.len() == 0 {
+            if let Some(rating) = self.rating {
+                self.votes.push(Vote {
+                    rating,
+                    timestamp: now,
+                });
+            }
+        }
+        self.votes.extend(votes.iter().cloned());
+        if let Some(score) = strategy.aggregate(&self.votes, &votes, now) {
+            self.rating = Some((score.value.round() as u8).max(1).min(5));
+            self.score = Some(score);
+        }
         self
     }
 
+    /// Adds tags, all tags are normalized and deduplicated.
     pub fn tag(&mut self, tags: Option<Vec<String>>) -> &mut Self {
         self.tags.append(&mut tags.unwrap_or(vec![]));
+        self.tags = tags::normalize_all(&self.tags);
+        self
+    }
+
+    pub fn untag(&mut self, tags: &Vec<String>) -> &mut Self {
+        let tags = tags::normalize_all(tags);
+        self.tags = tags::normalize_all(&self.tags)
+            .into_iter()
+            .filter(|tag| !tags.contains(tag))
+            .collect();
         self
     }
 
+    /// Removes the tag if the meal has it, adds it otherwise.
+    pub fn toggle_tag(&mut self, tag: &String) -> &mut Self {
+        if self
+            .tags
+            .iter()
+            .any(|t| tags::normalize(t) == tags::normalize(tag))
+        {
+            self.untag(&vec![tag.clone()])
+        } else {
+            self.tag(Some(vec![tag.clone()]))
+        }
+    }
+
+    /// Replaces the tag `old` with `new`, returns whether the meal had it.
+    pub fn rename_tag(&mut self, old: &String, new: &String) -> bool {
+        let old = tags::normalize(old);
+        let mut renamed = false;
+        let tags: Vec<String> = self
+            .tags
+            .iter()
+            .map(|tag| {
+                if tags::normalize(tag) == old {
+                    renamed = true;
+                    new.clone()
+                } else {
+                    tag.clone()
+                }
+            })
+            .collect();
+        self.tags = tags::normalize_all(&tags);
+        renamed
+    }
+
+    /// Whether the meal has all tags of `filters`, and none of those starting with "-".
+    pub fn matches_tags(&self, filters: &Vec<String>) -> bool {
+        let has_tag = |tag: &str| {
+            self.tags
+                .iter()
+                .any(|t| tags::normalize(t) == tags::normalize(tag))
+        };
+        filters.iter().all(|filter| {
+            if filter.starts_with('-') {
+                !has_tag(&filter[1..])
+            } else {
+                has_tag(filter)
+            }
+        })
+    }
+
+    /// Sets the diet labels, `None` infers them from the ingredients.
+    pub fn label(&mut self, labels: Option<Vec<Label>>) -> &mut Self {
+        self.labels = labels.unwrap_or(Label::infer(&self.ingredients));
+        self
+    }
+
+    /// Labels of the meal (inferred from the ingredients if none are set),
+    /// tags that name a label and allergens found in its ingredients.
+    pub fn effective_labels(&self) -> Vec<Label> {
+        let mut labels = if self.labels.len() == 0 && self.ingredients.len() > 0 {
+            Label::infer(&self.ingredients)
+        } else {
+            self.labels.clone()
+        };
+        labels.extend(self.tags.iter().filter_map(|tag| Label::parse(tag)));
+        labels.extend(Label::allergens(&self.ingredients));
+        labels.sort();
+        labels.dedup();
+        labels
+    }
+
+    /// Whether nothing is known about the diet of the meal:
+    /// no labels, no label tags and no ingredients to infer them from.
+    pub fn is_unlabeled(&self) -> bool {
+        self.labels.len() == 0
+            && self.ingredients.len() == 0
+            && !self.tags.iter().any(|tag| Label::parse(tag).is_some())
+    }
+
+    /// Whether the meal fits a diet profile, unlabeled meals only with `include_unlabeled`.
+    pub fn fits(&self, profile: &Vec<Label>, include_unlabeled: bool) -> bool {
+        if profile.len() == 0 {
+            true
+        } else if self.is_unlabeled() {
+            include_unlabeled
+        } else {
+            diet::fits(&self.effective_labels(), profile)
+        }
+    }
+
     pub fn url(&mut self, url: Option<String>) -> &mut Self {
         self.url = url;
         self
     }
 
+    /// Adds a photo, the oldest one is dropped beyond `MAX_PHOTOS`.
     pub fn photo(&mut self, photo: PhotoSize) -> &mut Self {
-        self.photos.push(photo);
+        if !self
+            .photos
+            .iter()
+            .any(|old| old.file_unique_id == photo.file_unique_id)
+        {
+            self.photos.push(photo);
+        }
+        while self.photos.len() > MAX_PHOTOS {
+            let oldest = self.photos[0].file_unique_id.clone();
+            self.remove_photo(&oldest);
+        }
+        self
+    }
+
+    /// Removes a photo and its link to the stored image, the file is kept on disk.
+    pub fn remove_photo(&mut self, file_unique_id: &String) -> bool {
+        let before = self.photos.len();
+        self.photos
+            .retain(|photo| &photo.file_unique_id != file_unique_id);
+        self.images
+            .retain(|image| &image.file_unique_id != file_unique_id);
+        if self.cover.as_ref() == Some(file_unique_id) {
+            self.cover = None;
+        }
+        self.photos.len() != before
+    }
+
+    pub fn set_cover(&mut self, file_unique_id: &String) -> &mut Self {
+        if self
+            .photos
+            .iter()
+            .any(|photo| &photo.file_unique_id == file_unique_id)
+        {
+            self.cover = Some(file_unique_id.clone());
+        }
         self
     }
 
+    /// Index of the photo shown on the meal card.
+    pub fn cover_index(&self) -> Option<usize> {
+        self.cover
+            .as_ref()
+            .and_then(|cover| {
+                self.photos
+                    .iter()
+                    .position(|photo| &photo.file_unique_id == cover)
+            })
+            .or(self.photos.len().checked_sub(1))
+    }
+
+    pub fn cover_photo(&self) -> Option<&PhotoSize> {
+        self.cover_index().and_then(|index| self.photos.get(index))
+    }
+
+    /// "📷 2/3" below the caption of a gallery photo.
+    pub fn photo_position(&self, index: usize) -> String {
+        format!("📷 {}/{}", index + 1, self.photos.len())
+    }
+
+    pub fn image(&mut self, image: Option<StoredImage>) -> &mut Self {
+        if let Some(image) = image {
+            if !self.images.contains(&image) {
+                self.images.push(image);
+            }
+        }
+        self
+    }
+
+    /// Stored copy of a photo of this meal.
+    pub fn image_of(&self, photo: &PhotoSize) -> Option<&StoredImage> {
+        self.images
+            .iter()
+            .find(|image| image.file_unique_id == photo.file_unique_id)
+    }
+
+    /// Replaces a photo whose file id stopped working with the re-uploaded one.
+    pub fn replace_photo(&mut self, file_unique_id: &String, photo: PhotoSize) -> &mut Self {
+        for image in self
+            .images
+            .iter_mut()
+            .filter(|image| &image.file_unique_id == file_unique_id)
+        {
+            image.file_unique_id = photo.file_unique_id.clone();
+        }
+        for old in self
+            .photos
+            .iter_mut()
+            .filter(|old| &old.file_unique_id == file_unique_id)
+        {
+            *old = photo.clone();
+        }
+        self
+    }
+
+    pub fn steps(&mut self, steps: Vec<String>) -> &mut Self {
+        self.steps = steps
+            .into_iter()
+            .map(|step| step.trim().to_string())
+            .filter(|step| step.len() > 0)
+            .collect();
+        self
+    }
+
+    pub fn recipe_info(
+        &mut self,
+        prep_time: Option<u32>,
+        cook_time: Option<u32>,
+        servings: Option<u8>,
+    ) -> &mut Self {
+        self.prep_time = prep_time;
+        self.cook_time = cook_time;
+        self.servings = servings;
+        self
+    }
+
+    pub fn ingredients(&mut self, ingredients: Vec<Ingredient>) -> &mut Self {
+        self.ingredients = ingredients;
+        self
+    }
+
+    /// Ingredients scaled from the meal's base servings to `servings`.
+    pub fn scaled_ingredients(&self, servings: Option<u8>) -> Vec<Ingredient> {
+        match (self.servings, servings) {
+            (Some(base), Some(target)) if base > 0 && base != target => {
+                let factor = target as f64 / base as f64;
+                self.ingredients
+                    .iter()
+                    .map(|ingredient| ingredient.scaled(factor))
+                    .collect()
+            }
+            _ => self.ingredients.clone(),
+        }
+    }
+
+    pub fn recipe_text(&self, servings: Option<u8>) -> String {
+        let mut info = vec![];
+        if let Some(prep) = self.prep_time {
+            info.push(format!("Prep: {} min", prep));
+        }
+        if let Some(cook) = self.cook_time {
+            info.push(format!("Cook: {} min", cook));
+        }
+        if let Some(servings) = servings.or(self.servings) {
+            info.push(format!("Servings: {}", servings));
+        }
+        let info = if info.len() > 0 {
+            format!("\n\n{}", info.join(" | "))
+        } else {
+            "".into()
+        };
+        let ingredients = if self.ingredients.len() > 0 {
+            format!(
+                "\n\n{}",
+                self.scaled_ingredients(servings)
+                    .iter()
+                    .map(|ingredient| format!("- {}", ingredient))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        } else {
+            "".into()
+        };
+        let steps = if self.steps.len() > 0 {
+            format!(
+                "\n\n{}",
+                self.steps
+                    .iter()
+                    .enumerate()
+                    .map(|(i, step)| format!("{}. {}", i + 1, step))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        } else {
+            "\n\nNo steps yet, add with /steps <name>, <step> | <step> ...".into()
+        };
+        format!("{}{}{}{}", self, info, ingredients, steps)
+    }
+
+    pub fn step_text(&self, step: usize) -> String {
+        match self.steps.get(step) {
+            Some(text) => format!(
+                "{}\n\nStep {}/{}:\n{}",
+                self.name.to_uppercase(),
+                step + 1,
+                self.steps.len(),
+                text
+            ),
+            None => format!("{}\n\nDone! Enjoy your meal.", self.name.to_uppercase()),
+        }
+    }
+
+    /// Timer in minutes mentioned in a step, e.g. "simmer for 20 min" or "bake 1h".
+    pub fn step_timer(&self, step: usize) -> Option<u32> {
+        self.steps.get(step).and_then(|text| parse_minutes(text))
+    }
+
     pub fn save(&self, state: &StateLock) -> &Self {
         state
             .write()
@@ -62,6 +434,17 @@ impl Meal {
         cx: &ContextMessage,
         sub_text: Option<String>,
         keyboard: Option<Keyboard>,
+    ) -> RequestKind {
+        self.send_request(&cx.bot, ChatId::Id(cx.chat_id()), sub_text, keyboard)
+    }
+
+    /// Meal card with its latest photo, if it has one.
+    pub fn send_request(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        sub_text: Option<String>,
+        keyboard: Option<Keyboard>,
     ) -> RequestKind {
         let message_text = format!(
             "{}{}",
@@ -72,20 +455,25 @@ impl Meal {
                 "".to_string()
             }
         );
-        if self.photos.len() > 0 {
-            let mut req = cx
-                .answer_photo(InputFile::FileId(
-                    self.photos.last().unwrap().file_id.clone(),
-                ))
+        if let Some(photo) = self.cover_photo() {
+            let mut req = bot
+                .send_photo(chat_id, InputFile::FileId(photo.file_id.clone()))
                 .caption(message_text);
             if let Some(keyboard_) = keyboard {
                 req = req.reply_markup(ReplyMarkup::InlineKeyboardMarkup(
                     keyboard_.inline_keyboard(),
                 ));
             }
-            RequestKind::Photo(req)
+            let fallback = self
+                .image_of(photo)
+                .filter(|image| image.exists())
+                .map(|image| PhotoFallback {
+                    meal_id: self.id.clone(),
+                    image: image.clone(),
+                });
+            RequestKind::Photo(req, fallback)
         } else {
-            let mut req = cx.answer(message_text);
+            let mut req = bot.send_message(chat_id, message_text);
             if let Some(keyboard_) = keyboard {
                 req = req.reply_markup(ReplyMarkup::InlineKeyboardMarkup(
                     keyboard_.inline_keyboard(),
@@ -99,11 +487,31 @@ impl Meal {
 impl fmt::Display for Meal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let name = self.name.to_uppercase();
-        let rating = if let Some(rating) = self.rating {
+        let rating = if let Some(score) = &self.score {
+            format!("\n{}", score)
+        } else if let Some(rating) = self.rating {
             format!("\n{}", "⭐".repeat(rating as usize))
         } else {
             "".into()
         };
+        let nutrition = if let Some(nutrition) = &self.nutrition {
+            format!("\n{} per serving", nutrition)
+        } else {
+            "".into()
+        };
+        let labels = self.effective_labels();
+        let labels = if labels.len() > 0 {
+            format!(
+                "\n{}",
+                labels
+                    .iter()
+                    .map(|label| label.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" · ")
+            )
+        } else {
+            "".into()
+        };
         let tags = if self.tags.len() > 0 {
             format!(
                 "\n\n{} |",
@@ -119,6 +527,105 @@ impl fmt::Display for Meal {
         } else {
             "".into()
         };
-        write!(f, "{}{}{}{}", name, rating, tags, url)
+        write!(
+            f,
+            "{}{}{}{}{}{}",
+            name, rating, nutrition, labels, tags, url
+        )
+    }
+}
+
+const MINUTE_UNITS: [&str; 6] = ["m", "min", "mins", "minute", "minutes", "minuten"];
+const HOUR_UNITS: [&str; 8] = [
+    "h", "hr", "hrs", "hour", "hours", "std", "stunde", "stunden",
+];
+
+/// Duration of the first time like "10 min" or "2h" in a step, in minutes.
+fn parse_minutes(text: &str) -> Option<u32> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase()
+        })
+        .collect();
+    for (i, word) in words.iter().enumerate() {
+        let digits: String = word.chars().take_while(|c| c.is_ascii_digit()).collect();
+        if let Ok(amount) = digits.parse::<u32>() {
+            let unit = if digits.len() < word.len() {
+                word[digits.len()..].to_string()
+            } else {
+                words.get(i + 1).cloned().unwrap_or_default()
+            };
+            if MINUTE_UNITS.contains(&unit.as_str()) {
+                return Some(amount);
+            } else if HOUR_UNITS.contains(&unit.as_str()) {
+                // an absurd number of hours is no timer
+                if let Some(minutes) = amount.checked_mul(60) {
+                    return Some(minutes);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meal(ingredients: &[&str]) -> Meal {
+        let mut meal = Meal::new(&"Meal".to_string());
+        meal.ingredients(
+            ingredients
+                .iter()
+                .map(|name| Ingredient::new(name, None, None))
+                .collect(),
+        );
+        meal
+    }
+
+    #[test]
+    fn unlabeled_meals_only_fit_when_included() {
+        let unlabeled = meal(&[]);
+        assert!(unlabeled.is_unlabeled());
+        let no_nuts = vec![Label::ContainsNuts];
+        assert!(!unlabeled.fits(&no_nuts, false));
+        assert!(!unlabeled.fits(&vec![Label::Vegan], false));
+        assert!(unlabeled.fits(&no_nuts, true));
+        assert!(unlabeled.fits(&vec![], false));
+    }
+
+    #[test]
+    fn labeled_meals_fit_by_their_labels() {
+        let no_nuts = vec![Label::ContainsNuts];
+        assert!(meal(&["rice", "carrots"]).fits(&no_nuts, false));
+        assert!(!meal(&["rice", "peanuts"]).fits(&no_nuts, true));
+        let mut tagged = meal(&[]);
+        tagged.tag(Some(vec!["vegan".to_string()]));
+        assert!(!tagged.is_unlabeled());
+        assert!(tagged.fits(&vec![Label::Vegetarian], false));
+        let mut labeled = meal(&[]);
+        labeled.label(Some(vec![Label::ContainsNuts]));
+        assert!(!labeled.fits(&no_nuts, true));
+    }
+
+    #[test]
+    fn step_timers_from_explicit_units() {
+        assert_eq!(parse_minutes("Simmer for 10 min."), Some(10));
+        assert_eq!(parse_minutes("Bake (25min) until golden"), Some(25));
+        assert_eq!(parse_minutes("Let it rest 2 hours"), Some(120));
+        assert_eq!(parse_minutes("Ruhen lassen: 1 Stunde"), Some(60));
+        assert_eq!(parse_minutes("Marinate 3h"), Some(180));
+        assert_eq!(parse_minutes("Add 2 handfuls of spinach"), None);
+        assert_eq!(parse_minutes("Crush 2 heads of garlic"), None);
+        assert_eq!(parse_minutes("Add 2 halves and 3 minced onions"), None);
+    }
+
+    #[test]
+    fn step_timers_skip_overflowing_hours() {
+        assert_eq!(parse_minutes("Wait 4294967295 hours"), None);
+        assert_eq!(parse_minutes("Wait 99999999 h, then 5 min"), Some(5));
+        assert_eq!(parse_minutes("Wait 99999999999 min"), None);
     }
 }
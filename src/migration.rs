@@ -0,0 +1,394 @@
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use teloxide::types::PhotoSize;
+
+use crate::auth::Invite;
+use crate::db::DBKeys;
+use crate::images;
+use crate::meal::Meal;
+use crate::plan::Plan;
+use crate::state::TgState;
+use crate::tags;
+
+/// Meal layout up to v0.4.3, stored with bincode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MealV1 {
+    pub name: String,
+    pub rating: Option<u8>,
+    pub id: String,
+    pub url: Option<String>,
+    pub tags: Vec<String>,
+    pub photos: Vec<PhotoSize>,
+}
+
+impl From<MealV1> for Meal {
+    fn from(old: MealV1) -> Self {
+        let mut meal = Meal::new(&old.name);
+        meal.id = old.id;
+        meal.rating = old.rating;
+        meal.url = old.url;
+        meal.tags = old.tags;
+        meal.photos = old.photos;
+        meal
+    }
+}
+
+/// Plan layout up to v0.4.3, stored with bincode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanV1 {
+    pub meals: Vec<MealV1>,
+    pub days: usize,
+    pub id: String,
+}
+
+impl From<PlanV1> for PlanV2 {
+    fn from(old: PlanV1) -> Self {
+        Self {
+            meals: old.meals.into_iter().map(Meal::from).collect(),
+            days: old.days,
+            id: old.id,
+            servings: HashMap::new(),
+        }
+    }
+}
+
+/// Plan layout up to v0.5.0 without dates and meal slots, stored as json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanV2 {
+    pub meals: Vec<Meal>,
+    pub days: usize,
+    pub id: String,
+    #[serde(default)]
+    pub servings: HashMap<usize, u8>,
+}
+
+impl From<PlanV2> for Plan {
+    fn from(old: PlanV2) -> Self {
+        let mut plan = Plan::from_meals(old.meals);
+        plan.id = old.id;
+        for entry in plan.entries.iter_mut() {
+            entry.servings = old.servings.get(&entry.day).cloned();
+        }
+        plan
+    }
+}
+
+/// Returns the database at `path` if it is still stored in the old bincode format.
+fn legacy_bin_db(path: &str) -> Option<PickleDb> {
+    if PickleDb::load(
+        path,
+        PickleDbDumpPolicy::NeverDump,
+        SerializationMethod::Json,
+    )
+    .is_ok()
+    {
+        return None;
+    }
+    PickleDb::load(
+        path,
+        PickleDbDumpPolicy::NeverDump,
+        SerializationMethod::Bin,
+    )
+    .ok()
+}
+
+fn move_legacy_db(path: &str) -> bool {
+    let legacy_path = format!("{}.v1", path);
+    match fs::rename(path, &legacy_path) {
+        Ok(()) => {
+            log::info!("Moved old database {} to {}", path, legacy_path);
+            true
+        }
+        Err(err) => {
+            log::warn!("Migration of {} aborted: {}", path, err);
+            false
+        }
+    }
+}
+
+fn new_json_db(path: &str) -> PickleDb {
+    PickleDb::new(
+        path,
+        PickleDbDumpPolicy::AutoDump,
+        SerializationMethod::Json,
+    )
+}
+
+/// Items of a list in a bincode database, empty if the list does not exist.
+fn legacy_list<T: DeserializeOwned>(old_db: &PickleDb, key: &DBKeys) -> Vec<T> {
+    if old_db.lexists(&key.to_string()) {
+        old_db
+            .liter(&key.to_string())
+            .filter_map(|item| item.get_item::<T>())
+            .collect()
+    } else {
+        vec![]
+    }
+}
+
+fn add_list<T: Serialize>(db: &mut PickleDb, key: &DBKeys, items: &Vec<T>) {
+    if let Err(err) = db.lcreate(&key.to_string()) {
+        log::warn!("{}", err);
+    }
+    db.lextend(&key.to_string(), items);
+    log::info!("Migrated {} {}", items.len(), key);
+}
+
+/// Moves the user lists (whitelist, admins and invites) of a bincode database into a json one.
+/// Their layout did not change, only the storage format.
+fn convert_user_lists(old_db: &PickleDb, db: &mut PickleDb) {
+    let whitelist: Vec<String> = legacy_list(old_db, &DBKeys::Whitelist);
+    let admins: Vec<String> = if old_db.lexists(&DBKeys::Admins.to_string()) {
+        legacy_list(old_db, &DBKeys::Admins)
+    } else {
+        whitelist.clone()
+    };
+    let invites: Vec<Invite> = legacy_list(old_db, &DBKeys::Invites);
+    add_list(db, &DBKeys::Whitelist, &whitelist);
+    add_list(db, &DBKeys::Admins, &admins);
+    add_list(db, &DBKeys::Invites, &invites);
+}
+
+/// Users whitelisted before there were admins all logged in with /op, so they become admins.
+pub fn seed_admins(db: &mut PickleDb) {
+    let whitelist: Vec<String> = db
+        .liter(&DBKeys::Whitelist.to_string())
+        .filter_map(|item| item.get_item::<String>())
+        .collect();
+    db.lextend(&DBKeys::Admins.to_string(), &whitelist);
+    log::info!("Made {} whitelisted users admins", whitelist.len());
+}
+
+/// Converts the bincode meal database to json, so new optional meal fields can be added
+/// without breaking old entries. Meals are upgraded from `MealV1`, the user lists are kept as they are.
+pub fn migrate_meals_db(path: &str) {
+    if let Some(old_db) = legacy_bin_db(path) {
+        log::info!("Migrating {} to json...", path);
+        let meals: Vec<Meal> = legacy_list::<MealV1>(&old_db, &DBKeys::Meals)
+            .into_iter()
+            .map(Meal::from)
+            .collect();
+        let mut db = new_json_db(&format!("{}.json", path));
+        add_list(&mut db, &DBKeys::Meals, &meals);
+        convert_user_lists(&old_db, &mut db);
+        if let Err(err) = db.dump() {
+            log::warn!("Migration of {} aborted: {}", path, err);
+            return;
+        }
+        if move_legacy_db(path) {
+            if let Err(err) = fs::rename(format!("{}.json", path), path) {
+                log::warn!("Migration of {} aborted: {}", path, err);
+            }
+        }
+    }
+}
+
+/// Converts the bincode plan database (one plan per chat id) to json.
+pub fn migrate_plans_db(path: &str) {
+    if let Some(old_db) = legacy_bin_db(path) {
+        log::info!("Migrating {} to json...", path);
+        let plans: Vec<(String, Plan)> = old_db
+            .iter()
+            .filter_map(|kv| {
+                kv.get_value::<PlanV1>()
+                    .map(|plan| (kv.get_key().to_string(), Plan::from(PlanV2::from(plan))))
+            })
+            .collect();
+        if !move_legacy_db(path) {
+            return;
+        }
+        let mut db = new_json_db(path);
+        for (chat_id, plan) in plans.iter() {
+            if let Err(err) = db.set(chat_id, plan) {
+                log::warn!("{}", err);
+            }
+        }
+        log::info!("Migrated {} plans", plans.len());
+    }
+}
+
+/// Converts plans without dates and slots to dated plans starting today.
+pub fn upgrade_plans(db: &mut PickleDb) {
+    let outdated: Vec<(String, PlanV2)> = db
+        .iter()
+        .filter(|kv| kv.get_value::<Plan>().is_none())
+        .filter_map(|kv| {
+            kv.get_value::<PlanV2>()
+                .map(|plan| (kv.get_key().to_string(), plan))
+        })
+        .collect();
+    for (chat_id, plan) in outdated.into_iter() {
+        match db.set(&chat_id, &Plan::from(plan)) {
+            Ok(()) => log::info!("Upgraded plan of chat {}", chat_id),
+            Err(err) => log::warn!("{}", err),
+        }
+    }
+}
+
+/// Replaces plans without dates and slots anywhere in a json value, e.g. inside buttons and polls.
+fn upgrade_plan_values(value: &mut Value) {
+    let upgraded = match &*value {
+        Value::Object(map)
+            if map.contains_key("meals")
+                && map.contains_key("days")
+                && !map.contains_key("entries") =>
+        {
+            serde_json::from_value::<PlanV2>(value.clone())
+                .ok()
+                .and_then(|plan| serde_json::to_value(Plan::from(plan)).ok())
+        }
+        _ => None,
+    };
+    match (upgraded, value) {
+        (Some(plan), value) => *value = plan,
+        (None, Value::Object(map)) => map.values_mut().for_each(upgrade_plan_values),
+        (None, Value::Array(items)) => items.iter_mut().for_each(upgrade_plan_values),
+        _ => {}
+    }
+}
+
+/// Entries of a map field that can be decoded, the keys of the others are added to `dropped`.
+fn decodable_entries<T: DeserializeOwned>(
+    value: &Value,
+    field: &str,
+    dropped: &mut Vec<String>,
+) -> HashMap<String, T> {
+    let mut entries = HashMap::new();
+    if let Some(map) = value.get(field).and_then(|map| map.as_object()) {
+        for (key, entry) in map.iter() {
+            match serde_json::from_value::<T>(entry.clone()) {
+                Ok(entry) => {
+                    entries.insert(key.clone(), entry);
+                }
+                Err(err) => dropped.push(format!("{} {}: {}", field, key, err)),
+            }
+        }
+    }
+    entries
+}
+
+/// Decodes the telegram state entry by entry, upgrading old plans on the way.
+/// Returns the state with the entries that could be decoded and a description of the dropped ones.
+pub fn lenient_state(mut value: Value) -> (TgState, Vec<String>) {
+    upgrade_plan_values(&mut value);
+    let mut dropped = vec![];
+    let state = TgState {
+        keyboards: decodable_entries(&value, "keyboards", &mut dropped),
+        meals: decodable_entries(&value, "meals", &mut dropped),
+        polls: decodable_entries(&value, "polls", &mut dropped),
+        jobs: decodable_entries(&value, "jobs", &mut dropped),
+        sent: value
+            .get("sent")
+            .and_then(|sent| serde_json::from_value(sent.clone()).ok())
+            .unwrap_or_default(),
+        plan_messages: value
+            .get("plan_messages")
+            .and_then(|messages| serde_json::from_value(messages.clone()).ok())
+            .unwrap_or_default(),
+    };
+    (state, dropped)
+}
+
+/// Upgrades a telegram state that no longer decodes, e.g. with plans in an old layout,
+/// instead of replacing it with an empty one. Entries that still fail are dropped and logged.
+pub fn upgrade_state(db: &mut PickleDb) {
+    let key = DBKeys::State.to_string();
+    if !db.exists(&key) || db.get::<TgState>(&key).is_some() {
+        return;
+    }
+    let value = match db.get::<Value>(&key) {
+        Some(value) => value,
+        None => {
+            log::warn!("Telegram state is not valid json, it can not be upgraded!");
+            return;
+        }
+    };
+    let (state, dropped) = lenient_state(value);
+    for entry in dropped.iter() {
+        log::warn!("Dropped telegram state entry {}", entry);
+    }
+    match db.set(&key, &state) {
+        Ok(()) => log::info!("Upgraded telegram state, dropped {} entries", dropped.len()),
+        Err(err) => log::warn!("{}", err),
+    }
+}
+
+/// Normalizes and deduplicates the tags of meals saved before tags were normalized on write.
+pub fn normalize_tags(db: &mut PickleDb) {
+    let key = DBKeys::Meals.to_string();
+    let outdated: Vec<Meal> = db
+        .liter(&key)
+        .filter_map(|item| item.get_item::<Meal>())
+        .filter(|meal| meal.tags != tags::normalize_all(&meal.tags))
+        .collect();
+    for meal in outdated.into_iter() {
+        let mut normalized = meal.clone();
+        normalized.tags = tags::normalize_all(&meal.tags);
+        match db.lrem_value(&key, &meal) {
+            Ok(true) => {
+                db.ladd(&key, &normalized);
+                log::info!("Normalized tags of meal {}", meal.name);
+            }
+            Ok(false) => {}
+            Err(err) => log::warn!("{}", err),
+        }
+    }
+}
+
+/// Moves photos downloaded as `images/<file_unique_id>.png` into the image store
+/// and links them to their meals.
+pub fn store_images(db: &mut PickleDb) {
+    let key = DBKeys::Meals.to_string();
+    let outdated: Vec<Meal> = db
+        .liter(&key)
+        .filter_map(|item| item.get_item::<Meal>())
+        .filter(|meal| {
+            meal.photos
+                .iter()
+                .any(|photo| meal.image_of(photo).is_none() && legacy_image(photo).exists())
+        })
+        .collect();
+    for meal in outdated.into_iter() {
+        let mut stored = meal.clone();
+        let mut legacy_paths = vec![];
+        for photo in meal
+            .photos
+            .iter()
+            .filter(|photo| meal.image_of(photo).is_none())
+        {
+            let path = legacy_image(photo);
+            match fs::read(&path).map_err(|err| err.to_string()) {
+                Ok(bytes) => match images::store(&bytes, &photo.file_unique_id) {
+                    Ok(image) => {
+                        stored.image(Some(image));
+                        legacy_paths.push(path);
+                    }
+                    Err(err) => log::warn!("{}", err),
+                },
+                Err(err) => log::warn!("{}: {}", path.display(), err),
+            }
+        }
+        match db.lrem_value(&key, &meal) {
+            Ok(true) => {
+                db.ladd(&key, &stored);
+                log::info!("Stored images of meal {}", meal.name);
+                for path in legacy_paths {
+                    if let Err(err) = fs::remove_file(&path) {
+                        log::warn!("{}: {}", path.display(), err);
+                    }
+                }
+            }
+            Ok(false) => {}
+            Err(err) => log::warn!("{}", err),
+        }
+    }
+}
+
+fn legacy_image(photo: &PhotoSize) -> PathBuf {
+    Path::new(images::IMAGES_DIR).join(format!("{}.png", photo.file_unique_id))
+}
@@ -0,0 +1,219 @@
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Add;
+
+use crate::ingredient::{format_quantity, Ingredient};
+use crate::meal::Meal;
+
+/// Food composition per 100 g, a subset of the USDA FoodData Central values.
+const FOODS_CSV: &str = include_str!("../assets/foods.csv");
+/// Fuzzy matches with a lower score are treated as unmatched.
+const MIN_SCORE: i64 = 40;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Nutrients {
+    pub kcal: f64,
+    pub protein: f64,
+    pub fat: f64,
+    pub carbs: f64,
+}
+
+impl Nutrients {
+    pub fn scaled(&self, factor: f64) -> Self {
+        Self {
+            kcal: self.kcal * factor,
+            protein: self.protein * factor,
+            fat: self.fat * factor,
+            carbs: self.carbs * factor,
+        }
+    }
+}
+
+impl Add for Nutrients {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            kcal: self.kcal + other.kcal,
+            protein: self.protein + other.protein,
+            fat: self.fat + other.fat,
+            carbs: self.carbs + other.carbs,
+        }
+    }
+}
+
+impl fmt::Display for Nutrients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "🔥 {:.0} kcal · P {:.0} g · F {:.0} g · C {:.0} g",
+            self.kcal, self.protein, self.fat, self.carbs
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Food {
+    pub name: String,
+    /// Per 100 g.
+    pub nutrients: Nutrients,
+    /// Weight of one piece, for ingredients like "2 onions".
+    pub piece_g: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NutritionAction {
+    Unmatched,
+    Meal(String),
+    Map(String, String),
+}
+
+/// Nutrients of a meal and the ingredients that could not be counted.
+#[derive(Debug, Clone, Default)]
+pub struct MealNutrition {
+    pub total: Nutrients,
+    pub matched: Vec<(Ingredient, String, Nutrients)>,
+    pub unmatched: Vec<Ingredient>,
+}
+
+impl MealNutrition {
+    /// Nutrients per serving, or of the whole meal if it has no servings.
+    pub fn per_serving(&self, servings: Option<u8>) -> Nutrients {
+        self.total
+            .scaled(1.0 / servings.filter(|s| *s > 0).unwrap_or(1) as f64)
+    }
+}
+
+pub struct FoodTable {
+    foods: Vec<Food>,
+}
+
+impl FoodTable {
+    pub fn bundled() -> Self {
+        let number = |value: Option<&str>| -> Option<f64> {
+            value.and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        let foods = FOODS_CSV
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                Some(Food {
+                    name: fields.get(0)?.trim().to_lowercase(),
+                    nutrients: Nutrients {
+                        kcal: number(fields.get(1).cloned())?,
+                        protein: number(fields.get(2).cloned())?,
+                        fat: number(fields.get(3).cloned())?,
+                        carbs: number(fields.get(4).cloned())?,
+                    },
+                    piece_g: number(fields.get(5).cloned()),
+                })
+            })
+            .collect();
+        Self { foods }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Food> {
+        let name = name.trim().to_lowercase();
+        self.foods.iter().find(|food| food.name == name)
+    }
+
+    /// Exact match first, then the best fuzzy match in either direction,
+    /// so "red onions" finds "onion".
+    pub fn find(&self, name: &str) -> Option<&Food> {
+        if let Some(food) = self.get(name) {
+            return Some(food);
+        }
+        let name = name.trim().to_lowercase();
+        let matcher = SkimMatcherV2::default();
+        self.foods
+            .iter()
+            .filter_map(|food| {
+                let score = matcher
+                    .fuzzy_match(&food.name, &name)
+                    .max(matcher.fuzzy_match(&name, &food.name))?;
+                Some((score, food))
+            })
+            .filter(|(score, _)| *score >= MIN_SCORE)
+            .max_by_key(|(score, food)| (*score, food.name.len()))
+            .map(|(_, food)| food)
+    }
+
+    /// Weight of an ingredient in gram, liquids are counted like water.
+    fn grams(ingredient: &Ingredient, food: &Food) -> Option<f64> {
+        let quantity = ingredient.quantity?;
+        let factor = match ingredient.unit.as_deref() {
+            Some("mg") => 0.001,
+            Some("g") | Some("ml") => 1.0,
+            Some("cl") => 10.0,
+            Some("dl") => 100.0,
+            Some("kg") | Some("l") => 1000.0,
+            Some("tsp") => 5.0,
+            Some("tbsp") => 15.0,
+            Some("cup") | Some("cups") => 240.0,
+            Some("pinch") => 0.5,
+            Some("can") | Some("cans") => 400.0,
+            Some("pack") | Some("packs") => 250.0,
+            _ => food.piece_g?,
+        };
+        Some(quantity * factor)
+    }
+
+    /// Sums up all ingredients of a meal, `mappings` map ingredient names to food names.
+    pub fn meal(&self, meal: &Meal, mappings: &HashMap<String, String>) -> MealNutrition {
+        let mut nutrition = MealNutrition::default();
+        for ingredient in meal.ingredients.iter() {
+            let food = match mappings.get(&ingredient.name) {
+                Some(name) => self.get(name),
+                None => self.find(&ingredient.name),
+            };
+            match food.and_then(|food| Some((food, Self::grams(ingredient, food)?))) {
+                Some((food, grams)) => {
+                    let nutrients = food.nutrients.scaled(grams / 100.0);
+                    nutrition.total = nutrition.total + nutrients;
+                    nutrition
+                        .matched
+                        .push((ingredient.clone(), food.name.clone(), nutrients));
+                }
+                None => nutrition.unmatched.push(ingredient.clone()),
+            }
+        }
+        nutrition
+    }
+}
+
+/// Breakdown of a meal's nutrition per ingredient.
+pub fn meal_text(meal: &Meal, nutrition: &MealNutrition) -> String {
+    let mut text = format!(
+        "{}\n\n{}{}",
+        meal.name.to_uppercase(),
+        nutrition.per_serving(meal.servings),
+        match meal.servings {
+            Some(servings) => format!(" per serving ({} servings)", servings),
+            None => " per meal".to_string(),
+        }
+    );
+    for (ingredient, food, nutrients) in nutrition.matched.iter() {
+        text.push_str(&format!(
+            "\n- {} ({}): {} kcal",
+            ingredient,
+            food,
+            format_quantity(nutrients.kcal.round())
+        ));
+    }
+    if nutrition.unmatched.len() > 0 {
+        text.push_str(&format!(
+            "\n\nNot counted: {}\n(Map with /nutrition map <ingredient>, <food>)",
+            nutrition
+                .unmatched
+                .iter()
+                .map(|ingredient| ingredient.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    text
+}
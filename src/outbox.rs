@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use teloxide::requests::{Request, ResponseResult};
+use teloxide::types::ChatId;
+use teloxide::{ApiErrorKind, KnownApiErrorKind, RequestError};
+
+use crate::error::Error;
+use crate::StateLock;
+
+/// Telegram allows about one message per second in a private chat,
+/// 20 messages per minute in a group and 30 messages per second in total.
+const PRIVATE_CHAT_INTERVAL: Duration = Duration::from_millis(1_000);
+const GROUP_CHAT_INTERVAL: Duration = Duration::from_millis(3_000);
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(34);
+/// Attempts of a request before it is given up.
+const MAX_ATTEMPTS: u32 = 4;
+/// Wait before the first retry of a network or server error, doubled for every retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Counters of the outgoing requests since the bot started.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub sent: u64,
+    /// Requests that were sent again after a failure.
+    pub retried: u64,
+    /// Times telegram asked to slow down with `retry_after`.
+    pub flood_waits: u64,
+    /// "message is not modified" and "message to delete not found", treated as success.
+    pub ignored: u64,
+    /// Requests that were given up, by request name.
+    pub failed: HashMap<String, u64>,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut failed: Vec<_> = self.failed.iter().collect();
+        failed.sort();
+        write!(
+            f,
+            "Requests sent: {}\nRetried: {}\nFlood waits: {}\nIgnored: {}\nFailed: {}{}",
+            self.sent,
+            self.retried,
+            self.flood_waits,
+            self.ignored,
+            self.failed.values().sum::<u64>(),
+            failed
+                .iter()
+                .map(|(name, count)| format!("\n- {}: {}", name, count))
+                .collect::<String>()
+        )
+    }
+}
+
+/// Limits a request counts towards, all requests count towards the global limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// New messages (text, photos, albums, documents, polls) are spaced out per chat.
+    Send(i64),
+    /// Edits, deletes and other requests in a chat only wait while the chat is blocked.
+    Chat(i64),
+    /// Requests without a chat, e.g. callback answers and inline edits.
+    Global,
+}
+
+impl Limit {
+    /// Limit of a new message, channel usernames share the global limit only.
+    pub fn send(chat_id: &ChatId) -> Self {
+        match chat_key(chat_id) {
+            Some(chat_id) => Limit::Send(chat_id),
+            None => Limit::Global,
+        }
+    }
+
+    /// Limit of any other request in a chat.
+    pub fn chat(chat_id: &ChatId) -> Self {
+        match chat_key(chat_id) {
+            Some(chat_id) => Limit::Chat(chat_id),
+            None => Limit::Global,
+        }
+    }
+
+    fn chat_id(&self) -> Option<i64> {
+        match self {
+            Limit::Send(chat_id) | Limit::Chat(chat_id) => Some(*chat_id),
+            Limit::Global => None,
+        }
+    }
+}
+
+/// Spaces out the outgoing requests per chat and in total, so telegram's flood limits are not hit.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    /// Earliest time the next message may be sent, by chat id.
+    next_slot: HashMap<i64, Instant>,
+    /// Chats whose requests are held back after a `retry_after`.
+    blocked: HashMap<i64, Instant>,
+    next_global_slot: Option<Instant>,
+    pub metrics: Metrics,
+}
+
+impl Outbox {
+    /// Reserves the next free slot for a request and returns how long to wait for it.
+    fn reserve(&mut self, limit: Limit, now: Instant) -> Duration {
+        let mut slot = self.next_global_slot.unwrap_or(now).max(now);
+        if let Some(blocked) = limit
+            .chat_id()
+            .and_then(|chat_id| self.blocked.get(&chat_id))
+        {
+            slot = slot.max(*blocked);
+        }
+        if let Limit::Send(chat_id) = limit {
+            let next = self.next_slot.get(&chat_id).cloned().unwrap_or(now);
+            slot = slot.max(next);
+            let interval = if chat_id < 0 {
+                GROUP_CHAT_INTERVAL
+            } else {
+                PRIVATE_CHAT_INTERVAL
+            };
+            self.next_slot.insert(chat_id, slot + interval);
+        }
+        self.next_global_slot = Some(slot + GLOBAL_INTERVAL);
+        // forget chats that are idle again
+        self.next_slot.retain(|_, next| *next > now);
+        self.blocked.retain(|_, until| *until > now);
+        slot - now
+    }
+
+    /// Holds back all requests to a chat until telegram's `retry_after` passed.
+    fn block(&mut self, limit: Limit, until: Instant) {
+        match limit.chat_id() {
+            Some(chat_id) => {
+                let blocked = self.blocked.entry(chat_id).or_insert(until);
+                *blocked = (*blocked).max(until);
+            }
+            None => {
+                self.next_global_slot = Some(self.next_global_slot.unwrap_or(until).max(until));
+            }
+        }
+    }
+}
+
+/// Id of a chat for the rate limit, channel usernames share the global limit only.
+pub fn chat_key(chat_id: &ChatId) -> Option<i64> {
+    match chat_id {
+        ChatId::Id(id) => Some(*id),
+        _ => None,
+    }
+}
+
+enum Failure {
+    /// Nothing to do, e.g. the message already has this text.
+    Ignore,
+    RetryAfter(Duration),
+    Retry,
+    GiveUp,
+}
+
+fn classify(err: &RequestError) -> Failure {
+    match err {
+        RequestError::ApiError {
+            kind:
+                ApiErrorKind::Known(KnownApiErrorKind::MessageNotModified)
+                | ApiErrorKind::Known(KnownApiErrorKind::MessageToDeleteNotFound),
+            ..
+        } => Failure::Ignore,
+        RequestError::ApiError { status_code, .. } if status_code.as_u16() == 429 => {
+            Failure::RetryAfter(Duration::from_secs(1))
+        }
+        RequestError::ApiError { status_code, .. } if status_code.is_server_error() => {
+            Failure::Retry
+        }
+        RequestError::RetryAfter(seconds) => {
+            Failure::RetryAfter(Duration::from_secs((*seconds).max(1) as u64))
+        }
+        RequestError::NetworkError(_) => Failure::Retry,
+        _ => Failure::GiveUp,
+    }
+}
+
+/// Whether telegram does not know the file id of a photo, e.g. after the bot token changed.
+pub fn is_invalid_file(err: &RequestError) -> bool {
+    match err {
+        RequestError::ApiError {
+            kind:
+                ApiErrorKind::Known(KnownApiErrorKind::WrongFileId)
+                | ApiErrorKind::Known(KnownApiErrorKind::WrongFileIdOrUrl),
+            ..
+        } => true,
+        RequestError::ApiError {
+            kind: ApiErrorKind::Unknown(description),
+            ..
+        } => description.contains("wrong file identifier"),
+        _ => false,
+    }
+}
+
+/// Sends a request within the rate limits, retrying flood waits and transient errors.
+/// `Ok(None)` if telegram had nothing to change.
+pub async fn send<R>(
+    state: &StateLock,
+    limit: Limit,
+    request: &R,
+    name: &str,
+) -> Result<Option<R::Output>, Error>
+where
+    R: Request,
+{
+    try_send(state, limit, request, name)
+        .await
+        .map_err(|err| given_up(err, name))
+}
+
+/// Error of a request that was given up, it is logged with the request name.
+pub fn given_up(err: RequestError, name: &str) -> Error {
+    let err = Error::from(err);
+    err.log(name);
+    err
+}
+
+/// Like `send`, but the error of a request that was given up is returned as telegram sent it.
+pub async fn try_send<R>(
+    state: &StateLock,
+    limit: Limit,
+    request: &R,
+    name: &str,
+) -> Result<Option<R::Output>, RequestError>
+where
+    R: Request,
+{
+    let mut attempt = 1;
+    loop {
+        let wait = state.write().outbox.reserve(limit, Instant::now());
+        if wait > Duration::from_millis(0) {
+            tokio::time::delay_for(wait).await;
+        }
+        let response: ResponseResult<R::Output> = request.send().await;
+        let err = match response {
+            Ok(output) => {
+                state.write().outbox.metrics.sent += 1;
+                log::info!("{}", name);
+                return Ok(Some(output));
+            }
+            Err(err) => err,
+        };
+        let failure = classify(&err);
+        let mut guard = state.write();
+        let outbox = &mut guard.outbox;
+        match failure {
+            Failure::Ignore => {
+                outbox.metrics.ignored += 1;
+                log::info!("{}: {}", name, err);
+                return Ok(None);
+            }
+            Failure::RetryAfter(retry_after) if attempt < MAX_ATTEMPTS => {
+                outbox.metrics.flood_waits += 1;
+                outbox.metrics.retried += 1;
+                outbox.block(limit, Instant::now() + retry_after);
+                log::warn!("{}: retry after {}s", name, retry_after.as_secs());
+            }
+            Failure::Retry if attempt < MAX_ATTEMPTS => {
+                outbox.metrics.retried += 1;
+                outbox.block(
+                    limit,
+                    Instant::now() + RETRY_BACKOFF * 2u32.pow(attempt - 1),
+                );
+                log::warn!("{}: {}, attempt {} of {}", name, err, attempt, MAX_ATTEMPTS);
+            }
+            _ => {
+                *outbox.metrics.failed.entry(name.to_string()).or_insert(0) += 1;
+                return Err(err);
+            }
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP: i64 = -100;
+    const PRIVATE: i64 = 100;
+
+    #[test]
+    fn spaces_out_messages_per_chat() {
+        let now = Instant::now();
+        let mut outbox = Outbox::default();
+        assert_eq!(
+            outbox.reserve(Limit::Send(GROUP), now),
+            Duration::from_millis(0)
+        );
+        assert_eq!(outbox.reserve(Limit::Send(GROUP), now), GROUP_CHAT_INTERVAL);
+        let mut outbox = Outbox::default();
+        assert_eq!(
+            outbox.reserve(Limit::Send(PRIVATE), now),
+            Duration::from_millis(0)
+        );
+        assert_eq!(
+            outbox.reserve(Limit::Send(PRIVATE), now),
+            PRIVATE_CHAT_INTERVAL
+        );
+    }
+
+    #[test]
+    fn other_requests_only_wait_for_the_global_slot() {
+        let mut outbox = Outbox::default();
+        let now = Instant::now();
+        outbox.reserve(Limit::Send(GROUP), now);
+        assert_eq!(outbox.reserve(Limit::Chat(GROUP), now), GLOBAL_INTERVAL);
+        assert_eq!(outbox.reserve(Limit::Global, now), GLOBAL_INTERVAL * 2);
+        // the next message keeps its distance to the first one
+        assert_eq!(outbox.reserve(Limit::Send(GROUP), now), GROUP_CHAT_INTERVAL);
+    }
+
+    #[test]
+    fn blocked_chats_wait_for_retry_after() {
+        let mut outbox = Outbox::default();
+        let now = Instant::now();
+        let retry_after = Duration::from_secs(5);
+        outbox.block(Limit::Chat(GROUP), now + retry_after);
+        assert_eq!(outbox.reserve(Limit::Chat(GROUP), now), retry_after);
+        assert_eq!(
+            outbox.reserve(Limit::Send(GROUP), now),
+            retry_after + GLOBAL_INTERVAL
+        );
+        // other chats are not held back by the block, only by the global slot
+        assert_eq!(
+            outbox.reserve(Limit::Send(PRIVATE), now + retry_after * 2),
+            Duration::from_millis(0)
+        );
+    }
+
+    #[test]
+    fn forgets_idle_chats() {
+        let mut outbox = Outbox::default();
+        let now = Instant::now();
+        outbox.reserve(Limit::Send(GROUP), now);
+        outbox.block(Limit::Chat(PRIVATE), now + Duration::from_secs(1));
+        outbox.reserve(Limit::Global, now + Duration::from_secs(10));
+        assert!(outbox.next_slot.is_empty());
+        assert!(outbox.blocked.is_empty());
+    }
+}
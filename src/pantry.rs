@@ -0,0 +1,468 @@
+use chrono::{Duration, NaiveDate, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+use crate::ingredient::{round_quantity, Ingredient};
+use crate::meal::Meal;
+
+/// Number of meals listed by /cancook.
+const CANCOOK_RESULTS: usize = 10;
+/// Remaining quantities below this are treated as used up.
+const EPSILON: f64 = 0.001;
+/// Items expiring within these days are announced by the daily warning.
+pub const EXPIRY_WARNING_DAYS: i64 = 2;
+/// Number of meals suggested for an expiring item.
+const SUGGESTED_MEALS: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PantryAction {
+    List,
+    Add(Ingredient, Option<NaiveDate>),
+    Remove(Ingredient),
+    Sync(bool),
+    /// Time of the daily expiry warning, `None` turns it off.
+    Warn(Option<NaiveTime>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PantryItem {
+    pub ingredient: Ingredient,
+    pub expires: Option<NaiveDate>,
+}
+
+/// Ingredients a chat has at home.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Pantry {
+    pub items: Vec<PantryItem>,
+}
+
+/// How many ingredients of a meal are in the pantry and what is missing.
+pub struct Coverage {
+    pub meal: Meal,
+    pub available: usize,
+    pub missing: Vec<Ingredient>,
+}
+
+impl Pantry {
+    /// Adds an ingredient, quantities of the same food are added up
+    /// and the earlier expiry date is kept. Returns whether a new item was created.
+    pub fn add(&mut self, ingredient: Ingredient, expires: Option<NaiveDate>) -> bool {
+        let existing = self.items.iter_mut().find(|item| {
+            item.ingredient.same_food(&ingredient)
+                && match (item.ingredient.quantity, ingredient.quantity) {
+                    (None, None) => true,
+                    (Some(_), Some(_)) => ingredient.quantity_in(&item.ingredient).is_some(),
+                    _ => false,
+                }
+        });
+        match existing {
+            Some(item) => {
+                if let Some(quantity) = ingredient.quantity_in(&item.ingredient) {
+                    item.ingredient.quantity =
+                        item.ingredient.quantity.map(|existing| existing + quantity);
+                }
+                item.expires = match (item.expires, expires) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                false
+            }
+            None => {
+                self.items.push(PantryItem {
+                    ingredient,
+                    expires,
+                });
+                true
+            }
+        }
+    }
+
+    /// Undoes `add`: the quantity is taken out again,
+    /// an ingredient without quantity only removes the item without quantity.
+    pub fn undo_add(&mut self, ingredient: &Ingredient) -> bool {
+        if ingredient.quantity.is_some() {
+            return self.take(ingredient);
+        }
+        match self.items.iter().position(|item| {
+            item.ingredient.same_food(ingredient) && item.ingredient.quantity.is_none()
+        }) {
+            Some(position) => {
+                self.items.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the quantity of `ingredient` out of the pantry, items expiring first are used first.
+    /// Without a quantity the food is removed completely. Returns whether anything changed.
+    pub fn take(&mut self, ingredient: &Ingredient) -> bool {
+        if ingredient.quantity.is_none() {
+            let before = self.items.len();
+            self.items
+                .retain(|item| !item.ingredient.same_food(ingredient));
+            return self.items.len() != before;
+        }
+        self.items
+            .sort_by_key(|item| (item.expires.is_none(), item.expires));
+        let mut remaining = ingredient.clone();
+        let mut changed = false;
+        for item in self
+            .items
+            .iter_mut()
+            .filter(|item| item.ingredient.same_food(ingredient))
+        {
+            let remaining_quantity = remaining.quantity.unwrap_or(0.0);
+            if remaining_quantity <= EPSILON {
+                break;
+            }
+            if let (Some(quantity), Some(needed)) = (
+                item.ingredient.quantity,
+                remaining.quantity_in(&item.ingredient),
+            ) {
+                let used = quantity.min(needed);
+                item.ingredient.quantity = Some(quantity - used);
+                remaining.quantity = Some(remaining_quantity * (1.0 - used / needed));
+                changed = true;
+            }
+        }
+        self.items.retain(|item| match item.ingredient.quantity {
+            Some(quantity) => quantity > EPSILON,
+            None => true,
+        });
+        changed
+    }
+
+    /// Takes the ingredients of a cooked meal, ingredients without quantity are kept.
+    pub fn deduct(&mut self, meal: &Meal) -> bool {
+        let mut changed = false;
+        for ingredient in meal.ingredients.iter() {
+            if ingredient.quantity.is_some() {
+                changed = self.take(ingredient) || changed;
+            }
+        }
+        changed
+    }
+
+    /// Missing part of an ingredient, `None` if the pantry has enough.
+    /// Foods stored without quantity count as enough.
+    fn missing(&self, ingredient: &Ingredient) -> Option<Ingredient> {
+        let items: Vec<&PantryItem> = self
+            .items
+            .iter()
+            .filter(|item| item.ingredient.same_food(ingredient))
+            .collect();
+        if items.len() == 0 {
+            return Some(ingredient.clone());
+        }
+        let needed = match ingredient.quantity {
+            Some(needed) => needed,
+            None => return None,
+        };
+        if items.iter().any(|item| item.ingredient.quantity.is_none()) {
+            return None;
+        }
+        let available: f64 = items
+            .iter()
+            .filter_map(|item| item.ingredient.quantity_in(ingredient))
+            .sum();
+        if available + EPSILON >= needed {
+            None
+        } else {
+            Some(Ingredient {
+                name: ingredient.name.clone(),
+                quantity: Some(round_quantity(
+                    needed - available,
+                    ingredient.unit.as_deref(),
+                )),
+                unit: ingredient.unit.clone(),
+            })
+        }
+    }
+
+    pub fn coverage(&self, meal: &Meal) -> Coverage {
+        let missing: Vec<Ingredient> = meal
+            .ingredients
+            .iter()
+            .filter_map(|ingredient| self.missing(ingredient))
+            .collect();
+        Coverage {
+            meal: meal.clone(),
+            available: meal.ingredients.len() - missing.len(),
+            missing,
+        }
+    }
+
+    /// Meals with ingredients, those missing the fewest ingredients first.
+    pub fn cancook(&self, meals: Vec<Meal>) -> Vec<Coverage> {
+        let mut coverages: Vec<Coverage> = meals
+            .iter()
+            .filter(|meal| meal.ingredients.len() > 0)
+            .map(|meal| self.coverage(meal))
+            .collect();
+        coverages.sort_by(|a, b| {
+            a.missing
+                .len()
+                .cmp(&b.missing.len())
+                .then(b.available.cmp(&a.available))
+                .then(a.meal.name.cmp(&b.meal.name))
+        });
+        coverages
+    }
+
+    /// Items expiring between today and `days` later, the soonest first.
+    pub fn expiring(&self, today: NaiveDate, days: i64) -> Vec<&PantryItem> {
+        let mut items: Vec<&PantryItem> = self
+            .items
+            .iter()
+            .filter(|item| match item.expires {
+                Some(expires) => expires >= today && expires <= today + Duration::days(days),
+                None => false,
+            })
+            .collect();
+        items.sort_by_key(|item| item.expires);
+        items
+    }
+
+    pub fn text(&self, today: NaiveDate) -> String {
+        if self.items.len() == 0 {
+            return "Pantry is empty!\n(Add with /pantry add 500 g rice, 2020-10-26)".to_string();
+        }
+        let mut items: Vec<&PantryItem> = self.items.iter().collect();
+        items.sort_by(|a, b| {
+            (a.expires.is_none(), a.expires, &a.ingredient.name).cmp(&(
+                b.expires.is_none(),
+                b.expires,
+                &b.ingredient.name,
+            ))
+        });
+        format!(
+            "Pantry:\n{}",
+            items
+                .iter()
+                .map(|item| match item.expires {
+                    Some(expires) => format!(
+                        "{}- {} (expires {})",
+                        if expires < today { "⚠️ " } else { "" },
+                        item.ingredient,
+                        expires.format("%d.%m.")
+                    ),
+                    None => format!("- {}", item.ingredient),
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+}
+
+pub fn cancook_text(coverages: &Vec<Coverage>) -> String {
+    if coverages.len() == 0 {
+        return "No meals with ingredients yet!\n(Add with /ingredients)".to_string();
+    }
+    format!(
+        "What you can cook:\n{}",
+        coverages
+            .iter()
+            .take(CANCOOK_RESULTS)
+            .map(|coverage| {
+                let mut line = format!(
+                    "{} {} ({}/{})",
+                    if coverage.missing.len() == 0 {
+                        "✅"
+                    } else {
+                        "🛒"
+                    },
+                    coverage.meal.name.to_uppercase(),
+                    coverage.available,
+                    coverage.meal.ingredients.len()
+                );
+                if coverage.missing.len() > 0 {
+                    line.push_str(&format!(
+                        "\n   missing: {}",
+                        coverage
+                            .missing
+                            .iter()
+                            .map(|ingredient| ingredient.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// "Spinach expires in 2 days — you could make: …"
+pub fn expiry_warning(item: &PantryItem, today: NaiveDate, meals: &Vec<Meal>) -> String {
+    let mut name = item.ingredient.name.clone();
+    if let Some(first) = name.get(..1) {
+        name = first.to_uppercase() + &name[1..];
+    }
+    let mut text = match item.expires.map(|expires| (expires - today).num_days()) {
+        Some(0) => format!("{} expires today", name),
+        Some(1) => format!("{} expires tomorrow", name),
+        Some(days) => format!("{} expires in {} days", name, days),
+        None => name,
+    };
+    if meals.len() > 0 {
+        text.push_str(&format!(
+            " — you could make: {}",
+            meals
+                .iter()
+                .take(SUGGESTED_MEALS)
+                .map(|meal| meal.name.to_uppercase())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingredient(input: &str) -> Ingredient {
+        Ingredient::parse(input).unwrap()
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2021, 3, day)
+    }
+
+    fn quantities(pantry: &Pantry) -> Vec<(String, Option<f64>)> {
+        pantry
+            .items
+            .iter()
+            .map(|item| {
+                (
+                    item.ingredient.name.clone(),
+                    item.ingredient.quantity.map(|quantity| quantity.round()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn add_sums_up_the_same_food() {
+        let mut pantry = Pantry::default();
+        assert!(pantry.add(ingredient("500 g rice"), Some(date(10))));
+        assert!(!pantry.add(ingredient("1 kg rice"), Some(date(5))));
+        assert!(!pantry.add(ingredient("1 kg rice"), None));
+        assert!(pantry.add(ingredient("2 onions"), None));
+        assert!(!pantry.add(ingredient("1 onion"), None));
+        // a food without quantity is kept apart from measured ones
+        assert!(pantry.add(ingredient("salt"), None));
+        assert!(pantry.add(ingredient("1 tsp salt"), None));
+        assert!(pantry.add(ingredient("1 l milk"), None));
+        assert!(pantry.add(ingredient("2 cans milk"), None));
+        assert_eq!(
+            quantities(&pantry),
+            vec![
+                ("rice".to_string(), Some(2500.0)),
+                ("onions".to_string(), Some(3.0)),
+                ("salt".to_string(), None),
+                ("salt".to_string(), Some(1.0)),
+                ("milk".to_string(), Some(1.0)),
+                ("milk".to_string(), Some(2.0)),
+            ]
+        );
+        assert_eq!(pantry.items[0].expires, Some(date(5)));
+    }
+
+    #[test]
+    fn undo_add_restores_the_pantry() {
+        let mut pantry = Pantry::default();
+        pantry.add(ingredient("1 kg rice"), None);
+        pantry.add(ingredient("salt"), None);
+        pantry.add(ingredient("1 tsp salt"), None);
+        assert!(!pantry.add(ingredient("500 g rice"), None));
+        assert!(pantry.undo_add(&ingredient("500 g rice")));
+        assert_eq!(quantities(&pantry)[0], ("rice".to_string(), Some(1.0)));
+        // only the item without quantity is removed
+        assert!(pantry.undo_add(&ingredient("salt")));
+        assert_eq!(
+            quantities(&pantry),
+            vec![
+                ("rice".to_string(), Some(1.0)),
+                ("salt".to_string(), Some(1.0)),
+            ]
+        );
+        assert!(!pantry.undo_add(&ingredient("pepper")));
+    }
+
+    #[test]
+    fn take_uses_items_expiring_first() {
+        let mut pantry = Pantry::default();
+        for expires in vec![Some(date(9)), None, Some(date(3))] {
+            pantry.items.push(PantryItem {
+                ingredient: ingredient("500 g rice"),
+                expires,
+            });
+        }
+        assert!(pantry.take(&ingredient("0.7 kg rice")));
+        let left: Vec<(Option<NaiveDate>, Option<f64>)> = pantry
+            .items
+            .iter()
+            .map(|item| (item.expires, item.ingredient.quantity.map(|q| q.round())))
+            .collect();
+        assert_eq!(
+            left,
+            vec![(Some(date(9)), Some(300.0)), (None, Some(500.0))]
+        );
+        // without quantity the food is removed completely
+        assert!(pantry.take(&ingredient("rice")));
+        assert_eq!(pantry.items.len(), 0);
+        assert!(!pantry.take(&ingredient("rice")));
+    }
+
+    #[test]
+    fn deduct_keeps_ingredients_without_quantity() {
+        let mut pantry = Pantry::default();
+        pantry.add(ingredient("1000 g rice"), None);
+        pantry.add(ingredient("salt"), None);
+        let mut meal = Meal::new(&"Risotto".to_string());
+        meal.ingredients(vec![ingredient("0.25 kg rice"), ingredient("salt")]);
+        assert!(pantry.deduct(&meal));
+        assert_eq!(
+            quantities(&pantry),
+            vec![
+                ("rice".to_string(), Some(750.0)),
+                ("salt".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn coverage_lists_missing_quantities() {
+        let mut pantry = Pantry::default();
+        pantry.add(ingredient("300 g rice"), None);
+        pantry.add(ingredient("salt"), None);
+        let mut meal = Meal::new(&"Risotto".to_string());
+        meal.ingredients(vec![
+            ingredient("0.5 kg rice"),
+            ingredient("2 onions"),
+            ingredient("1 tsp salt"),
+        ]);
+        let coverage = pantry.coverage(&meal);
+        assert_eq!(coverage.available, 1);
+        let missing: Vec<String> = coverage.missing.iter().map(|i| i.to_string()).collect();
+        assert_eq!(missing, vec!["0.2 kg rice", "2 onions"]);
+    }
+
+    #[test]
+    fn expiring_within_days() {
+        let mut pantry = Pantry::default();
+        pantry.add(ingredient("milk"), Some(date(12)));
+        pantry.add(ingredient("eggs"), Some(date(10)));
+        pantry.add(ingredient("rice"), None);
+        pantry.add(ingredient("cheese"), Some(date(9)));
+        let names: Vec<&str> = pantry
+            .expiring(date(10), 2)
+            .iter()
+            .map(|item| item.ingredient.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["eggs", "milk"]);
+    }
+}
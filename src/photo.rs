@@ -0,0 +1,345 @@
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineKeyboardButtonKind, InlineKeyboardMarkup, MediaKind, MediaPhoto, Message, MessageCommon,
+    MessageKind, PhotoSize,
+};
+use teloxide::utils::command::BotCommand;
+
+use crate::button;
+use crate::command::{self, Command};
+use crate::error::Error;
+use crate::images::{self, StoredImage};
+use crate::keyboard::Keyboard;
+use crate::meal::{Meal, MAX_PHOTOS};
+use crate::request::RequestResult;
+use crate::{ContextMessage, StateLock};
+
+/// Time to wait for the remaining photos of an album, they arrive as separate messages.
+const ALBUM_DELAY_MILLIS: u64 = 1500;
+
+/// Photos sent together, with the caption and reply of any of their messages.
+#[derive(Debug, Clone, Default)]
+pub struct Album {
+    /// Largest size of each photo with the id of its message.
+    photos: Vec<(i32, PhotoSize)>,
+    caption: Option<String>,
+    reply_to: Option<Message>,
+}
+
+impl Album {
+    fn add(&mut self, message: &Message) {
+        if let Some(photo) = message.photo().and_then(|sizes| sizes.last()) {
+            self.photos.push((message.id, photo.clone()));
+        }
+        if self.caption.is_none() {
+            self.caption = message.caption().map(|caption| caption.to_string());
+        }
+        if self.reply_to.is_none() {
+            self.reply_to = reply_to_message(message).cloned();
+        }
+    }
+
+    /// Photos in the order they were sent.
+    fn photos(&self) -> Vec<PhotoSize> {
+        let mut photos = self.photos.clone();
+        photos.sort_by_key(|(message_id, _)| *message_id);
+        photos.into_iter().map(|(_, photo)| photo).collect()
+    }
+}
+
+enum Target {
+    New {
+        meal_name: String,
+        rating: Option<u8>,
+        tags: Option<Vec<String>>,
+        url: Option<String>,
+    },
+    Named(String),
+    Replied(String),
+}
+
+fn media_group_id(message: &Message) -> Option<&String> {
+    match message {
+        Message {
+            kind:
+                MessageKind::Common(MessageCommon {
+                    media_kind: MediaKind::Photo(MediaPhoto { media_group_id, .. }),
+                    ..
+                }),
+            ..
+        } => media_group_id.as_ref(),
+        _ => None,
+    }
+}
+
+fn reply_to_message(message: &Message) -> Option<&Message> {
+    match message {
+        Message {
+            kind:
+                MessageKind::Common(MessageCommon {
+                    reply_to_message, ..
+                }),
+            ..
+        } => reply_to_message.as_deref(),
+        _ => None,
+    }
+}
+
+fn reply_markup(message: &Message) -> Option<&InlineKeyboardMarkup> {
+    match message {
+        Message {
+            kind: MessageKind::Common(MessageCommon { reply_markup, .. }),
+            ..
+        } => reply_markup.as_ref(),
+        _ => None,
+    }
+}
+
+/// Meal shown on a meal card, found through its buttons or the meal name in its first line.
+fn meal_of_card(state: &StateLock, card: &Message) -> Option<String> {
+    let keyboards = state.read().keyboards().clone();
+    let from_buttons = reply_markup(card).and_then(|markup| {
+        markup
+            .inline_keyboard
+            .iter()
+            .flatten()
+            .filter_map(|btn| match &btn.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => {
+                    let ids: Vec<&str> = data.split(".").collect();
+                    match *ids {
+                        [keyboard_id, button_id] => keyboards
+                            .get(keyboard_id)
+                            .and_then(|keyboard| keyboard.get_btn(button_id.to_string()))
+                            .and_then(|button| button.kind.meal_id()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .next()
+    });
+    from_buttons.or_else(|| {
+        let name = card
+            .caption()
+            .or(card.text())?
+            .lines()
+            .next()?
+            .trim()
+            .to_string();
+        state
+            .read()
+            .get_saved_meals_by_name(name)
+            .first()
+            .map(|meal| meal.id.clone())
+    })
+}
+
+/// Meal the photos are for, `None` if the message is not meant for the bot.
+fn target(state: &StateLock, album: &Album) -> Option<Result<Target, Error>> {
+    match &album.caption {
+        Some(caption) if caption.starts_with("/") => {
+            let bot_name = state.read().config.name.clone();
+            match Command::parse(caption, bot_name) {
+                Ok(Command::New {
+                    meal_name,
+                    rating,
+                    tags,
+                    url,
+                }) => Some(Ok(Target::New {
+                    meal_name,
+                    rating,
+                    tags,
+                    url,
+                })),
+                Ok(Command::Photo(meal_name)) => Some(Ok(Target::Named(meal_name))),
+                Ok(_) => None,
+                Err(err) => Some(Err(Error::from(err))),
+            }
+        }
+        _ => {
+            let card = album.reply_to.as_ref()?;
+            if card.from().map(|user| user.is_bot) != Some(true) {
+                return None;
+            }
+            Some(
+                meal_of_card(state, card)
+                    .map(Target::Replied)
+                    .ok_or(Error::NotFound(
+                        "Meal of this message (use /photo <name> as caption)".to_string(),
+                    )),
+            )
+        }
+    }
+}
+
+async fn download(
+    cx: &ContextMessage,
+    photos: Vec<PhotoSize>,
+) -> Vec<(PhotoSize, Option<StoredImage>)> {
+    let mut downloaded = vec![];
+    for photo in photos {
+        let image = match images::download(&cx.bot, &photo).await {
+            Ok(image) => Some(image),
+            Err(err) => {
+                log::warn!("{}", err);
+                None
+            }
+        };
+        downloaded.push((photo, image));
+    }
+    downloaded
+}
+
+/// Adds photos until the meal has `MAX_PHOTOS`, returns how many were added.
+fn add_photos(meal: &mut Meal, photos: &Vec<(PhotoSize, Option<StoredImage>)>) -> usize {
+    let mut added = 0;
+    for (photo, image) in photos.iter() {
+        if meal.photos.len() >= MAX_PHOTOS {
+            break;
+        }
+        let before = meal.photos.len();
+        meal.photo(photo.clone()).image(image.clone());
+        added += meal.photos.len() - before;
+    }
+    added
+}
+
+fn saved_photos_text(added: usize, count: usize) -> String {
+    match (added, count - added) {
+        (0, _) => format!(
+            "No new photo added, a meal has at most {} photos (delete some with /get)!",
+            MAX_PHOTOS
+        ),
+        (1, 0) => "Saved new photo!".to_string(),
+        (added, 0) => format!("Saved {} new photos!", added),
+        (added, skipped) => format!(
+            "Saved {} new photos, {} skipped (at most {} per meal)!",
+            added, skipped, MAX_PHOTOS
+        ),
+    }
+}
+
+async fn attach_request(
+    cx: &ContextMessage,
+    state: &StateLock,
+    album: Album,
+) -> Result<RequestResult, Error> {
+    let mut request = RequestResult::default();
+    let target = match target(state, &album) {
+        Some(target) => target?,
+        None => return Ok(request),
+    };
+    command::whitelisted_user(state, cx.update.from())?;
+    let photos = album.photos();
+    match target {
+        Target::New {
+            meal_name,
+            rating,
+            tags,
+            url,
+        } => {
+            let photos = download(cx, photos).await;
+            let mut meal = Meal::new(&meal_name);
+            meal.rate(rating).tag(tags).url(url);
+            add_photos(&mut meal, &photos);
+            meal.save(state);
+            let counts = state.read().tag_counts();
+            request.add(
+                meal.request(
+                    cx,
+                    None,
+                    Some(
+                        Keyboard::new()
+                            .buttons(button::new_meal_keyboard(&meal, &counts))
+                            .save(state),
+                    ),
+                ),
+            );
+        }
+        Target::Named(meal_name) => {
+            let meals = state.read().get_saved_meals_by_name(meal_name.clone());
+            if meals.len() == 0 {
+                return Err(Error::NotFound(format!("Meal {}", meal_name)));
+            }
+            let photos = download(cx, photos).await;
+            for meal in meals {
+                let mut added = 0;
+                let edited = state.write().edit_saved_meal(&meal.id, |meal| {
+                    added = add_photos(meal, &photos);
+                })?;
+                if let Some(meal) = edited {
+                    log::info!("Added {} photos to meal {}", added, meal.name);
+                    request.add(meal.request(
+                        cx,
+                        Some(saved_photos_text(added, photos.len())),
+                        None,
+                    ));
+                }
+            }
+        }
+        Target::Replied(meal_id) => {
+            let photos = download(cx, photos).await;
+            // meal that is being created
+            let temporary = state.write().meals_mut().get_mut(&meal_id).map(|meal| {
+                let added = add_photos(meal, &photos);
+                (meal.clone(), added)
+            });
+            let edited = match temporary {
+                Some(edited) => Some(edited),
+                None => {
+                    let mut added = 0;
+                    state
+                        .write()
+                        .edit_saved_meal(&meal_id, |meal| {
+                            added = add_photos(meal, &photos);
+                        })?
+                        .map(|meal| (meal, added))
+                }
+            };
+            match edited {
+                Some((meal, added)) => {
+                    log::info!("Added {} photos to meal {}", added, meal.name);
+                    request.add(meal.request(
+                        cx,
+                        Some(saved_photos_text(added, photos.len())),
+                        None,
+                    ));
+                }
+                None => return Err(Error::NotFound("Meal".to_string())),
+            }
+        }
+    }
+    Ok(request)
+}
+
+/// Attaches the photos of a message to a meal, photos of an album are collected first.
+pub async fn message_request(
+    cx: &ContextMessage,
+    state: &StateLock,
+) -> Result<RequestResult, Error> {
+    match media_group_id(&cx.update).cloned() {
+        Some(group_id) => {
+            let first = {
+                let mut state = state.write();
+                let album = state.albums.entry(group_id.clone()).or_default();
+                album.add(&cx.update);
+                album.photos.len() == 1
+            };
+            if !first {
+                return Ok(RequestResult::default());
+            }
+            tokio::time::delay_for(Duration::from_millis(ALBUM_DELAY_MILLIS)).await;
+            let album = state.write().albums.remove(&group_id);
+            match album {
+                Some(album) => attach_request(cx, state, album).await,
+                None => Ok(RequestResult::default()),
+            }
+        }
+        None => {
+            let mut album = Album::default();
+            album.add(&cx.update);
+            attach_request(cx, state, album).await
+        }
+    }
+}
@@ -1,67 +1,369 @@
+use chrono::{Duration, Local, NaiveDate};
 use nanoid::nanoid;
 use random_choice::random_choice;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 use crate::button::{Button, ButtonKind};
 use crate::meal::Meal;
+use crate::nutrition::Nutrients;
+
+/// Weight factor for meals that were planned in one of the recent archived plans.
+const RECENT_PENALTY: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Slot {
+    Breakfast,
+    Lunch,
+    Dinner,
+}
+
+impl Slot {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "breakfast" => Some(Slot::Breakfast),
+            "lunch" => Some(Slot::Lunch),
+            "dinner" => Some(Slot::Dinner),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Slot::Breakfast => write!(f, "Breakfast"),
+            Slot::Lunch => write!(f, "Lunch"),
+            Slot::Dinner => write!(f, "Dinner"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PlanAction {
+    Show,
+    Gen {
+        days: usize,
+        start: Option<NaiveDate>,
+    },
+    Swap(usize, usize),
+    Move(usize, usize),
+    Export,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub day: usize,
+    pub slot: Slot,
+    pub meal: Meal,
+    /// Servings for this entry, overriding the meal's own servings.
+    #[serde(default)]
+    pub servings: Option<u8>,
+    /// Locked entries are kept when the plan is rerolled.
+    #[serde(default)]
+    pub locked: bool,
+}
+
+impl PlanEntry {
+    pub fn new(day: usize, slot: Slot, meal: Meal) -> Self {
+        Self {
+            day,
+            slot,
+            meal: meal.embedded(),
+            servings: None,
+            locked: false,
+        }
+    }
+
+    pub fn servings(&self) -> Option<u8> {
+        self.servings.or(self.meal.servings)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Plan {
-    pub meals: Vec<Meal>,
-    pub days: usize,
     pub id: String,
+    pub start: NaiveDate,
+    pub days: usize,
+    pub slots: Vec<Slot>,
+    pub entries: Vec<PlanEntry>,
 }
 
 impl Plan {
-    pub fn new(meals: Vec<Meal>) -> Self {
+    pub fn new(start: NaiveDate, days: usize, slots: Vec<Slot>) -> Self {
         Self {
-            meals: meals.clone(),
-            days: meals.len(),
             id: nanoid!(),
+            start,
+            days,
+            slots,
+            entries: vec![],
         }
     }
 
-    pub fn gen(meals: Vec<Meal>, amount: usize) -> Self {
+    /// Plan with one dinner per day, starting today.
+    pub fn from_meals(meals: Vec<Meal>) -> Self {
+        let mut plan = Self::new(
+            Local::today().naive_local(),
+            meals.len(),
+            vec![Slot::Dinner],
+        );
+        plan.entries = meals
+            .into_iter()
+            .enumerate()
+            .map(|(day, meal)| PlanEntry::new(day, Slot::Dinner, meal))
+            .collect();
+        plan
+    }
+
+    /// Picks `amount` meals weighted by their rating,
+    /// meals with an id in `recent` are picked less often.
+    fn choose(meals: &Vec<Meal>, amount: usize, recent: &Vec<String>) -> Vec<Meal> {
+        if meals.len() == 0 {
+            return vec![];
+        }
         let weights: Vec<f64> = meals
             .iter()
-            .map(|meal| meal.rating.unwrap_or(1) as f64)
+            .map(|meal| {
+                let weight = meal.rating.unwrap_or(1) as f64;
+                if recent.contains(&meal.id) {
+                    weight * RECENT_PENALTY
+                } else {
+                    weight
+                }
+            })
             .collect();
-        let meal_plan: Vec<_> = random_choice()
-            .random_choice_f64(&meals, &weights, amount)
+        random_choice()
+            .random_choice_f64(meals, &weights, amount)
             .into_iter()
             .map(|m| m.clone())
+            .collect()
+    }
+
+    /// Picks up to `amount` different meals, weighted like `choose`.
+    pub fn choose_distinct(meals: Vec<Meal>, amount: usize, recent: &Vec<String>) -> Vec<Meal> {
+        let mut pool = meals;
+        let mut chosen = vec![];
+        while chosen.len() < amount {
+            match Self::choose(&pool, 1, recent).pop() {
+                Some(meal) => {
+                    pool.retain(|m| m.id != meal.id);
+                    chosen.push(meal);
+                }
+                None => break,
+            }
+        }
+        chosen
+    }
+
+    pub fn gen(
+        meals: Vec<Meal>,
+        days: usize,
+        start: NaiveDate,
+        slots: Vec<Slot>,
+        recent: &Vec<String>,
+    ) -> Self {
+        let meal_plan = Self::choose(&meals, days * slots.len(), recent);
+        let mut plan = Self::new(start, days, slots.clone());
+        plan.entries = meal_plan
+            .into_iter()
+            .enumerate()
+            .map(|(i, meal)| PlanEntry::new(i / slots.len(), slots[i % slots.len()], meal))
             .collect();
-        let days = meal_plan.len();
+        plan
+    }
+
+    /// Replaces all unlocked entries with new meals.
+    pub fn reroll(&mut self, meals: Vec<Meal>, recent: &Vec<String>) {
+        let unlocked: Vec<usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.locked)
+            .map(|(index, _)| index)
+            .collect();
+        let new_meals = Self::choose(&meals, unlocked.len(), recent);
+        for (index, meal) in unlocked.into_iter().zip(new_meals.into_iter()) {
+            self.entries[index].meal = meal.embedded();
+        }
+    }
+
+    /// Replaces a single entry with a different meal, if there is one.
+    pub fn reroll_entry(&mut self, index: usize, meals: Vec<Meal>, recent: &Vec<String>) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            let candidates: Vec<Meal> = meals
+                .into_iter()
+                .filter(|meal| meal.id != entry.meal.id)
+                .collect();
+            if let Some(meal) = Self::choose(&candidates, 1, recent).pop() {
+                entry.meal = meal.embedded();
+            }
+        }
+    }
+
+    /// Copy of this plan with a new id, starting at `start`.
+    pub fn reuse(&self, start: NaiveDate) -> Self {
         Self {
-            meals: meal_plan,
-            days: days,
             id: nanoid!(),
+            start,
+            ..self.clone()
         }
     }
 
-    pub fn buttons(&self) -> Vec<Vec<Button>> {
-        self.meals
+    pub fn set_entry_meal(&mut self, index: usize, meal: Meal) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.meal = meal.embedded();
+        }
+    }
+
+    pub fn toggle_lock(&mut self, index: usize) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.locked = !entry.locked;
+        }
+    }
+
+    pub fn date(&self, day: usize) -> NaiveDate {
+        self.start + Duration::days(day as i64)
+    }
+
+    pub fn end(&self) -> NaiveDate {
+        self.date(self.days.max(1) - 1)
+    }
+
+    pub fn day_entries(&self, day: usize) -> Vec<&PlanEntry> {
+        let mut entries: Vec<&PlanEntry> = self
+            .entries
             .iter()
-            .map(|meal| {
-                vec![Button::new(
-                    meal.name.clone(),
-                    ButtonKind::DisplayPlanMeal {
-                        meal: meal.clone(),
-                        plan: self.clone(),
-                    },
-                )]
+            .filter(|entry| entry.day == day)
+            .collect();
+        entries.sort_by_key(|entry| entry.slot);
+        entries
+    }
+
+    /// Label of an entry like "Mon 14: LASAGNE" (slot is added if the plan has several).
+    pub fn entry_label(&self, entry: &PlanEntry) -> String {
+        format!(
+            "{}{}: {}",
+            self.date(entry.day).format("%a %d"),
+            if self.slots.len() > 1 {
+                format!(" {}", entry.slot)
+            } else {
+                "".to_string()
+            },
+            entry.meal.name.to_uppercase()
+        )
+    }
+
+    pub fn title(&self) -> String {
+        format!(
+            "Plan {} - {}",
+            self.start.format("%a %d.%m."),
+            self.end().format("%a %d.%m.")
+        )
+    }
+
+    pub fn calendar(&self) -> String {
+        let days: Vec<String> = (0..self.days)
+            .map(|day| {
+                let entries = self.day_entries(day);
+                if entries.len() == 0 {
+                    format!("{}: -", self.date(day).format("%a %d"))
+                } else {
+                    let mut lines: Vec<String> = entries
+                        .iter()
+                        .map(|entry| self.entry_label(entry))
+                        .collect();
+                    if entries.iter().any(|entry| entry.meal.nutrition.is_some()) {
+                        let total = entries
+                            .iter()
+                            .filter_map(|entry| entry.meal.nutrition)
+                            .fold(Nutrients::default(), |total, n| total + n);
+                        lines.push(format!("    Σ {}", total));
+                    }
+                    lines.join("\n")
+                }
+            })
+            .collect();
+        format!("{}\n\n{}", self.title(), days.join("\n"))
+    }
+
+    /// Swaps all entries of two days (0 based).
+    pub fn swap_days(&mut self, a: usize, b: usize) {
+        for entry in self.entries.iter_mut() {
+            if entry.day == a {
+                entry.day = b;
+            } else if entry.day == b {
+                entry.day = a;
+            }
+        }
+    }
+
+    /// Moves all entries of day `from` to day `to`, shifting the days in between.
+    pub fn move_day(&mut self, from: usize, to: usize) {
+        for entry in self.entries.iter_mut() {
+            if entry.day == from {
+                entry.day = to;
+            } else if from < to && entry.day > from && entry.day <= to {
+                entry.day -= 1;
+            } else if to < from && entry.day >= to && entry.day < from {
+                entry.day += 1;
+            }
+        }
+    }
+
+    /// Entries with their index, ordered by day and slot.
+    pub fn sorted_entries(&self) -> Vec<(usize, &PlanEntry)> {
+        let mut entries: Vec<(usize, &PlanEntry)> = self.entries.iter().enumerate().collect();
+        entries.sort_by_key(|(_, entry)| (entry.day, entry.slot));
+        entries
+    }
+
+    pub fn buttons(&self) -> Vec<Vec<Button>> {
+        self.sorted_entries()
+            .into_iter()
+            .map(|(index, entry)| {
+                vec![
+                    Button::new(
+                        self.entry_label(entry),
+                        ButtonKind::DisplayPlanMeal {
+                            meal: entry.meal.clone(),
+                            plan: self.clone(),
+                        },
+                    ),
+                    Button::new(
+                        if entry.locked { "🔒" } else { "🔓" }.to_string(),
+                        ButtonKind::TogglePlanLock {
+                            plan_id: self.id.clone(),
+                            entry: index,
+                        },
+                    ),
+                    Button::new(
+                        "🎲".to_string(),
+                        ButtonKind::SwapPlanEntry {
+                            plan_id: self.id.clone(),
+                            entry: index,
+                        },
+                    ),
+                    Button::new(
+                        "✏️".to_string(),
+                        ButtonKind::PickPlanMeal {
+                            plan_id: self.id.clone(),
+                            entry: index,
+                            page: 0,
+                        },
+                    ),
+                ]
             })
             .collect()
     }
 
     pub fn answers(&self) -> Vec<String> {
-        self.meals
-            .iter()
-            .map(|meal| {
+        (0..self.days)
+            .flat_map(|day| self.day_entries(day))
+            .map(|entry| {
                 format!(
                     "{} ({}⭐)",
-                    meal.name.to_uppercase(),
-                    meal.rating.unwrap_or(1)
+                    self.entry_label(entry),
+                    entry.meal.rating.unwrap_or(1)
                 )
             })
             .collect()
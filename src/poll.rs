@@ -1,12 +1,16 @@
+use chrono::Utc;
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use teloxide::dispatching::UpdateWithCx;
+use teloxide::requests::Request;
 use teloxide::types::{ChatId, Poll as TgPoll};
+use teloxide::{ApiErrorKind, Bot, KnownApiErrorKind, RequestError};
 
 use crate::button;
 use crate::button::{Button, ButtonKind};
 use crate::keyboard::Keyboard;
+use crate::meal::Meal;
 use crate::plan::Plan;
+use crate::rating::{RatingStrategy, Vote};
 use crate::request::{RequestKind, RequestResult};
 use crate::StateLock;
 
@@ -19,8 +23,15 @@ pub enum PollKind {
     Plan {
         plan: Plan,
     },
+    /// Non-anonymous poll about what to cook tonight, one option per meal.
+    Tonight {
+        meals: Vec<Meal>,
+    },
 }
 
+/// Hours after which a tonight poll is closed.
+pub const TONIGHT_DEADLINE_HOURS: i64 = 2;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Poll {
     pub id: String,
@@ -30,6 +41,16 @@ pub struct Poll {
     pub poll_kind: PollKind,
     pub is_canceled: bool,
     pub keyboard_id: String,
+    /// Unix timestamp of when the poll was sent.
+    #[serde(default)]
+    pub created: i64,
+    /// Unix timestamp after which the poll is stopped automatically.
+    #[serde(default)]
+    pub deadline: Option<i64>,
+    /// Last update received for the poll, its votes are applied
+    /// when the poll turns out to be closed already.
+    #[serde(default)]
+    pub last_update: Option<TgPoll>,
 }
 
 impl Poll {
@@ -48,6 +69,64 @@ impl Poll {
             poll_kind,
             keyboard_id,
             is_canceled: false,
+            created: Utc::now().timestamp(),
+            deadline: None,
+            last_update: None,
+        }
+    }
+
+    /// Sets the deadline to `hours` after the poll was sent, `None` removes it.
+    pub fn deadline(mut self, hours: Option<i64>) -> Self {
+        self.deadline = hours.map(|hours| self.created + hours * 60 * 60);
+        self
+    }
+
+    /// Removes the poll and its keyboard from the state.
+    /// Returns false if the poll was already removed.
+    pub fn forget(&self, state: &StateLock) -> bool {
+        let mut state = state.write();
+        let removed = state.polls_mut().remove(&self.id).is_some();
+        state.keyboards_mut().remove(&self.keyboard_id);
+        removed
+    }
+
+    /// Stops the poll and applies its votes.
+    /// Polls that are already closed are applied with their last update, deleted polls are forgotten.
+    pub async fn stop(&self, bot: &Bot, state: &StateLock) {
+        if let Some(poll) = state.write().polls_mut().get_mut(&self.id) {
+            poll.deadline = None;
+        }
+        match bot
+            .stop_poll(self.chat_id.clone(), self.message_id)
+            .send()
+            .await
+        {
+            Ok(update) => {
+                log::info!("Stopped poll {}", self.id);
+                self.handle_votes(state, bot, &update).send(state).await;
+            }
+            Err(err) if is_closed(&err) => {
+                log::info!("Poll {} was closed already", self.id);
+                let last_update = state
+                    .read()
+                    .polls()
+                    .get(&self.id)
+                    .and_then(|poll| poll.last_update.clone());
+                match last_update {
+                    Some(mut update) => {
+                        update.is_closed = true;
+                        self.handle_votes(state, bot, &update).send(state).await;
+                    }
+                    None => {
+                        self.forget(state);
+                    }
+                }
+            }
+            Err(err) if is_gone(&err) => {
+                log::info!("Poll {} was deleted: {}", self.id, err);
+                self.forget(state);
+            }
+            Err(err) => log::warn!("Stop poll {}: {}", self.id, err),
         }
     }
 
@@ -59,7 +138,29 @@ impl Poll {
         self
     }
 
-    pub fn handle_votes(&self, state: &StateLock, cx: &UpdateWithCx<TgPoll>) -> RequestResult {
+    /// Handles a poll update, tonight polls are stopped as soon as enough people voted.
+    pub async fn handle_update(&self, state: &StateLock, bot: &Bot, update: &TgPoll) {
+        if let Some(poll) = state.write().polls_mut().get_mut(&self.id) {
+            poll.last_update = Some(update.clone());
+        }
+        if let PollKind::Tonight { .. } = self.poll_kind {
+            if !update.is_closed {
+                let voters = match self.chat_id {
+                    ChatId::Id(chat_id) => state.read().get_settings(chat_id).tonight_voters,
+                    _ => None,
+                };
+                if let Some(voters) = voters {
+                    if update.total_voter_count >= voters {
+                        self.stop(bot, state).await;
+                    }
+                }
+                return;
+            }
+        }
+        self.handle_votes(state, bot, update).send(state).await;
+    }
+
+    pub fn handle_votes(&self, state: &StateLock, bot: &Bot, update: &TgPoll) -> RequestResult {
         match &self.poll_kind {
             PollKind::Meal {
                 meal_id,
@@ -73,72 +174,81 @@ impl Poll {
                         log::warn!("No meal with id {} found for poll: {:?}", meal_id, self);
                         RequestResult::default()
                             .add(RequestKind::StopPoll(
-                                cx.bot.stop_poll(self.chat_id.clone(), self.message_id),
+                                bot.stop_poll(self.chat_id.clone(), self.message_id),
                             ))
                             .clone()
                     }
                     Some(meal) => {
-                        let total_votes = cx.update.total_voter_count;
-                        if cx.update.is_closed {
-                            state.write().polls_mut().remove(&self.id.clone());
+                        let total_votes = update.total_voter_count;
+                        if update.is_closed {
+                            // the votes of a poll are applied only once
+                            if !self.forget(state) {
+                                return RequestResult::default();
+                            }
                             if total_votes > 0 && !self.is_canceled {
                                 // someone voted and poll closed successfully ->
                                 //              update meal and save meal and poll
-                                let votes: Vec<(i32, i32)> = cx
-                                    .update
+                                let now = Utc::now().timestamp();
+                                let votes: Vec<Vote> = update
                                     .options
                                     .iter()
                                     .enumerate()
-                                    .map(|(i, po)| ((i + 1) as i32, po.voter_count))
+                                    .flat_map(|(i, option)| {
+                                        (0..option.voter_count).map(move |_| Vote {
+                                            rating: (i + 1) as u8,
+                                            timestamp: now,
+                                        })
+                                    })
                                     .collect();
-                                let avg = votes.iter().fold(0, |sum, vote| sum + vote.0 * vote.1)
-                                    / total_votes;
+                                let strategy = match self.chat_id {
+                                    ChatId::Id(chat_id) => {
+                                        state.read().get_settings(chat_id).rating_strategy
+                                    }
+                                    _ => RatingStrategy::default(),
+                                };
                                 let mut meal = meal.clone();
-                                meal.rate(Some(
-                                    ((avg as u8) + meal.rating.unwrap_or(avg as u8)) / 2,
-                                ));
-                                state.write().save_meal(&meal);
-                                state.write().meals_mut().remove(&meal.id);
-                                log::info!("Poll closed: {}", meal.name);
-                                // tell user that meal has been saved with new rating
+                                meal.add_votes(votes, strategy, now);
+                                let saved = state.write().save_meal(&meal);
+                                let text = match saved {
+                                    Ok(()) => {
+                                        state.write().meals_mut().remove(&meal.id);
+                                        log::info!("Poll closed: {}", meal.name);
+                                        format!("{}\n\nSaved!", meal)
+                                    }
+                                    Err(err) => {
+                                        format!("{}\n\n{}", meal, err.report("Save poll rating"))
+                                    }
+                                };
+                                // tell user whether the meal has been saved with new rating
                                 RequestResult::default()
-                                    .add(RequestKind::EditMessage(cx.bot.edit_message_text(
+                                    .add(RequestKind::EditMessage(bot.edit_message_text(
                                         self.chat_id.clone(),
                                         *reply_message_id,
-                                        format!("{}\n\nSaved!", meal),
+                                        text,
                                     )))
                                     .clone()
                             } else {
                                 // nobody voted or vote got canceled -> remove poll
                                 log::info!("Poll ended: {}", meal.name);
+                                let counts = state.read().tag_counts();
                                 // tell user that vote endet but nobody voted
                                 // and remove poll message and show old message again
                                 RequestResult::default()
                                     .add(RequestKind::EditMessage(
-                                        cx.bot
-                                            .edit_message_text(
-                                                self.chat_id.clone(),
-                                                *reply_message_id,
-                                                format!("{}\n\nPoll Canceled!", meal),
-                                            )
-                                            .reply_markup(
-                                                Keyboard::new()
-                                                    .buttons(vec![
-                                                        vec![Button::new(
-                                                            "Rate with Poll".into(),
-                                                            ButtonKind::PollRating {
-                                                                meal: meal.clone(),
-                                                            },
-                                                        )],
-                                                        button::save_meal_button_row(&meal.id),
-                                                    ])
-                                                    .save(&state)
-                                                    .inline_keyboard(),
-                                            ),
+                                        bot.edit_message_text(
+                                            self.chat_id.clone(),
+                                            *reply_message_id,
+                                            format!("{}\n\nPoll Canceled!", meal),
+                                        )
+                                        .reply_markup(
+                                            Keyboard::new()
+                                                .buttons(button::new_meal_keyboard(&meal, &counts))
+                                                .save(&state)
+                                                .inline_keyboard(),
+                                        ),
                                     ))
                                     .add(RequestKind::DeleteMessage(
-                                        cx.bot
-                                            .delete_message(self.chat_id.clone(), self.message_id),
+                                        bot.delete_message(self.chat_id.clone(), self.message_id),
                                     ))
                                     .clone()
                             }
@@ -162,12 +272,11 @@ impl Poll {
                                 // show save button
                                 RequestResult::default()
                                     .add(RequestKind::EditReplyMarkup(
-                                        cx.bot
-                                            .edit_message_reply_markup(
-                                                self.chat_id.clone(),
-                                                self.message_id,
-                                            )
-                                            .reply_markup(keyboard.inline_keyboard()),
+                                        bot.edit_message_reply_markup(
+                                            self.chat_id.clone(),
+                                            self.message_id,
+                                        )
+                                        .reply_markup(keyboard.inline_keyboard()),
                                     ))
                                     .clone()
                             } else {
@@ -186,12 +295,11 @@ impl Poll {
                                 }); // hide show button
                                 RequestResult::default()
                                     .add(RequestKind::EditReplyMarkup(
-                                        cx.bot
-                                            .edit_message_reply_markup(
-                                                self.chat_id.clone(),
-                                                self.message_id,
-                                            )
-                                            .reply_markup(keyboard.inline_keyboard()),
+                                        bot.edit_message_reply_markup(
+                                            self.chat_id.clone(),
+                                            self.message_id,
+                                        )
+                                        .reply_markup(keyboard.inline_keyboard()),
                                     ))
                                     .clone()
                             }
@@ -199,7 +307,148 @@ impl Poll {
                     }
                 }
             }
-            PollKind::Plan { .. } => RequestResult::default(),
+            PollKind::Tonight { meals } => {
+                let mut request = RequestResult::default();
+                if update.is_closed {
+                    if !self.forget(state) {
+                        return request;
+                    }
+                    let winner = update
+                        .options
+                        .iter()
+                        .zip(meals.iter())
+                        .filter(|(option, _)| option.voter_count > 0)
+                        .max_by(|(a, meal_a), (b, meal_b)| {
+                            a.voter_count
+                                .cmp(&b.voter_count)
+                                .then(meal_a.rating.cmp(&meal_b.rating))
+                        });
+                    match winner {
+                        Some((option, meal)) => {
+                            let meal = state
+                                .read()
+                                .get_saved_meal(meal.id.clone())
+                                .unwrap_or(meal.clone());
+                            if let ChatId::Id(chat_id) = self.chat_id {
+                                if let Err(err) = state.write().log_cooked(chat_id, &meal) {
+                                    err.log("Log cooked meal");
+                                }
+                            }
+                            request.add(meal.send_request(
+                                bot,
+                                self.chat_id.clone(),
+                                Some(format!(
+                                    "Tonight's winner with {} of {} votes!",
+                                    option.voter_count, update.total_voter_count
+                                )),
+                                None,
+                            ));
+                        }
+                        None => {
+                            request.message(
+                                bot.send_message(self.chat_id.clone(), "Nobody voted for tonight!"),
+                            );
+                        }
+                    }
+                }
+                request
+            }
+            PollKind::Plan { .. } => {
+                if update.is_closed {
+                    state.write().polls_mut().remove(&self.id);
+                }
+                RequestResult::default()
+            }
+        }
+    }
+}
+
+fn is_closed(err: &RequestError) -> bool {
+    match err {
+        RequestError::ApiError {
+            kind: ApiErrorKind::Known(KnownApiErrorKind::PollHasAlreadyClosed),
+            ..
+        } => true,
+        _ => false,
+    }
+}
+
+fn is_gone(err: &RequestError) -> bool {
+    match err {
+        RequestError::ApiError {
+            kind: ApiErrorKind::Known(kind),
+            ..
+        } => match kind {
+            KnownApiErrorKind::MessageWithPollNotFound
+            | KnownApiErrorKind::MessageToEditNotFound => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Stops all polls whose deadline passed at `now` (unix timestamp).
+pub async fn close_expired(bot: &Bot, state: &StateLock, now: i64) {
+    let expired: Vec<Poll> = state
+        .read()
+        .polls()
+        .values()
+        .filter(|poll| {
+            poll.deadline
+                .map(|deadline| deadline <= now)
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect();
+    if expired.len() > 0 {
+        for poll in expired {
+            poll.stop(bot, state).await;
+        }
+        if let Err(err) = state.write().save_tg() {
+            err.log("Save state");
+        }
+    }
+}
+
+/// Reconciles the stored polls with telegram after a restart:
+/// expired polls are stopped and applied, polls whose message is gone are forgotten.
+pub async fn reconcile(bot: &Bot, state: &StateLock) {
+    let now = Utc::now().timestamp();
+    let polls: Vec<Poll> = state.read().polls().values().cloned().collect();
+    log::info!("Reconciling {} polls...", polls.len());
+    for poll in polls {
+        if poll
+            .deadline
+            .map(|deadline| deadline <= now)
+            .unwrap_or(false)
+        {
+            poll.stop(bot, state).await;
+            continue;
+        }
+        let markup = state
+            .read()
+            .keyboards()
+            .get(&poll.keyboard_id)
+            .map(|keyboard| keyboard.inline_keyboard());
+        // without its keyboard the message can't be checked without changing it
+        let markup = match markup {
+            Some(markup) => markup,
+            None => continue,
+        };
+        // editing the markup to itself only fails if the message is gone
+        if let Err(err) = bot
+            .edit_message_reply_markup(poll.chat_id.clone(), poll.message_id)
+            .reply_markup(markup)
+            .send()
+            .await
+        {
+            if is_gone(&err) {
+                log::info!("Poll {} was deleted: {}", poll.id, err);
+                poll.forget(state);
+            }
         }
     }
+    if let Err(err) = state.write().save_tg() {
+        err.log("Save state");
+    }
 }
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Votes lose half of their weight every `HALF_LIFE_DAYS` with the recency strategy.
+const HALF_LIFE_DAYS: f64 = 30.0;
+
+/// A single vote of a rating poll.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vote {
+    pub rating: u8,
+    /// Unix timestamp of when the poll closed.
+    pub timestamp: i64,
+}
+
+/// Fractional rating of a meal and the number of votes it is based on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    pub value: f64,
+    pub votes: usize,
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "⭐ {:.1} ({} vote{})",
+            self.value,
+            self.votes,
+            if self.votes == 1 { "" } else { "s" }
+        )
+    }
+}
+
+/// How the votes of a closed poll are merged into the rating of a meal.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RatingStrategy {
+    /// Only the votes of the latest poll count.
+    Replace,
+    /// Mean of all votes ever given.
+    Mean,
+    /// Mean of all votes, recent votes weigh more.
+    Recency,
+}
+
+impl Default for RatingStrategy {
+    fn default() -> Self {
+        RatingStrategy::Mean
+    }
+}
+
+impl RatingStrategy {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "replace" => Some(RatingStrategy::Replace),
+            "mean" => Some(RatingStrategy::Mean),
+            "recency" => Some(RatingStrategy::Recency),
+            _ => None,
+        }
+    }
+
+    /// Aggregated rating of `history` (all votes) after `latest` were added, at `now`.
+    pub fn aggregate(&self, history: &Vec<Vote>, latest: &Vec<Vote>, now: i64) -> Option<Score> {
+        let votes = match self {
+            RatingStrategy::Replace => latest,
+            _ => history,
+        };
+        let weight = |vote: &Vote| match self {
+            RatingStrategy::Recency => {
+                let age_days = (now - vote.timestamp).max(0) as f64 / (24.0 * 60.0 * 60.0);
+                0.5f64.powf(age_days / HALF_LIFE_DAYS)
+            }
+            _ => 1.0,
+        };
+        let total: f64 = votes.iter().map(weight).sum();
+        if total > 0.0 {
+            Some(Score {
+                value: votes
+                    .iter()
+                    .map(|vote| vote.rating as f64 * weight(vote))
+                    .sum::<f64>()
+                    / total,
+                votes: votes.len(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for RatingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatingStrategy::Replace => write!(f, "replace"),
+            RatingStrategy::Mean => write!(f, "mean"),
+            RatingStrategy::Recency => write!(f, "recency"),
+        }
+    }
+}
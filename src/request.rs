@@ -1,13 +1,56 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use teloxide::requests::*;
 use teloxide::types::*;
 
-use crate::poll::{Poll, PollKind};
+use crate::error::Error;
+use crate::images::StoredImage;
+use crate::outbox::{self, Limit};
+use crate::poll::{Poll, PollKind, TONIGHT_DEADLINE_HOURS};
 use crate::StateLock;
 
+/// Stored copy of a meal photo, uploaded again if its file id stopped working
+/// (e.g. after the bot token changed).
+#[derive(Clone)]
+pub struct PhotoFallback {
+    pub meal_id: String,
+    pub image: StoredImage,
+}
+
+/// Message sent by the bot, `date` is the unix timestamp it was sent at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub message_id: i32,
+    pub date: i32,
+}
+
+/// What to remember about the messages of a request once they are sent.
+#[derive(Debug, Clone)]
+pub enum Hook {
+    /// The message is the chat's live plan message.
+    PlanMessage,
+    /// The message shows the keyboard with this id.
+    Keyboard(String),
+}
+
+impl Hook {
+    fn run(&self, state: &StateLock, message: &Message) {
+        let mut state = state.write();
+        match self {
+            Hook::PlanMessage => state.set_plan_message(message.chat_id(), message.id),
+            Hook::Keyboard(keyboard_id) => {
+                state.set_keyboard_message(keyboard_id, message.chat_id(), message.id)
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum RequestKind {
     Message(SendMessage, bool),
-    Photo(SendPhoto),
+    Photo(SendPhoto, Option<PhotoFallback>),
+    MediaGroup(SendMediaGroup),
+    Document(SendDocument),
     EditMessage(EditMessageText),
     EditInlineMessage(EditInlineMessageText),
     EditMedia(EditMessageMedia),
@@ -24,118 +67,342 @@ pub enum RequestKind {
 #[derive(Clone)]
 pub struct RequestResult {
     pub requests: Vec<RequestKind>,
+    /// Hooks with the index of their request.
+    hooks: Vec<(usize, Hook)>,
 }
 
 impl Default for RequestResult {
     fn default() -> Self {
-        Self { requests: vec![] }
+        Self {
+            requests: vec![],
+            hooks: vec![],
+        }
+    }
+}
+
+/// Messages and errors of sent requests.
+#[derive(Default)]
+pub struct Sent {
+    pub messages: Vec<Message>,
+    pub errors: Vec<Error>,
+}
+
+/// Id of the stored keyboard shown by a reply markup, its buttons carry "<keyboard id>.<button id>".
+fn keyboard_id(markup: &Option<ReplyMarkup>) -> Option<String> {
+    match markup {
+        Some(ReplyMarkup::InlineKeyboardMarkup(markup)) => markup
+            .inline_keyboard
+            .iter()
+            .flatten()
+            .find_map(|button| match &button.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => data
+                    .split('.')
+                    .next()
+                    .filter(|id| id.len() > 0)
+                    .map(|id| id.to_string()),
+                _ => None,
+            }),
+        _ => None,
     }
 }
 
+/// Message of a sent request, none if telegram had nothing to change.
+fn sent(result: Result<Option<Message>, Error>) -> Result<Vec<Message>, Error> {
+    result.map(|message| message.into_iter().collect())
+}
+
+/// Edits change existing messages, there is nothing new to remember.
+fn edited<T>(result: Result<Option<T>, Error>) -> Result<Vec<Message>, Error> {
+    result.map(|_| vec![])
+}
+
 impl RequestResult {
+    /// Adds a request, new messages with a keyboard are remembered for the keyboard.
     pub fn add(&mut self, request: RequestKind) -> &mut Self {
+        let keyboard = match &request {
+            RequestKind::Message(send_request, _) => keyboard_id(&send_request.reply_markup),
+            RequestKind::Photo(send_request, _) => keyboard_id(&send_request.reply_markup),
+            RequestKind::Document(send_request) => keyboard_id(&send_request.reply_markup),
+            RequestKind::Poll(_, _, keyboard_id) => Some(keyboard_id.clone()),
+            _ => None,
+        };
         self.requests.push(request);
+        if let Some(keyboard_id) = keyboard {
+            self.hook(Hook::Keyboard(keyboard_id));
+        }
         self
     }
 
-    pub fn message(&mut self, message: SendMessage) -> &mut Self {
-        self.requests.push(RequestKind::Message(message, false));
+    /// Runs the hook with the messages of the last added request once it is sent.
+    pub fn hook(&mut self, hook: Hook) -> &mut Self {
+        if let Some(index) = self.requests.len().checked_sub(1) {
+            self.hooks.push((index, hook));
+        }
+        self
+    }
+
+    /// Adds the requests of another result after the own ones.
+    pub fn append(&mut self, other: RequestResult) -> &mut Self {
+        let offset = self.requests.len();
+        self.requests.extend(other.requests);
+        self.hooks.extend(
+            other
+                .hooks
+                .into_iter()
+                .map(|(index, hook)| (index + offset, hook)),
+        );
         self
     }
 
-    pub async fn send(&self, state: &StateLock) {
-        for request in &self.requests {
-            match request {
+    pub fn message(&mut self, message: SendMessage) -> &mut Self {
+        self.add(RequestKind::Message(message, false))
+    }
+
+    /// Sends the requests in order through the outbox, failures are logged and counted there.
+    /// Returns the new messages, they are remembered for /clean and passed to the hooks,
+    /// and the errors of the requests the user would notice missing.
+    pub async fn send(&self, state: &StateLock) -> Sent {
+        let mut result = Sent::default();
+        // a message can be deleted by several requests, e.g. a plan button and the new plan
+        let mut deleted = HashSet::new();
+        for (index, request) in self.requests.iter().enumerate() {
+            let new_messages = match request {
                 RequestKind::Message(send_request, notify) => {
-                    match send_request
-                        .clone()
-                        .disable_notification(!notify)
-                        .send()
-                        .await
-                    {
-                        Ok(_) => log::info!("Send Message"),
-                        Err(err) => log::warn!("Send Message: {}", err),
+                    let send_request = send_request.clone().disable_notification(!notify);
+                    sent(
+                        outbox::send(
+                            state,
+                            Limit::send(&send_request.chat_id),
+                            &send_request,
+                            "Send Message",
+                        )
+                        .await,
+                    )
+                }
+                RequestKind::DeleteMessage(send_request) => {
+                    let chat_id = outbox::chat_key(&send_request.chat_id);
+                    if deleted.insert((chat_id, send_request.message_id)) {
+                        let deleted = outbox::send(
+                            state,
+                            Limit::chat(&send_request.chat_id),
+                            send_request,
+                            "Delete Message",
+                        )
+                        .await;
+                        if let (Ok(_), Some(chat_id)) = (deleted, chat_id) {
+                            state
+                                .write()
+                                .forget_message(chat_id, send_request.message_id);
+                        }
                     }
+                    // deleting is best effort, e.g. the bot may not be allowed to in a group
+                    Ok(vec![])
                 }
-                RequestKind::DeleteMessage(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Delete Message"),
-                    Err(err) => log::warn!("Delete Message: {}", err),
-                },
-                RequestKind::Photo(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Send Photo"),
-                    Err(err) => log::warn!("Send Photo: {}", err),
-                },
-                RequestKind::EditMessage(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Edit Message"),
-                    Err(err) => log::warn!("Edit Message: {}", err),
-                },
-                RequestKind::EditReplyMarkup(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Edit Reply Markup"),
-                    Err(err) => log::warn!("Edit Reply Markup: {}", err),
-                },
-                RequestKind::EditInlineMessage(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Edit Inline Message"),
-                    Err(err) => log::warn!("Edit Inline Message: {}", err),
-                },
-                RequestKind::EditMedia(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Edit Media"),
-                    Err(err) => log::warn!("Edit Media: {}", err),
-                },
-                RequestKind::EditInlineMedia(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Edit Inline Media"),
-                    Err(err) => log::warn!("Edit Inline Media: {}", err),
-                },
-                RequestKind::EditCaption(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Edit Caption"),
-                    Err(err) => log::warn!("Edit Caption: {}", err),
-                },
-                RequestKind::CallbackAnswer(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Callback Answer"),
-                    Err(err) => log::warn!("Callback Answer: {}", err),
-                },
-                RequestKind::Pin(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Pin Message"),
-                    Err(err) => log::warn!("Pin Message: {}", err),
-                },
+                RequestKind::Photo(send_request, fallback) => {
+                    let sent_photo = outbox::try_send(
+                        state,
+                        Limit::send(&send_request.chat_id),
+                        send_request,
+                        "Send Photo",
+                    )
+                    .await;
+                    match (sent_photo, fallback) {
+                        (Err(err), Some(fallback)) if outbox::is_invalid_file(&err) => {
+                            log::info!("Send Photo: {}, uploading the stored copy", err);
+                            Self::reupload(state, send_request, fallback)
+                                .await
+                                .map(|message| message.into_iter().collect())
+                        }
+                        (sent_photo, _) => {
+                            sent(sent_photo.map_err(|err| outbox::given_up(err, "Send Photo")))
+                        }
+                    }
+                }
+                RequestKind::MediaGroup(send_request) => outbox::send(
+                    state,
+                    Limit::send(&send_request.chat_id),
+                    send_request,
+                    "Send Media Group",
+                )
+                .await
+                .map(|messages| messages.unwrap_or_default()),
+                RequestKind::Document(send_request) => sent(
+                    outbox::send(
+                        state,
+                        Limit::send(&send_request.chat_id),
+                        send_request,
+                        "Send Document",
+                    )
+                    .await,
+                ),
+                RequestKind::EditMessage(send_request) => edited(
+                    outbox::send(
+                        state,
+                        Limit::chat(&send_request.chat_id),
+                        send_request,
+                        "Edit Message",
+                    )
+                    .await,
+                ),
+                RequestKind::EditReplyMarkup(send_request) => edited(
+                    outbox::send(
+                        state,
+                        Limit::chat(&send_request.chat_id),
+                        send_request,
+                        "Edit Reply Markup",
+                    )
+                    .await,
+                ),
+                RequestKind::EditInlineMessage(send_request) => edited(
+                    outbox::send(state, Limit::Global, send_request, "Edit Inline Message").await,
+                ),
+                RequestKind::EditMedia(send_request) => edited(
+                    outbox::send(
+                        state,
+                        Limit::chat(&send_request.chat_id),
+                        send_request,
+                        "Edit Media",
+                    )
+                    .await,
+                ),
+                RequestKind::EditInlineMedia(send_request) => edited(
+                    outbox::send(state, Limit::Global, send_request, "Edit Inline Media").await,
+                ),
+                RequestKind::EditCaption(send_request) => edited(
+                    outbox::send(
+                        state,
+                        Limit::chat(&send_request.chat_id),
+                        send_request,
+                        "Edit Caption",
+                    )
+                    .await,
+                ),
+                RequestKind::CallbackAnswer(send_request) => {
+                    // an error could only be told with another answer, which would fail as well
+                    let _ =
+                        outbox::send(state, Limit::Global, send_request, "Callback Answer").await;
+                    Ok(vec![])
+                }
+                RequestKind::Pin(send_request) => edited(
+                    outbox::send(
+                        state,
+                        Limit::chat(&send_request.chat_id),
+                        send_request,
+                        "Pin Message",
+                    )
+                    .await,
+                ),
                 RequestKind::Poll(send_request, poll_kind, keyboard_id) => {
-                    match send_request.send().await {
-                        Ok(message) => match message.clone() {
-                            Message {
-                                kind:
-                                    MessageKind::Common(MessageCommon {
-                                        media_kind: MediaKind::Poll(MediaPoll { poll, .. }),
-                                        ..
-                                    }),
-                                id: message_id,
-                                chat:
-                                    Chat {
-                                        id: chat_id_raw, ..
-                                    },
-                                ..
-                            } => {
-                                let poll_id = poll.id;
-                                let chat_id = ChatId::Id(chat_id_raw);
-                                Poll::new(
-                                    poll_id,
-                                    chat_id,
-                                    message_id,
-                                    poll_kind.clone(),
-                                    keyboard_id.clone(),
-                                )
-                                .save(&state);
-                                log::info!("Send Poll",);
-                            }
-                            _ => log::warn!("No Poll found in Message: {:?}", message),
-                        },
-                        Err(err) => log::warn!("Send Poll: {}", err),
+                    let new_messages = sent(
+                        outbox::send(
+                            state,
+                            Limit::send(&send_request.chat_id),
+                            send_request,
+                            "Send Poll",
+                        )
+                        .await,
+                    );
+                    for message in new_messages.iter().flatten() {
+                        Self::save_poll(state, message, poll_kind, keyboard_id);
                     }
+                    new_messages
                 }
-                RequestKind::StopPoll(send_request) => match send_request.send().await {
-                    Ok(_) => log::info!("Stopping Poll"),
-                    Err(err) => log::warn!("Error Stop Poll: {}", err),
+                RequestKind::StopPoll(send_request) => edited(
+                    outbox::send(
+                        state,
+                        Limit::chat(&send_request.chat_id),
+                        send_request,
+                        "Stop Poll",
+                    )
+                    .await,
+                ),
+            };
+            match new_messages {
+                Ok(new_messages) => {
+                    for message in new_messages.iter() {
+                        state.write().remember_message(message);
+                        for (_, hook) in self.hooks.iter().filter(|(hooked, _)| *hooked == index) {
+                            hook.run(state, message);
+                        }
+                    }
+                    result.messages.extend(new_messages);
+                }
+                Err(err) => result.errors.push(err),
+            }
+        }
+        let saved = state.write().save_tg();
+        if let Err(err) = saved {
+            err.log("Save state");
+            result.errors.push(err);
+        }
+        result
+    }
+
+    fn save_poll(state: &StateLock, message: &Message, poll_kind: &PollKind, keyboard_id: &str) {
+        match message.clone() {
+            Message {
+                kind:
+                    MessageKind::Common(MessageCommon {
+                        media_kind: MediaKind::Poll(MediaPoll { poll, .. }),
+                        ..
+                    }),
+                id: message_id,
+                chat: Chat {
+                    id: chat_id_raw, ..
                 },
+                ..
+            } => {
+                let poll_id = poll.id;
+                let chat_id = ChatId::Id(chat_id_raw);
+                let timeout = match poll_kind {
+                    PollKind::Meal { .. } => state.read().get_settings(chat_id_raw).poll_timeout,
+                    PollKind::Tonight { .. } => Some(TONIGHT_DEADLINE_HOURS),
+                    _ => None,
+                };
+                Poll::new(
+                    poll_id,
+                    chat_id,
+                    message_id,
+                    poll_kind.clone(),
+                    keyboard_id.to_string(),
+                )
+                .deadline(timeout)
+                .save(&state);
+            }
+            _ => log::warn!("No Poll found in Message: {:?}", message),
+        }
+    }
+
+    /// Sends the stored copy of a photo and keeps the new file id for the meal.
+    async fn reupload(
+        state: &StateLock,
+        send_request: &SendPhoto,
+        fallback: &PhotoFallback,
+    ) -> Result<Option<Message>, Error> {
+        let path = fallback.image.path();
+        let send_request = send_request.clone().photo(InputFile::File(path.clone()));
+        let context = format!("Re-upload Photo {}", path.display());
+        let message = outbox::send(
+            state,
+            Limit::send(&send_request.chat_id),
+            &send_request,
+            &context,
+        )
+        .await?;
+        if let Some(photo) = message
+            .as_ref()
+            .and_then(|message| message.photo())
+            .and_then(|photos| photos.last())
+        {
+            let replaced = state.write().replace_meal_photo(
+                &fallback.meal_id,
+                &fallback.image.file_unique_id,
+                photo.clone(),
+            );
+            if let Err(err) = replaced {
+                err.log(&context);
             }
         }
-        state.write().save_tg();
+        Ok(message)
     }
 }
@@ -0,0 +1,552 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, Timelike};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use teloxide::types::ChatId;
+use teloxide::Bot;
+
+use crate::button;
+use crate::pantry;
+use crate::plan::Plan;
+use crate::poll::{self, PollKind};
+use crate::request::{RequestKind, RequestResult};
+use crate::StateLock;
+
+const TICK_SECS: u64 = 30;
+/// Upper bound for searching the next run, cron expressions like "0 0 31 2 *" never match.
+const MAX_SEARCH_STEPS: usize = 100_000;
+
+/// Source of the current time, replaceable to run jobs at arbitrary times.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Local>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// Five field cron expression (minute hour day month weekday) in local time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cron {
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: Vec<u32>,
+    months: Vec<u32>,
+    /// 0 is sunday.
+    weekdays: Vec<u32>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<u32>, String> {
+    let invalid = || format!("Invalid cron field {} (allowed {}-{})", field, min, max);
+    let mut values = vec![];
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(pos) => (
+                &part[..pos],
+                part[pos + 1..].parse::<u32>().map_err(|_| invalid())?,
+            ),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some(pos) = range.find('-') {
+            (
+                range[..pos].parse::<u32>().map_err(|_| invalid())?,
+                range[pos + 1..].parse::<u32>().map_err(|_| invalid())?,
+            )
+        } else {
+            let value = range.parse::<u32>().map_err(|_| invalid())?;
+            (value, if part.contains('/') { max } else { value })
+        };
+        if from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        values.extend((from..=to).step_by(step as usize));
+    }
+    values.sort();
+    values.dedup();
+    Ok(values)
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expression => expression,
+        };
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        match fields.as_slice() {
+            [minutes, hours, days, months, weekdays] => Ok(Self {
+                minutes: parse_field(minutes, 0, 59)?,
+                hours: parse_field(hours, 0, 23)?,
+                days: parse_field(days, 1, 31)?,
+                months: parse_field(months, 1, 12)?,
+                weekdays: {
+                    let mut weekdays: Vec<u32> = parse_field(weekdays, 0, 7)?
+                        .into_iter()
+                        .map(|day| day % 7)
+                        .collect();
+                    weekdays.sort();
+                    weekdays.dedup();
+                    weekdays
+                },
+                days_restricted: *days != "*",
+                weekdays_restricted: *weekdays != "*",
+            }),
+            _ => Err(format!(
+                "Cron expression needs 5 fields (minute hour day month weekday), got {}",
+                expression
+            )),
+        }
+    }
+
+    /// Day of month and weekday match if either does when both are restricted, like cron.
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let day = self.days.contains(&date.day());
+        let weekday = self
+            .weekdays
+            .contains(&date.weekday().num_days_from_sunday());
+        self.months.contains(&date.month())
+            && if self.days_restricted && self.weekdays_restricted {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+
+    /// First matching minute after `after`.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = after.date().and_hms(after.hour(), after.minute(), 0) + Duration::minutes(1);
+        for _ in 0..MAX_SEARCH_STEPS {
+            if !self.matches_date(time.date()) {
+                time = time.date().succ().and_hms(0, 0, 0);
+            } else if !self.hours.contains(&time.hour()) {
+                time = time.date().and_hms(time.hour(), 0, 0) + Duration::hours(1);
+            } else if !self.minutes.contains(&time.minute()) {
+                time = time + Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JobAction {
+    PostTomorrow,
+    GeneratePlan { days: usize },
+    ClosePolls { hours: i64 },
+    Remind(String),
+    WarnExpiring { days: i64 },
+}
+
+impl JobAction {
+    pub fn parse(words: &[&str]) -> Result<Self, String> {
+        let number = |pos: usize, default: i64| -> Result<i64, String> {
+            match words.get(pos) {
+                Some(word) => match word.parse::<i64>() {
+                    Ok(number) if number > 0 => Ok(number),
+                    _ => Err(format!("{} has to be a positive number!", word)),
+                },
+                None => Ok(default),
+            }
+        };
+        match words.get(0) {
+            Some(&"tomorrow") => Ok(JobAction::PostTomorrow),
+            Some(&"plan") => Ok(JobAction::GeneratePlan {
+                days: number(1, 7)? as usize,
+            }),
+            Some(&"closepolls") => Ok(JobAction::ClosePolls {
+                hours: number(1, 24)?,
+            }),
+            Some(&"remind") if words.len() > 1 => Ok(JobAction::Remind(words[1..].join(" "))),
+            Some(&"expiring") => Ok(JobAction::WarnExpiring {
+                days: number(1, pantry::EXPIRY_WARNING_DAYS)?,
+            }),
+            _ => Err(
+                "Action has to be tomorrow, plan [days], closepolls [hours], remind <text> or expiring [days]!"
+                    .to_string(),
+            ),
+        }
+    }
+
+    pub fn request(
+        &self,
+        bot: &Bot,
+        chat_id: i64,
+        state: &StateLock,
+        now: DateTime<Local>,
+    ) -> RequestResult {
+        let mut request = RequestResult::default();
+        let tomorrow = now.date().naive_local().succ();
+        match self {
+            JobAction::PostTomorrow => {
+                let plan_opt = state.read().get_plan(chat_id);
+                if let Some(plan) = plan_opt {
+                    let day = (tomorrow - plan.start).num_days();
+                    if day >= 0 && (day as usize) < plan.days {
+                        let entries = plan.day_entries(day as usize);
+                        if entries.len() > 0 {
+                            request.message(bot.send_message(
+                                chat_id,
+                                format!(
+                                    "Tomorrow:\n{}",
+                                    entries
+                                        .iter()
+                                        .map(|entry| plan.entry_label(entry))
+                                        .collect::<Vec<_>>()
+                                        .join("\n")
+                                ),
+                            ));
+                        }
+                    }
+                }
+            }
+            JobAction::GeneratePlan { days } => {
+                let meals = state.read().get_saved_meals_for(chat_id);
+                if meals.len() > 0 {
+                    let slots = state.read().get_settings(chat_id).slots;
+                    let recent = state.read().recent_meal_ids(chat_id);
+                    let plan = Plan::gen(meals, *days, tomorrow, slots, &recent);
+                    let saved = state.write().save_plan(chat_id, plan.clone());
+                    match saved {
+                        Ok(()) => {
+                            request.append(button::plan_request(bot, chat_id, plan, state));
+                        }
+                        Err(err) => {
+                            request.add(RequestKind::Message(
+                                bot.send_message(chat_id, err.report("Scheduled plan")),
+                                false,
+                            ));
+                        }
+                    }
+                }
+            }
+            JobAction::ClosePolls { hours } => {
+                let polls = state.read().polls().clone();
+                for poll in polls.values().filter(|poll| {
+                    poll.chat_id == ChatId::Id(chat_id)
+                        && !poll.is_canceled
+                        && poll.created + hours * 60 * 60 <= now.timestamp()
+                        && match poll.poll_kind {
+                            PollKind::Meal { .. } => true,
+                            _ => false,
+                        }
+                }) {
+                    request.add(RequestKind::StopPoll(
+                        bot.stop_poll(poll.chat_id.clone(), poll.message_id),
+                    ));
+                }
+            }
+            JobAction::Remind(text) => {
+                request.message(bot.send_message(chat_id, text.clone()));
+            }
+            JobAction::WarnExpiring { days } => {
+                let today = now.date().naive_local();
+                let pantry = state.read().get_pantry(chat_id);
+                let mut warnings = vec![];
+                for item in pantry.expiring(today, *days) {
+                    let meals = state
+                        .read()
+                        .meals_with_ingredient(chat_id, &item.ingredient);
+                    // meals that need the fewest other ingredients first
+                    let meals = pantry
+                        .cancook(meals)
+                        .into_iter()
+                        .map(|coverage| coverage.meal)
+                        .collect();
+                    warnings.push(pantry::expiry_warning(item, today, &meals));
+                }
+                if warnings.len() > 0 {
+                    request.message(bot.send_message(chat_id, warnings.join("\n")));
+                }
+            }
+        }
+        request
+    }
+}
+
+impl fmt::Display for JobAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobAction::PostTomorrow => write!(f, "post tomorrow's meals"),
+            JobAction::GeneratePlan { days } => write!(f, "generate a {} day plan", days),
+            JobAction::ClosePolls { hours } => {
+                write!(f, "close rating polls older than {}h", hours)
+            }
+            JobAction::Remind(text) => write!(f, "remind \"{}\"", text),
+            JobAction::WarnExpiring { days } => {
+                write!(f, "warn about pantry items expiring within {} days", days)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ScheduleAction {
+    List,
+    Add {
+        expression: String,
+        cron: Cron,
+        action: JobAction,
+    },
+    Remove(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub chat_id: i64,
+    pub expression: String,
+    pub cron: Cron,
+    pub action: JobAction,
+    /// Local time of the last run, or of the creation if the job never ran.
+    pub last_run: NaiveDateTime,
+}
+
+impl Job {
+    pub fn new(
+        chat_id: i64,
+        expression: String,
+        cron: Cron,
+        action: JobAction,
+        now: DateTime<Local>,
+    ) -> Self {
+        Self {
+            id: nanoid!(6),
+            chat_id,
+            expression,
+            cron,
+            action,
+            last_run: now.naive_local(),
+        }
+    }
+
+    pub fn next_run(&self) -> Option<NaiveDateTime> {
+        self.cron.next_after(self.last_run)
+    }
+
+    /// Runs missed while the bot was offline are caught up once.
+    pub fn is_due(&self, now: DateTime<Local>) -> bool {
+        match self.next_run() {
+            Some(next) => next <= now.naive_local(),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: \"{}\" {} (next: {})",
+            self.id,
+            self.expression,
+            self.action,
+            self.next_run()
+                .map(|next| next.format("%a %d.%m. %H:%M").to_string())
+                .unwrap_or("never".to_string())
+        )
+    }
+}
+
+/// Jobs due at `now`, their last run is set to `now`.
+fn take_due(jobs: &mut HashMap<String, Job>, now: DateTime<Local>) -> Vec<Job> {
+    jobs.values_mut()
+        .filter(|job| job.is_due(now))
+        .map(|job| {
+            job.last_run = now.naive_local();
+            job.clone()
+        })
+        .collect()
+}
+
+/// Takes the due jobs of all chats and saves their last run.
+pub fn take_due_jobs(state: &StateLock, now: DateTime<Local>) -> Vec<Job> {
+    let mut state = state.write();
+    let due = take_due(state.jobs_mut(), now);
+    if due.len() > 0 {
+        if let Err(err) = state.save_tg() {
+            err.log("Save jobs");
+        }
+    }
+    due
+}
+
+/// Reconciles the stored polls, then checks the stored jobs periodically and runs the due ones,
+/// polls are stopped when their deadline passed.
+pub fn spawn<C: Clock>(bot: Bot, state: StateLock, clock: C) {
+    tokio::spawn(async move {
+        poll::reconcile(&bot, &state).await;
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(TICK_SECS));
+        loop {
+            interval.tick().await;
+            for job in take_due_jobs(&state, clock.now()) {
+                log::info!("Running job {}", job);
+                job.action
+                    .request(&bot, job.chat_id, &state, clock.now())
+                    .send(&state)
+                    .await;
+            }
+            poll::close_expired(&bot, &state, clock.now().timestamp()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    struct FixedClock(NaiveDateTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Local> {
+            Local.from_local_datetime(&self.0).unwrap()
+        }
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    fn job(expression: &str, created: NaiveDateTime) -> Job {
+        Job::new(
+            1,
+            expression.to_string(),
+            Cron::parse(expression).unwrap(),
+            JobAction::Remind("test".to_string()),
+            FixedClock(created).now(),
+        )
+    }
+
+    #[test]
+    fn parses_fields() {
+        let cron = Cron::parse("*/15 8-10 1,15 * 1-5").unwrap();
+        assert_eq!(cron.minutes, vec![0, 15, 30, 45]);
+        assert_eq!(cron.hours, vec![8, 9, 10]);
+        assert_eq!(cron.days, vec![1, 15]);
+        assert_eq!(cron.months, (1..=12).collect::<Vec<u32>>());
+        assert_eq!(cron.weekdays, vec![1, 2, 3, 4, 5]);
+        assert!(cron.days_restricted && cron.weekdays_restricted);
+    }
+
+    #[test]
+    fn parses_steps_and_sunday() {
+        let cron = Cron::parse("5/20 * * * 5-7").unwrap();
+        assert_eq!(cron.minutes, vec![5, 25, 45]);
+        assert_eq!(cron.weekdays, vec![0, 5, 6]);
+        assert!(!cron.days_restricted);
+        assert_eq!(Cron::parse("@daily"), Cron::parse("0 0 * * *"));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in &[
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "5-3 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "* * * *",
+        ] {
+            assert!(Cron::parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn next_after_skips_current_minute() {
+        let cron = Cron::parse("30 9 * * *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 1, 1, 9, 30)),
+            Some(at(2021, 1, 2, 9, 30))
+        );
+        assert_eq!(
+            cron.next_after(at(2021, 1, 1, 9, 29)),
+            Some(at(2021, 1, 1, 9, 30))
+        );
+    }
+
+    #[test]
+    fn next_after_rolls_over_month_and_year() {
+        let cron = Cron::parse("30 9 1 * *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 1, 31, 10, 0)),
+            Some(at(2021, 2, 1, 9, 30))
+        );
+        let cron = Cron::parse("0 0 1 1 *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 6, 15, 12, 0)),
+            Some(at(2022, 1, 1, 0, 0))
+        );
+        let cron = Cron::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 1, 0, 0)),
+            Some(at(2024, 2, 29, 0, 0))
+        );
+        assert_eq!(
+            Cron::parse("0 0 31 2 *")
+                .unwrap()
+                .next_after(at(2021, 1, 1, 0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn next_after_matches_weekdays() {
+        // 2021-03-06 is a saturday
+        let cron = Cron::parse("0 18 * * 1").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 6, 12, 0)),
+            Some(at(2021, 3, 8, 18, 0))
+        );
+        // day of month or weekday, the friday comes first
+        let cron = Cron::parse("0 12 13 * 5").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 1, 0, 0)),
+            Some(at(2021, 3, 5, 12, 0))
+        );
+    }
+
+    #[test]
+    fn job_is_due() {
+        let job = job("0 9 * * *", at(2021, 1, 1, 8, 0));
+        assert!(!job.is_due(FixedClock(at(2021, 1, 1, 8, 59)).now()));
+        assert!(job.is_due(FixedClock(at(2021, 1, 1, 9, 0)).now()));
+        // missed runs are due after a restart
+        assert!(job.is_due(FixedClock(at(2021, 1, 5, 12, 0)).now()));
+    }
+
+    #[test]
+    fn takes_due_jobs_once() {
+        let daily = job("0 9 * * *", at(2021, 1, 1, 10, 0));
+        let monthly = job("0 9 1 * *", at(2021, 1, 1, 10, 0));
+        let mut jobs: HashMap<String, Job> = vec![daily.clone(), monthly.clone()]
+            .into_iter()
+            .map(|job| (job.id.clone(), job))
+            .collect();
+        let clock = FixedClock(at(2021, 1, 2, 9, 0));
+        let due = take_due(&mut jobs, clock.now());
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, daily.id);
+        assert_eq!(jobs[&daily.id].last_run, at(2021, 1, 2, 9, 0));
+        assert_eq!(jobs[&monthly.id].last_run, at(2021, 1, 1, 10, 0));
+        assert!(take_due(&mut jobs, clock.now()).is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use crate::diet::Label;
+use crate::plan::Slot;
+use crate::rating::RatingStrategy;
+
+fn default_history_penalty() -> usize {
+    2
+}
+
+fn default_slots() -> Vec<Slot> {
+    vec![Slot::Dinner]
+}
+
+/// Settings of a single chat, stored per chat id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatSettings {
+    #[serde(default = "default_slots")]
+    pub slots: Vec<Slot>,
+    /// Secret part of the calendar subscription url.
+    #[serde(default)]
+    pub ics_token: Option<String>,
+    /// Meals of the last `n` archived plans are picked less often.
+    #[serde(default = "default_history_penalty")]
+    pub history_penalty: usize,
+    /// Hours after which rating polls are closed automatically.
+    #[serde(default)]
+    pub poll_timeout: Option<i64>,
+    #[serde(default)]
+    pub rating_strategy: RatingStrategy,
+    /// Tonight polls are closed once this many people voted, otherwise at their deadline.
+    #[serde(default)]
+    pub tonight_voters: Option<i32>,
+    /// Diet profile, only fitting meals are suggested.
+    #[serde(default)]
+    pub diet: Vec<Label>,
+    /// Meals without any diet information still fit the diet profile.
+    #[serde(default)]
+    pub diet_unlabeled: bool,
+    /// Ticked shopping items are added to the pantry.
+    #[serde(default)]
+    pub pantry_sync: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            slots: default_slots(),
+            ics_token: None,
+            history_penalty: default_history_penalty(),
+            poll_timeout: None,
+            rating_strategy: RatingStrategy::default(),
+            tonight_voters: None,
+            diet: vec![],
+            diet_unlabeled: false,
+            pantry_sync: false,
+        }
+    }
+}
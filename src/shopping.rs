@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+use crate::button::{Button, ButtonKind};
+use crate::ingredient::Ingredient;
+use crate::meal::Meal;
+use crate::plan::Plan;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShoppingItem {
+    pub ingredient: Ingredient,
+    pub ticked: bool,
+    /// What ticking the item added to the pantry, taken out again when it is unticked.
+    #[serde(default)]
+    pub in_pantry: Option<Ingredient>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShoppingList {
+    pub plan_id: String,
+    pub items: Vec<ShoppingItem>,
+}
+
+impl ShoppingList {
+    /// Sums up the ingredients of all planned meals, scaled to the servings of each day.
+    /// Planned meals are looked up in `saved` to pick up ingredients added after planning.
+    /// Items already ticked in `previous` stay ticked.
+    pub fn from_plan(plan: &Plan, saved: &Vec<Meal>, previous: Option<ShoppingList>) -> Self {
+        let mut list = Self {
+            plan_id: plan.id.clone(),
+            items: vec![],
+        };
+        for entry in plan.entries.iter() {
+            let meal = saved
+                .iter()
+                .find(|meal| meal.id == entry.meal.id)
+                .unwrap_or(&entry.meal);
+            for ingredient in meal.scaled_ingredients(entry.servings()) {
+                list.add(ingredient);
+            }
+        }
+        if let Some(previous) = previous {
+            for item in list.items.iter_mut() {
+                if let Some(old) = previous
+                    .items
+                    .iter()
+                    .find(|old| old.ticked && old.ingredient.same_kind(&item.ingredient))
+                {
+                    item.ticked = true;
+                    item.in_pantry = old.in_pantry.clone();
+                }
+            }
+        }
+        list
+    }
+
+    pub fn add(&mut self, ingredient: Ingredient) {
+        match self
+            .items
+            .iter_mut()
+            .find(|item| item.ingredient.same_kind(&ingredient))
+        {
+            Some(item) => {
+                item.ingredient.quantity = match (item.ingredient.quantity, ingredient.quantity) {
+                    (Some(a), Some(b)) => Some(a + b),
+                    (a, b) => a.or(b),
+                };
+            }
+            None => self.items.push(ShoppingItem {
+                ingredient,
+                ticked: false,
+                in_pantry: None,
+            }),
+        }
+    }
+
+    pub fn toggle(&mut self, index: usize) -> Option<&mut ShoppingItem> {
+        match self.items.get_mut(index) {
+            Some(item) => {
+                item.ticked = !item.ticked;
+                Some(item)
+            }
+            None => None,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        if self.items.len() == 0 {
+            return "Shopping list is empty!\n(Add ingredients with /ingredients)".to_string();
+        }
+        format!(
+            "Shopping list: {}/{} done\n(Click to tick off)",
+            self.items.iter().filter(|item| item.ticked).count(),
+            self.items.len()
+        )
+    }
+
+    pub fn buttons(&self) -> Vec<Vec<Button>> {
+        let mut buttons: Vec<Vec<Button>> = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                vec![Button::new(
+                    format!(
+                        "{} {}",
+                        if item.ticked { "✅" } else { "⬜" },
+                        item.ingredient
+                    ),
+                    ButtonKind::TickShoppingItem { index },
+                )]
+            })
+            .collect();
+        buttons.push(vec![Button::new(
+            "Exit".to_string(),
+            ButtonKind::DeleteMessage,
+        )]);
+        buttons
+    }
+}
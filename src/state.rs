@@ -1,18 +1,46 @@
-use pickledb::error::Error;
+use chrono::Utc;
+use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use teloxide::types::{ChatId, Message, PhotoSize};
 
+use crate::auth::{Invite, LoginAttempts, Role};
 use crate::db::{DBKeys, StoreHandler};
+use crate::error::Error;
+use crate::history::Cooked;
+use crate::ingredient::Ingredient;
 use crate::keyboard::Keyboard;
 use crate::meal::Meal;
+use crate::nutrition::{FoodTable, MealNutrition};
+use crate::outbox::Outbox;
+use crate::pantry::Pantry;
+use crate::photo::Album;
 use crate::plan::Plan;
 use crate::poll::Poll;
+use crate::request::SentMessage;
+use crate::schedule::Job;
+use crate::settings::ChatSettings;
+use crate::shopping::ShoppingList;
+use crate::tags;
 use crate::Config;
 
+/// Messages remembered per chat, older ones can not be deleted by the bot anyway.
+const MAX_SENT_MESSAGES: usize = 200;
+
 pub struct State {
     sh: StoreHandler,
     tg: TgState,
     pub config: Config,
+    pub login_attempts: LoginAttempts,
+    pub foods: FoodTable,
+    /// Ids of the saved meals using an ingredient, by food key.
+    ingredient_index: HashMap<String, HashSet<String>>,
+    /// Albums whose photos are still arriving, by media_group_id.
+    pub albums: HashMap<String, Album>,
+    /// Meals of imports waiting for confirmation, by import id.
+    pub imports: HashMap<String, Vec<Meal>>,
+    /// Rate limits and counters of the requests sent to telegram.
+    pub outbox: Outbox,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -20,6 +48,14 @@ pub struct TgState {
     pub keyboards: HashMap<String, Keyboard>,
     pub meals: HashMap<String, Meal>,
     pub polls: HashMap<String, Poll>,
+    #[serde(default)]
+    pub jobs: HashMap<String, Job>,
+    /// Messages sent by the bot, by chat id, deleted with /clean.
+    #[serde(default)]
+    pub sent: HashMap<i64, Vec<SentMessage>>,
+    /// Message showing the current plan, by chat id.
+    #[serde(default)]
+    pub plan_messages: HashMap<i64, i32>,
 }
 
 impl State {
@@ -37,10 +73,27 @@ impl State {
                     keyboards: HashMap::new(),
                     meals: HashMap::new(),
                     polls: HashMap::new(),
+                    jobs: HashMap::new(),
+                    sent: HashMap::new(),
+                    plan_messages: HashMap::new(),
                 }
             }
         };
-        Self { sh, tg, config }
+        let mut state = Self {
+            sh,
+            tg,
+            config,
+            login_attempts: LoginAttempts::default(),
+            foods: FoodTable::bundled(),
+            ingredient_index: HashMap::new(),
+            albums: HashMap::new(),
+            imports: HashMap::new(),
+            outbox: Outbox::default(),
+        };
+        for meal in state.get_saved_meals() {
+            state.index_meal(&meal);
+        }
+        state
     }
 
     pub fn set_tg(&mut self, tg_state: TgState) -> &mut Self {
@@ -48,15 +101,12 @@ impl State {
         self
     }
 
-    pub fn save_tg(&mut self) {
-        match self
-            .sh
+    pub fn save_tg(&mut self) -> Result<(), Error> {
+        self.sh
             .state_db
-            .set(&DBKeys::State.to_string(), &self.tg.clone())
-        {
-            Ok(()) => log::info!("Saved state!"),
-            Err(err) => log::warn!("{}", err),
-        }
+            .set(&DBKeys::State.to_string(), &self.tg.clone())?;
+        log::info!("Saved state!");
+        Ok(())
         // log::debug!(
         //     "K: {} > {:#?} | M: {} | P: {}",
         //     self.keyboards().len(),
@@ -79,6 +129,9 @@ impl State {
     pub fn polls(&self) -> &HashMap<String, Poll> {
         &self.tg.polls
     }
+    pub fn jobs(&self) -> &HashMap<String, Job> {
+        &self.tg.jobs
+    }
     pub fn meals_mut(&mut self) -> &mut HashMap<String, Meal> {
         &mut self.tg.meals
     }
@@ -88,13 +141,75 @@ impl State {
     pub fn polls_mut(&mut self) -> &mut HashMap<String, Poll> {
         &mut self.tg.polls
     }
-    pub fn rate_meal(&mut self, meal_id: String, rating: u8) -> Result<Meal, ()> {
+    pub fn jobs_mut(&mut self) -> &mut HashMap<String, Job> {
+        &mut self.tg.jobs
+    }
+
+    /// Remembers a message sent by the bot, only the latest `MAX_SENT_MESSAGES` of a chat are kept.
+    pub fn remember_message(&mut self, message: &Message) {
+        let sent = self.tg.sent.entry(message.chat_id()).or_default();
+        sent.retain(|sent| sent.message_id != message.id);
+        sent.push(SentMessage {
+            message_id: message.id,
+            date: message.date,
+        });
+        if sent.len() > MAX_SENT_MESSAGES {
+            sent.remove(0);
+        }
+    }
+
+    /// Forgets a deleted message with its keyboards and polls.
+    pub fn forget_message(&mut self, chat_id: i64, message_id: i32) {
+        if let Some(sent) = self.tg.sent.get_mut(&chat_id) {
+            sent.retain(|sent| sent.message_id != message_id);
+        }
+        if self.tg.plan_messages.get(&chat_id) == Some(&message_id) {
+            self.tg.plan_messages.remove(&chat_id);
+        }
+        self.tg
+            .keyboards
+            .retain(|_, keyboard| keyboard.message != Some((chat_id, message_id)));
+        self.tg.polls.retain(|_, poll| {
+            !(poll.chat_id == ChatId::Id(chat_id) && poll.message_id == message_id)
+        });
+    }
+
+    pub fn sent_messages(&self, chat_id: i64) -> Vec<SentMessage> {
+        self.tg.sent.get(&chat_id).cloned().unwrap_or_default()
+    }
+
+    pub fn plan_message(&self, chat_id: i64) -> Option<i32> {
+        self.tg.plan_messages.get(&chat_id).cloned()
+    }
+
+    pub fn set_plan_message(&mut self, chat_id: i64, message_id: i32) {
+        self.tg.plan_messages.insert(chat_id, message_id);
+    }
+
+    /// Remembers which message shows a keyboard.
+    pub fn set_keyboard_message(&mut self, keyboard_id: &str, chat_id: i64, message_id: i32) {
+        if let Some(keyboard) = self.tg.keyboards.get_mut(keyboard_id) {
+            keyboard.message = Some((chat_id, message_id));
+        }
+    }
+    pub fn rate_meal(&mut self, meal_id: String, rating: u8) -> Result<Meal, Error> {
         match self.meals_mut().get_mut(&meal_id) {
             Some(meal) => {
                 meal.rate(Some(rating));
                 Ok(meal.clone())
             }
-            None => Err(()),
+            None => Err(Error::NotFound("Meal".to_string())),
+        }
+    }
+
+    /// Toggles a tag of a meal that is being created.
+    pub fn toggle_meal_tag(&mut self, meal_id: String, tag: &String) -> Result<Meal, Error> {
+        match self.meals_mut().get_mut(&meal_id) {
+            Some(meal) => {
+                meal.toggle_tag(tag);
+                Ok(meal.clone())
+            }
+            None => Err(Error::NotFound("Meal".to_string())),
         }
     }
 
@@ -117,6 +232,15 @@ impl State {
             .collect()
     }
 
+    /// Saved meals that fit the diet profile of a chat.
+    pub fn get_saved_meals_for(&self, chat_id: i64) -> Vec<Meal> {
+        let settings = self.get_settings(chat_id);
+        self.get_saved_meals()
+            .into_iter()
+            .filter(|meal| meal.fits(&settings.diet, settings.diet_unlabeled))
+            .collect()
+    }
+
     pub fn get_saved_meals_by_name(&self, meal_name: String) -> Vec<Meal> {
         self.sh
             .db
@@ -126,30 +250,169 @@ impl State {
             .collect()
     }
 
-    pub fn save_meal(&mut self, meal: &Meal) {
-        self.sh.db.ladd(&DBKeys::Meals.to_string(), meal);
+    /// Saves a meal, its nutrition per serving is updated
+    /// and tag aliases are resolved before.
+    pub fn save_meal(&mut self, meal: &Meal) -> Result<(), Error> {
+        let mut meal = meal.clone();
+        meal.tags =
+            tags::normalize_all(&meal.tags.iter().map(|tag| self.resolve_tag(tag)).collect());
+        let nutrition = self.meal_nutrition(&meal);
+        meal.nutrition = if nutrition.matched.len() > 0 {
+            Some(nutrition.per_serving(meal.servings))
+        } else {
+            None
+        };
+        self.sh
+            .db
+            .ladd(&DBKeys::Meals.to_string(), &meal)
+            .ok_or(Error::Storage(format!(
+                "Meal list missing, {} not saved",
+                meal.name
+            )))?;
+        self.index_meal(&meal);
         log::info!("Saving Meal: {:?}", meal);
+        Ok(())
+    }
+
+    /// Changes a saved meal, returns the saved meal or `None` if it was not found.
+    pub fn edit_saved_meal<F>(&mut self, meal_id: &String, edit: F) -> Result<Option<Meal>, Error>
+    where
+        F: FnOnce(&mut Meal),
+    {
+        let meal = match self.get_saved_meal(meal_id.clone()) {
+            Some(meal) => meal,
+            None => return Ok(None),
+        };
+        let mut new_meal = meal.clone();
+        edit(&mut new_meal);
+        if self.remove_saved_meal(&meal)? {
+            self.save_meal(&new_meal)?;
+            Ok(self.get_saved_meal(meal_id.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Replaces a photo of a saved or temporary meal, see `Meal::replace_photo`.
+    pub fn replace_meal_photo(
+        &mut self,
+        meal_id: &String,
+        file_unique_id: &String,
+        photo: PhotoSize,
+    ) -> Result<(), Error> {
+        if let Some(meal) = self.tg.meals.get_mut(meal_id) {
+            meal.replace_photo(file_unique_id, photo.clone());
+        }
+        self.edit_saved_meal(meal_id, |meal| {
+            meal.replace_photo(file_unique_id, photo);
+        })?;
+        Ok(())
     }
 
     pub fn remove_saved_meal(&mut self, meal: &Meal) -> Result<bool, Error> {
         log::info!("Removing Meal: {:?}", meal);
-        self.sh.db.lrem_value(&DBKeys::Meals.to_string(), meal)
+        let removed = self
+            .sh
+            .db
+            .lrem_value(&DBKeys::Meals.to_string(), meal)
+            .map_err(Error::from);
+        if let Ok(true) = removed {
+            self.unindex_meal(meal);
+        }
+        removed
     }
 
-    pub fn remove_saved_meal_by_id(&mut self, meal_id: String) {
-        if let Some(meal) = self.get_saved_meal(meal_id.clone()) {
-            match self.sh.db.lrem_value(&DBKeys::Meals.to_string(), &meal) {
-                Ok(rem) => log::info!("Removed Meal: {:?}? {}", meal, rem),
-                Err(err) => log::warn!("{}", err),
+    pub fn remove_saved_meal_by_id(&mut self, meal_id: String) -> Result<bool, Error> {
+        match self.get_saved_meal(meal_id.clone()) {
+            Some(meal) => self.remove_saved_meal(&meal),
+            None => Err(Error::NotFound(format!("Meal {}", meal_id))),
+        }
+    }
+
+    fn index_meal(&mut self, meal: &Meal) {
+        for ingredient in meal.ingredients.iter() {
+            self.ingredient_index
+                .entry(ingredient.food_key())
+                .or_insert(HashSet::new())
+                .insert(meal.id.clone());
+        }
+    }
+
+    fn unindex_meal(&mut self, meal: &Meal) {
+        for ingredient in meal.ingredients.iter() {
+            if let Some(ids) = self.ingredient_index.get_mut(&ingredient.food_key()) {
+                ids.remove(&meal.id);
             }
-        } else {
-            log::warn!("Meal to remove not found: {}", meal_id);
         }
     }
 
-    pub fn whitelist_user(&mut self, username: String) {
-        self.sh.db.ladd(&DBKeys::Whitelist.to_string(), &username);
-        log::info!("Whitelisting User: {}", username);
+    /// Saved meals using an ingredient that fit the diet profile of a chat.
+    pub fn meals_with_ingredient(&self, chat_id: i64, ingredient: &Ingredient) -> Vec<Meal> {
+        match self.ingredient_index.get(&ingredient.food_key()) {
+            Some(ids) if ids.len() > 0 => self
+                .get_saved_meals_for(chat_id)
+                .into_iter()
+                .filter(|meal| ids.contains(&meal.id))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn whitelist_user(&mut self, username: String, role: Role) -> Result<(), Error> {
+        if !self.get_whitelisted_users().contains(&username) {
+            self.sh
+                .db
+                .ladd(&DBKeys::Whitelist.to_string(), &username)
+                .ok_or(Error::Storage("Whitelist missing".to_string()))?;
+        }
+        if role == Role::Admin && !self.is_admin(&username) {
+            self.sh
+                .db
+                .ladd(&DBKeys::Admins.to_string(), &username)
+                .ok_or(Error::Storage("Admin list missing".to_string()))?;
+        }
+        log::info!("Whitelisting User: {} ({})", username, role);
+        Ok(())
+    }
+
+    pub fn is_admin(&self, username: &String) -> bool {
+        self.sh
+            .db
+            .liter(&DBKeys::Admins.to_string())
+            .filter_map(|item| item.get_item::<String>())
+            .any(|admin| &admin == username)
+    }
+
+    pub fn save_invite(&mut self, invite: &Invite) -> Result<(), Error> {
+        self.sh
+            .db
+            .ladd(&DBKeys::Invites.to_string(), invite)
+            .ok_or(Error::Storage("Invite list missing".to_string()))?;
+        log::info!("Saving Invite for role {}", invite.role);
+        Ok(())
+    }
+
+    /// Removes the invite with the given code and returns it if it is still valid.
+    /// Expired invites are dropped on the way.
+    pub fn take_invite(&mut self, code: &String) -> Result<Option<Invite>, Error> {
+        let invites: Vec<Invite> = self
+            .sh
+            .db
+            .liter(&DBKeys::Invites.to_string())
+            .filter_map(|item| item.get_item::<Invite>())
+            .collect();
+        let mut found = None;
+        for invite in invites {
+            if &invite.code == code || invite.is_expired() {
+                self.sh
+                    .db
+                    .lrem_value(&DBKeys::Invites.to_string(), &invite)?;
+                if &invite.code == code && !invite.is_expired() {
+                    found = Some(invite);
+                }
+            }
+        }
+        Ok(found)
     }
 
     pub fn get_whitelisted_users(&self) -> Vec<String> {
@@ -160,14 +423,226 @@ impl State {
             .collect()
     }
 
-    pub fn save_plan(&mut self, chat_id: i64, meal_plan: Plan) {
-        match self.sh.plan_db.set(&chat_id.to_string(), &meal_plan) {
-            Ok(()) => {}
-            Err(err) => log::warn!("{}", err),
+    /// Saves the current plan of a chat, a replaced plan is archived.
+    pub fn save_plan(&mut self, chat_id: i64, meal_plan: Plan) -> Result<(), Error> {
+        if let Some(previous) = self.get_plan(chat_id) {
+            if previous.id != meal_plan.id && previous.entries.len() > 0 {
+                self.archive_plan(chat_id, previous)?;
+            }
         }
+        self.sh.plan_db.set(&chat_id.to_string(), &meal_plan)?;
+        Ok(())
     }
 
     pub fn get_plan(&self, chat_id: i64) -> Option<Plan> {
         self.sh.plan_db.get(&chat_id.to_string())
     }
+
+    fn archive_plan(&mut self, chat_id: i64, plan: Plan) -> Result<(), Error> {
+        let mut plans = self.get_archived_plans(chat_id);
+        plans.push(plan);
+        self.sh.history_db.set(&chat_id.to_string(), &plans)?;
+        Ok(())
+    }
+
+    /// Archived plans of a chat, oldest first.
+    pub fn get_archived_plans(&self, chat_id: i64) -> Vec<Plan> {
+        self.sh
+            .history_db
+            .get(&chat_id.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn get_archived_plan(&self, chat_id: i64, plan_id: &String) -> Option<Plan> {
+        self.get_archived_plans(chat_id)
+            .into_iter()
+            .find(|plan| &plan.id == plan_id)
+    }
+
+    /// Ids of meals planned in the chat's last archived plans,
+    /// as many as set in the chat settings.
+    pub fn recent_meal_ids(&self, chat_id: i64) -> Vec<String> {
+        let penalty = self.get_settings(chat_id).history_penalty;
+        self.get_archived_plans(chat_id)
+            .iter()
+            .rev()
+            .take(penalty)
+            .flat_map(|plan| plan.entries.iter().map(|entry| entry.meal.id.clone()))
+            .collect()
+    }
+
+    /// Logs a cooked meal, its ingredients are taken out of the pantry.
+    pub fn log_cooked(&mut self, chat_id: i64, meal: &Meal) -> Result<(), Error> {
+        let mut history = self.get_cooking_history(chat_id);
+        history.push(Cooked::new(meal));
+        self.sh.cooking_db.set(&chat_id.to_string(), &history)?;
+        log::info!("Cooked {} in chat {}", meal.name, chat_id);
+        let mut pantry = self.get_pantry(chat_id);
+        if pantry.deduct(meal) {
+            self.save_pantry(chat_id, &pantry)?;
+        }
+        Ok(())
+    }
+
+    /// Cooked meals of a chat, oldest first.
+    pub fn get_cooking_history(&self, chat_id: i64) -> Vec<Cooked> {
+        self.sh
+            .cooking_db
+            .get(&chat_id.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Ids of meals cooked in the chat during the last `days` days.
+    pub fn recently_cooked_ids(&self, chat_id: i64, days: i64) -> Vec<String> {
+        let since = Utc::now().timestamp() - days * 24 * 60 * 60;
+        self.get_cooking_history(chat_id)
+            .into_iter()
+            .filter(|cooked| cooked.timestamp >= since)
+            .map(|cooked| cooked.meal_id)
+            .collect()
+    }
+
+    /// Manual mappings of ingredient names to food names.
+    pub fn food_mappings(&self) -> HashMap<String, String> {
+        self.sh
+            .nutrition_db
+            .iter()
+            .filter_map(|kv| Some((kv.get_key().to_string(), kv.get_value::<String>()?)))
+            .collect()
+    }
+
+    /// Maps an ingredient to a food and updates all meals using it.
+    pub fn map_food(&mut self, ingredient: &String, food: &String) -> Result<(), Error> {
+        self.sh.nutrition_db.set(ingredient, food)?;
+        for meal in self.get_saved_meals() {
+            if meal.ingredients.iter().any(|i| &i.name == ingredient) {
+                if self.remove_saved_meal(&meal)? {
+                    self.save_meal(&meal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn meal_nutrition(&self, meal: &Meal) -> MealNutrition {
+        self.foods.meal(meal, &self.food_mappings())
+    }
+
+    /// Aliases of tags, mapped to the tag they stand for.
+    pub fn tag_aliases(&self) -> HashMap<String, String> {
+        self.sh
+            .tags_db
+            .iter()
+            .filter_map(|kv| Some((kv.get_key().to_string(), kv.get_value::<String>()?)))
+            .collect()
+    }
+
+    pub fn tag_counts(&self) -> Vec<(String, usize)> {
+        tags::counts(&self.get_saved_meals())
+    }
+
+    /// Normalized tag, or the tag it is an alias for.
+    pub fn resolve_tag(&self, tag: &str) -> String {
+        let tag = tags::normalize(tag);
+        self.sh.tags_db.get::<String>(&tag).unwrap_or(tag)
+    }
+
+    /// Makes `alias` stand for `tag`, `None` removes the alias.
+    pub fn alias_tag(&mut self, alias: &String, tag: Option<&String>) -> Result<(), Error> {
+        let alias = tags::normalize(alias);
+        match tag.map(|tag| self.resolve_tag(tag)) {
+            Some(tag) if tag != alias => self.sh.tags_db.set(&alias, &tag)?,
+            _ => {
+                self.sh.tags_db.rem(&alias)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames a tag in all meals and aliases, returns the number of changed meals.
+    pub fn rename_tag(&mut self, old: &String, new: &String) -> Result<usize, Error> {
+        let (old, new) = (tags::normalize(old), tags::normalize(new));
+        for (alias, tag) in self.tag_aliases() {
+            if tag == old {
+                self.alias_tag(&alias, Some(&new))?;
+            }
+        }
+        let mut renamed = 0;
+        for meal in self.get_saved_meals() {
+            let mut new_meal = meal.clone();
+            if new_meal.rename_tag(&old, &new) && self.remove_saved_meal(&meal)? {
+                self.save_meal(&new_meal)?;
+                renamed += 1;
+            }
+        }
+        Ok(renamed)
+    }
+
+    /// Merges the tag `from` into `into`, `from` stays as alias of `into`.
+    pub fn merge_tags(&mut self, from: &String, into: &String) -> Result<usize, Error> {
+        let into = self.resolve_tag(into);
+        let merged = self.rename_tag(from, &into)?;
+        self.alias_tag(from, Some(&into))?;
+        Ok(merged)
+    }
+
+    pub fn get_pantry(&self, chat_id: i64) -> Pantry {
+        self.sh
+            .pantry_db
+            .get(&chat_id.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn save_pantry(&mut self, chat_id: i64, pantry: &Pantry) -> Result<(), Error> {
+        self.sh.pantry_db.set(&chat_id.to_string(), pantry)?;
+        Ok(())
+    }
+
+    pub fn save_shopping_list(&mut self, chat_id: i64, list: &ShoppingList) -> Result<(), Error> {
+        self.sh.shopping_db.set(&chat_id.to_string(), list)?;
+        Ok(())
+    }
+
+    pub fn get_shopping_list(&self, chat_id: i64) -> Option<ShoppingList> {
+        self.sh.shopping_db.get(&chat_id.to_string())
+    }
+
+    pub fn get_settings(&self, chat_id: i64) -> ChatSettings {
+        self.sh
+            .settings_db
+            .get(&chat_id.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn save_settings(&mut self, chat_id: i64, settings: &ChatSettings) -> Result<(), Error> {
+        self.sh.settings_db.set(&chat_id.to_string(), settings)?;
+        Ok(())
+    }
+
+    /// Calendar token of a chat, created on first use.
+    pub fn ics_token(&mut self, chat_id: i64) -> Result<String, Error> {
+        let mut settings = self.get_settings(chat_id);
+        match settings.ics_token.clone() {
+            Some(token) => Ok(token),
+            None => {
+                let token = nanoid!(24);
+                settings.ics_token = Some(token.clone());
+                self.save_settings(chat_id, &settings)?;
+                Ok(token)
+            }
+        }
+    }
+
+    pub fn find_chat_by_ics_token(&self, token: &str) -> Option<i64> {
+        self.sh
+            .settings_db
+            .iter()
+            .find(|kv| {
+                kv.get_value::<ChatSettings>()
+                    .and_then(|settings| settings.ics_token)
+                    .map(|ics_token| ics_token == token)
+                    .unwrap_or(false)
+            })
+            .and_then(|kv| kv.get_key().parse::<i64>().ok())
+    }
 }
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use crate::button::{Button, ButtonKind};
+use crate::meal::Meal;
+
+/// Number of most used tags offered as buttons when creating or editing a meal.
+const TAG_BUTTONS: usize = 6;
+const TAG_BUTTONS_PER_ROW: usize = 3;
+
+/// Tags are stored lowercase, without surrounding whitespace and a leading "#".
+pub fn normalize(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').trim().to_lowercase()
+}
+
+/// Normalized tags in their original order, without empty ones and duplicates.
+pub fn normalize_all(tags: &Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags.iter().map(|tag| normalize(tag)) {
+        if tag.len() > 0 && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+/// Tags of all meals and how many meals use them, most used first.
+pub fn counts(meals: &Vec<Meal>) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for tag in meals.iter().flat_map(|meal| normalize_all(&meal.tags)) {
+        *counts.entry(tag).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+pub fn tags_text(counts: &Vec<(String, usize)>, aliases: &HashMap<String, String>) -> String {
+    if counts.len() == 0 {
+        return "No tags yet!\n(Add with /tag <name>, <tag> <tag> ...)".to_string();
+    }
+    let mut text = format!(
+        "Tags:\n{}",
+        counts
+            .iter()
+            .map(|(tag, count)| format!("{} ({})", tag, count))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    if aliases.len() > 0 {
+        let mut aliases: Vec<_> = aliases.iter().collect();
+        aliases.sort();
+        text.push_str(&format!(
+            "\n\nAliases:\n{}",
+            aliases
+                .iter()
+                .map(|(alias, tag)| format!("{} → {}", alias, tag))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+    text
+}
+
+/// Buttons toggling the most used tags and those of the meal, set tags are checked.
+pub fn tag_buttons(meal: &Meal, counts: &Vec<(String, usize)>) -> Vec<Vec<Button>> {
+    let mut tags: Vec<String> = counts
+        .iter()
+        .take(TAG_BUTTONS)
+        .map(|(tag, _)| tag.clone())
+        .collect();
+    for tag in normalize_all(&meal.tags) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    let has_tag = |tag: &String| meal.tags.iter().any(|t| &normalize(t) == tag);
+    tags.chunks(TAG_BUTTONS_PER_ROW)
+        .map(|row| {
+            row.iter()
+                .map(|tag| {
+                    Button::new(
+                        if has_tag(tag) {
+                            format!("✓ #{}", tag)
+                        } else {
+                            format!("#{}", tag)
+                        },
+                        ButtonKind::ToggleTag {
+                            meal_id: meal.id.clone(),
+                            tag: tag.clone(),
+                        },
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}